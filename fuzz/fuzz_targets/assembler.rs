@@ -0,0 +1,10 @@
+#![no_main]
+// 任意のアセンブリを渡してもエラーを返すだけでパニックしないことを確かめる
+use libfuzzer_sys::fuzz_target;
+use virtual_machine::hack_emulator::assemble;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    let lines: Vec<String> = text.lines().map(|line| line.to_string()).collect();
+    let _ = assemble(&lines);
+});
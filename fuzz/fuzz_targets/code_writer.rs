@@ -0,0 +1,51 @@
+#![no_main]
+// バイト列から正しいVMコマンドの列を作って翻訳し、出力が必ずアセンブルできることを確かめる
+use libfuzzer_sys::fuzz_target;
+use virtual_machine::code_writer::CodeWriter;
+use virtual_machine::hack_emulator::assemble;
+use virtual_machine::program::{Program, VmFile};
+use virtual_machine::validator;
+
+const SEGMENTS: [(&str, u16); 8] = [
+    ("constant", 32768),
+    ("local", 32768),
+    ("argument", 32768),
+    ("this", 32768),
+    ("that", 32768),
+    ("pointer", 2),
+    ("temp", 8),
+    ("static", 240),
+];
+const ARITHMETIC_COMMANDS: [&str; 9] = ["add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not"];
+
+// 1コマンドにつき3バイト使う
+fn command(bytes: &[u8]) -> String {
+    let value = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let (segment, size) = SEGMENTS[bytes[1] as usize % SEGMENTS.len()];
+    // constantにはpopできない
+    let (pop_segment, pop_size) = SEGMENTS[1 + bytes[1] as usize % (SEGMENTS.len() - 1)];
+    let name = format!("Fuzz.f{}", bytes[1] % 4);
+    match bytes[0] % 9 {
+        0 => format!("push {} {}", segment, value % size),
+        1 => format!("pop {} {}", pop_segment, value % pop_size),
+        2 => ARITHMETIC_COMMANDS[bytes[1] as usize % ARITHMETIC_COMMANDS.len()].to_string(),
+        3 => format!("label L{}", bytes[1] % 8),
+        4 => format!("goto L{}", bytes[1] % 8),
+        5 => format!("if-goto L{}", bytes[1] % 8),
+        6 => format!("function {} {}", name, bytes[2] % 16),
+        7 => format!("call {} {}", name, bytes[2] % 16),
+        _ => "return".to_string(),
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let lines: Vec<String> = data.chunks_exact(3).map(command).collect();
+    let program = Program::new(vec![VmFile::new("Fuzz.vm", lines)]);
+    assert_eq!(validator::validate(&program), Vec::<String>::new());
+
+    let mut code_writer = CodeWriter::new("Fuzz.vm".to_string());
+    program.translate(&mut code_writer);
+    if let Err(error) = assemble(code_writer.code()) {
+        panic!("{}\n{}", error, code_writer.code().join("\n"));
+    }
+});
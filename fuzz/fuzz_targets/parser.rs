@@ -0,0 +1,44 @@
+#![no_main]
+// 任意のバイト列をVMファイルとして読み、パースと検査がパニックしないことを確かめる
+// 検査を通ったものは実行と全てのターゲットへの翻訳もパニックしないことを確かめる
+use libfuzzer_sys::fuzz_target;
+use virtual_machine::code_writer::CodeWriter;
+use virtual_machine::hack_emulator::assemble;
+use virtual_machine::program::{Program, VmFile};
+use virtual_machine::translator::Translator;
+use virtual_machine::vm_interpreter::VmInterpreter;
+use virtual_machine::{c_writer, llvm_writer, riscv_writer, wat_writer, x86_writer};
+use virtual_machine::{stack_depth, validator, warnings};
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    let lines = text.lines().map(|line| line.to_string()).collect();
+    let program = Program::new(vec![VmFile::new("Fuzz.vm", lines)]);
+    let errors = validator::validate(&program);
+    validator::check_calls(&program, &[]);
+    stack_depth::check(&program);
+    warnings::warnings(&program);
+    if !errors.is_empty() {
+        return;
+    }
+
+    let mut interpreter = VmInterpreter::prepare(&program, &[]);
+    interpreter.run(Some(10000));
+
+    let mut code_writer = CodeWriter::new("Fuzz.vm".to_string());
+    program.translate(&mut code_writer);
+    if let Err(error) = assemble(code_writer.code()) {
+        // 長い入力はROMに入らないことがある
+        assert!(error.starts_with("Program does not fit in ROM"), "{}", error);
+    }
+    let mut translators: Vec<Box<dyn Translator>> = vec![
+        Box::new(c_writer::CWriter::new("Fuzz.vm".to_string())),
+        Box::new(x86_writer::X86Writer::new("Fuzz.vm".to_string())),
+        Box::new(wat_writer::WatWriter::new("Fuzz.vm".to_string())),
+        Box::new(llvm_writer::LlvmWriter::new("Fuzz.vm".to_string())),
+        Box::new(riscv_writer::RiscvWriter::new("Fuzz.vm".to_string())),
+    ];
+    for translator in &mut translators {
+        program.translate(translator.as_mut());
+    }
+});
@@ -0,0 +1,371 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::code_writer::helper::{filename_without_extention, mangle_symbol};
+use crate::code_writer::segment::Segment;
+use crate::static_allocation::StaticAllocation;
+use crate::translator::Translator;
+use std::{fs::OpenOptions, io::prelude::*};
+
+const HEADER: &str = "#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static int16_t ram[32768];
+
+#define M(a) ram[(uint16_t)(a) & 0x7fff]
+#define SP ram[0]
+#define LCL ram[1]
+#define ARG ram[2]
+#define THIS ram[3]
+#define THAT ram[4]
+#define STEP if (limit >= 0 && steps++ >= limit) goto halt
+
+static int16_t wrap(int value) {
+    return (int16_t)(uint16_t)value;
+}
+
+int main(int argc, char **argv) {
+    long limit = -1;
+    long steps = 0;
+    int ret = 0;
+    for (int i = 1; i < argc; i++) {
+        int address, value;
+        if (argv[i][0] == '-' && argv[i][1] == 'n' && i + 1 < argc) {
+            limit = atol(argv[++i]);
+        } else if (sscanf(argv[i], \"%d=%d\", &address, &value) == 2) {
+            M(address) = wrap(value);
+        }
+    }
+";
+
+const FOOTER: &str = "halt:
+    for (int i = 0; i < 32768; i++) {
+        if (ram[i] != 0) {
+            printf(\"%d: %d\\n\", i, ram[i]);
+        }
+    }
+    return 0;
+}";
+
+pub struct CWriter {
+    file_name: String,
+    generated_code: Vec<String>,
+    function_name_stack: Vec<String>,
+    return_address_count: usize,
+    variables: StaticAllocation,
+    defined_functions: Vec<String>,
+    called_functions: Vec<String>,
+}
+
+impl CWriter {
+    pub fn new(file_name: String) -> CWriter {
+        CWriter {
+            file_name,
+            generated_code: vec![],
+            function_name_stack: vec!["null".to_string()],
+            return_address_count: 0,
+            variables: StaticAllocation::default(),
+            defined_functions: vec![],
+            called_functions: vec![],
+        }
+    }
+
+    pub fn generate(&self) -> Vec<String> {
+        let mut code: Vec<String> = HEADER.lines().map(|l| l.to_string()).collect();
+        code.append(&mut self.generated_code.clone());
+        code.push("    goto halt;".to_string());
+
+        if self.return_address_count > 0 {
+            code.push("dispatch:".to_string());
+            code.push("    switch (ret) {".to_string());
+            for i in 1..=self.return_address_count {
+                code.push(format!("    case {}: goto return_{};", i, i));
+            }
+            code.push("    default: goto halt;".to_string());
+            code.push("    }".to_string());
+        }
+
+        // 定義されていない関数を呼び出した場合は停止する
+        for function_name in &self.called_functions {
+            if !self.defined_functions.contains(function_name) {
+                code.push(format!("{}:", function_label(function_name)));
+                code.push("    goto halt;".to_string());
+            }
+        }
+
+        code.append(&mut FOOTER.lines().map(|l| l.to_string()).collect());
+        code
+    }
+
+    fn segment_address(&mut self, segment: &str, index: &str) -> String {
+        match Segment::from_str(segment) {
+            Some(Segment::LOCAL)
+            | Some(Segment::ARGUMENT)
+            | Some(Segment::THIS)
+            | Some(Segment::THAT) => {
+                format!("{} + {}", Segment::to_register_alias_str(segment), index)
+            }
+            Some(Segment::POINTER) => format!("3 + {}", index),
+            Some(Segment::TEMP) => format!("5 + {}", index),
+            Some(Segment::STATIC) => {
+                let name = format!("{}.{}", filename_without_extention(&self.file_name), index);
+                self.variables.address(&name).to_string()
+            }
+            _ => panic!("{} has no address", segment),
+        }
+    }
+
+    fn current_label(&self, label_name: &str) -> String {
+        mangle(&format!(
+            "{}${}",
+            self.function_name_stack.last().unwrap(),
+            label_name
+        ))
+    }
+
+    fn append(&mut self, lines: Vec<String>) {
+        self.generated_code.push("    STEP;".to_string());
+        for line in lines {
+            self.generated_code.push(format!("    {}", line));
+        }
+    }
+}
+
+impl Translator for CWriter {
+    fn set_static_allocation(&mut self, allocation: &StaticAllocation) {
+        self.variables = allocation.clone();
+    }
+
+    fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    fn output(&self, file_name: &str) {
+        let mut output = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        for line in self.generate() {
+            writeln!(output, "{}", line).unwrap();
+        }
+    }
+
+    fn push(&mut self, segment: &str, index: &str) {
+        let value = match Segment::from_str(segment) {
+            Some(Segment::CONSTANT) => index.to_string(),
+            _ => format!("M({})", self.segment_address(segment, index)),
+        };
+        self.append(vec![format!("M(SP) = {};", value), "SP++;".to_string()]);
+    }
+
+    fn pop(&mut self, segment: &str, index: &str) {
+        if Segment::from_str(segment) == Some(Segment::CONSTANT) {
+            return;
+        }
+        let address = self.segment_address(segment, index);
+        self.append(vec![
+            format!("ram[13] = wrap({});", address),
+            "SP--;".to_string(),
+            "M(ram[13]) = M(SP);".to_string(),
+        ]);
+    }
+
+    fn write_label(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.generated_code.push(format!("{}:", label));
+    }
+
+    fn write_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.append(vec![format!("goto {};", label)]);
+    }
+
+    fn write_if_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.append(vec![
+            "SP--;".to_string(),
+            format!("if (M(SP) != 0) goto {};", label),
+        ]);
+    }
+
+    fn write_call(&mut self, function_name: &str, n_arg: &str) {
+        self.return_address_count += 1;
+        let return_address = self.return_address_count;
+        if !self.called_functions.iter().any(|f| f == function_name) {
+            self.called_functions.push(function_name.to_string());
+        }
+
+        // return_addressの代わりに戻り先の番号をpushする
+        self.append(vec![
+            format!("M(SP) = {}; SP++;", return_address),
+            "M(SP) = LCL; SP++;".to_string(),
+            "M(SP) = ARG; SP++;".to_string(),
+            "M(SP) = THIS; SP++;".to_string(),
+            "M(SP) = THAT; SP++;".to_string(),
+            format!("ARG = wrap(SP - {} - 5);", n_arg),
+            "LCL = SP;".to_string(),
+            format!("goto {};", function_label(function_name)),
+        ]);
+        self.generated_code
+            .push(format!("return_{}:;", return_address));
+    }
+
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
+        use ArithmeticCommand::*;
+        let code = match ArithmeticCommand::from_str(arithmetic_command) {
+            Some(ADD) => two_operands("wrap(M(SP - 1) + M(SP))"),
+            Some(SUB) => two_operands("wrap(M(SP - 1) - M(SP))"),
+            Some(AND) => two_operands("M(SP - 1) & M(SP)"),
+            Some(OR) => two_operands("M(SP - 1) | M(SP)"),
+            // Hackと同じくx-yの符号で比較する
+            Some(EQ) => two_operands("wrap(M(SP - 1) - M(SP)) == 0 ? -1 : 0"),
+            Some(GT) => two_operands("wrap(M(SP - 1) - M(SP)) > 0 ? -1 : 0"),
+            Some(LT) => two_operands("wrap(M(SP - 1) - M(SP)) < 0 ? -1 : 0"),
+            Some(NEG) => vec!["M(SP - 1) = wrap(-M(SP - 1));".to_string()],
+            Some(NOT) => vec!["M(SP - 1) = ~M(SP - 1);".to_string()],
+            _ => return,
+        };
+        self.append(code);
+    }
+
+    fn write_function(&mut self, function_name: &str, num_locals: &str) {
+        self.function_name_stack.push(function_name.to_string());
+        self.defined_functions.push(function_name.to_string());
+        self.generated_code
+            .push(format!("{}:", function_label(function_name)));
+        self.append(vec![format!(
+            "for (int i = 0; i < {}; i++) {{ M(SP) = 0; SP++; }}",
+            num_locals.parse::<i32>().unwrap()
+        )]);
+    }
+
+    fn write_return(&mut self) {
+        let frame = self.variables.address("FRAME");
+        let ret = self.variables.address("RET");
+        self.append(vec![
+            format!("ram[{}] = LCL;", frame),
+            format!("ram[{}] = M(ram[{}] - 5);", ret, frame),
+            "ram[13] = ARG;".to_string(),
+            "SP--;".to_string(),
+            "M(ram[13]) = M(SP);".to_string(),
+            "SP = wrap(ARG + 1);".to_string(),
+            format!("THAT = M(ram[{}] - 1);", frame),
+            format!("THIS = M(ram[{}] - 2);", frame),
+            format!("ARG = M(ram[{}] - 3);", frame),
+            format!("LCL = M(ram[{}] - 4);", frame),
+            format!("ret = ram[{}];", ret),
+            "goto dispatch;".to_string(),
+        ]);
+    }
+}
+
+fn two_operands(expression: &str) -> Vec<String> {
+    vec!["SP--;".to_string(), format!("M(SP - 1) = {};", expression)]
+}
+
+fn function_label(function_name: &str) -> String {
+    format!("function_{}", mangle(function_name))
+}
+
+fn mangle(name: &str) -> String {
+    format!("L_{}", mangle_symbol(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::program::{Program, VmFile};
+    use crate::translator::skip_without_toolchain;
+    use std::{env, process::Command};
+
+    fn translate(file_name: &str, source: &str) -> CWriter {
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        let mut c_writer = CWriter::new(file_name.to_string());
+        for command in parser.collect_commands() {
+            c_writer.write_command(&command);
+        }
+        c_writer
+    }
+
+    #[test]
+    fn mangle_label() {
+        assert_eq!(mangle("Main.main$LOOP_1"), "L_Main_2emain_24LOOP__1");
+    }
+
+    #[test]
+    fn push_constant() {
+        let c_writer = translate("a.vm", "push constant 7");
+        assert_eq!(
+            c_writer.generated_code,
+            ["    STEP;", "    M(SP) = 7;", "    SP++;"]
+        );
+    }
+
+    #[test]
+    fn static_and_return_variables_are_allocated_in_order() {
+        let mut c_writer = translate("Foo.vm", "push static 3\npop static 1\nreturn");
+        assert_eq!(c_writer.variables.address("Foo.3"), 16);
+        assert_eq!(c_writer.variables.address("Foo.1"), 17);
+        assert_eq!(c_writer.variables.address("FRAME"), 18);
+        assert_eq!(c_writer.variables.address("RET"), 19);
+    }
+
+    #[test]
+    fn uses_static_allocation_of_program() {
+        let source = "call Math.abs 1\npop static 0";
+        let program = Program::new(vec![VmFile::new(
+            "Foo.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let mut c_writer = CWriter::new("Foo.vm".to_string());
+        program.translate(&mut c_writer);
+        // Hackと同じく定義されていないMath.absがRAM[16]を使う
+        assert_eq!(c_writer.variables.address("Foo.0"), 17);
+    }
+
+    #[test]
+    fn undefined_function_halts() {
+        let c_writer = translate("a.vm", "call Foo.bar 0");
+        let code = c_writer.generate();
+        assert!(code.contains(&"function_L_Foo_2ebar:".to_string()));
+        assert!(code.contains(&"    case 1: goto return_1;".to_string()));
+    }
+
+    #[test]
+    fn compiled_program_produces_ram_state() {
+        let source = "push constant 7
+push constant 8
+add
+push constant 3
+gt
+push constant 5
+neg
+pop local 2";
+        let c_writer = translate("SimpleAdd.vm", source);
+        let dir = env::temp_dir().join(format!("c_writer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let c_file = dir.join("SimpleAdd.c");
+        let binary = dir.join("SimpleAdd");
+        c_writer.output(c_file.to_str().unwrap());
+
+        let status = match Command::new("cc")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&c_file)
+            .status()
+        {
+            Ok(status) => status,
+            Err(_) => return skip_without_toolchain("cc is not installed"),
+        };
+        assert!(status.success());
+
+        let output = Command::new(&binary)
+            .args(["0=256", "1=300"])
+            .output()
+            .unwrap();
+        let dump = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(dump, "0: 257\n1: 300\n13: 302\n256: -1\n257: -5\n302: -5\n");
+    }
+}
@@ -0,0 +1,307 @@
+use crate::parser::{Command, CommandType};
+use crate::program::Program;
+use std::collections::HashMap;
+
+pub struct BasicBlock {
+    // 関数内でのコマンドの位置
+    pub start: usize,
+    pub commands: Vec<Command>,
+    pub successors: Vec<usize>,
+}
+
+pub struct FunctionGraph {
+    pub file_name: String,
+    pub name: String,
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl FunctionGraph {
+    // label の前と goto, if-goto, return, call の後で区切る
+    pub fn new(file_name: &str, name: &str, commands: &[Command]) -> FunctionGraph {
+        let mut starts = vec![];
+        for (i, command) in commands.iter().enumerate() {
+            let is_leader = i == 0
+                || command.command_type == CommandType::LABEL
+                || ends_block(&commands[i - 1]);
+            if is_leader {
+                starts.push(i);
+            }
+        }
+
+        let block_of_label: HashMap<&str, usize> = starts
+            .iter()
+            .enumerate()
+            .filter(|(_, &start)| commands[start].command_type == CommandType::LABEL)
+            .map(|(block, &start)| (commands[start].arg1.as_deref().unwrap_or_default(), block))
+            .collect();
+
+        let mut blocks = vec![];
+        for (block, &start) in starts.iter().enumerate() {
+            let end = starts.get(block + 1).copied().unwrap_or(commands.len());
+            let last = &commands[end - 1];
+            let next = Some(block + 1).filter(|&next| next < starts.len());
+            let jump = || {
+                block_of_label
+                    .get(last.arg1.as_deref().unwrap_or_default())
+                    .copied()
+            };
+            let mut successors: Vec<usize> = match last.command_type {
+                CommandType::GOTO => jump().into_iter().collect(),
+                CommandType::IF => jump().into_iter().chain(next).collect(),
+                CommandType::RETURN => vec![],
+                _ => next.into_iter().collect(),
+            };
+            successors.dedup();
+            blocks.push(BasicBlock {
+                start,
+                commands: commands[start..end].to_vec(),
+                successors,
+            });
+        }
+
+        FunctionGraph {
+            file_name: file_name.to_string(),
+            name: name.to_string(),
+            blocks,
+        }
+    }
+
+    // 先頭のブロックから辿れるかどうか
+    pub fn reachable_blocks(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut work = vec![];
+        if !self.blocks.is_empty() {
+            work.push(0);
+        }
+        while let Some(block) = work.pop() {
+            if !reachable[block] {
+                reachable[block] = true;
+                work.extend(self.blocks[block].successors.iter());
+            }
+        }
+        reachable
+    }
+}
+
+fn ends_block(command: &Command) -> bool {
+    matches!(
+        command.command_type,
+        CommandType::GOTO | CommandType::IF | CommandType::RETURN | CommandType::CALL
+    )
+}
+
+pub fn function_graphs(program: &Program) -> Vec<FunctionGraph> {
+    let mut graphs = vec![];
+    for file in &program.files {
+        for (name, commands) in file.functions() {
+            graphs.push(FunctionGraph::new(&file.file_name, &name, commands));
+        }
+    }
+    graphs
+}
+
+pub struct CallGraph {
+    // 定義された関数と、定義がなく呼ばれるだけの関数
+    pub functions: Vec<String>,
+    pub calls: Vec<(String, String)>,
+}
+
+impl CallGraph {
+    pub fn new(program: &Program) -> CallGraph {
+        let mut call_graph = CallGraph {
+            functions: vec![],
+            calls: vec![],
+        };
+        for file in &program.files {
+            for (name, _) in file.functions() {
+                call_graph.add_function(&name);
+            }
+        }
+        for file in &program.files {
+            for (name, commands) in file.functions() {
+                for command in commands {
+                    if command.command_type == CommandType::CALL {
+                        let callee = command.arg1.clone().unwrap_or_default();
+                        call_graph.add_function(&callee);
+                        let call = (name.clone(), callee);
+                        if !call_graph.calls.contains(&call) {
+                            call_graph.calls.push(call);
+                        }
+                    }
+                }
+            }
+        }
+        call_graph
+    }
+
+    fn add_function(&mut self, name: &str) {
+        if !self.functions.iter().any(|function| function == name) {
+            self.functions.push(name.to_string());
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// 関数ごとの制御フローグラフと呼び出しグラフをGraphvizのDOTで出力する
+pub fn to_dot(program: &Program) -> Vec<String> {
+    let mut lines = vec![
+        "digraph cfg {".to_string(),
+        "  node [shape=box];".to_string(),
+    ];
+    for graph in function_graphs(program) {
+        // トップレベルや同じ名前の関数が別のファイルにあっても混ざらないように、ファイル名を付ける
+        let id = format!("{}:{}", graph.file_name, graph.name);
+        let node = |block: usize| quote(&format!("{}:{}", id, block));
+        lines.push(format!(
+            "  subgraph {} {{",
+            quote(&format!("cluster_{}", id))
+        ));
+        lines.push(format!(
+            "    label={};",
+            quote(&format!("{} {}", graph.file_name, graph.name))
+        ));
+        for (i, block) in graph.blocks.iter().enumerate() {
+            let text = block
+                .commands
+                .iter()
+                .map(|command| format!("{}\\l", command))
+                .collect::<String>();
+            lines.push(format!(
+                "    {} [label=\"{}\"];",
+                node(i),
+                text.replace('"', "\\\"")
+            ));
+        }
+        for (i, block) in graph.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                lines.push(format!("    {} -> {};", node(i), node(successor)));
+            }
+        }
+        lines.push("  }".to_string());
+    }
+    lines.push("}".to_string());
+
+    let call_graph = CallGraph::new(program);
+    lines.push("digraph calls {".to_string());
+    for function in &call_graph.functions {
+        lines.push(format!("  {};", quote(function)));
+    }
+    for (caller, callee) in &call_graph.calls {
+        lines.push(format!("  {} -> {};", quote(caller), quote(callee)));
+    }
+    lines.push("}".to_string());
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::VmFile;
+
+    fn program(source: &str) -> Program {
+        Program::new(vec![VmFile::new(
+            "Foo.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )])
+    }
+
+    #[test]
+    fn basic_blocks() {
+        let program = program(
+            "function Foo.main 0
+push constant 1
+label LOOP
+push constant 2
+if-goto END
+call Foo.bar 0
+pop temp 0
+goto LOOP
+label END
+return
+push constant 3",
+        );
+        let graphs = function_graphs(&program);
+        assert_eq!(graphs.len(), 1);
+        let blocks = &graphs[0].blocks;
+        let starts: Vec<usize> = blocks.iter().map(|b| b.start).collect();
+        assert_eq!(starts, [0, 1, 4, 5, 7, 9]);
+        let successors: Vec<Vec<usize>> = blocks.iter().map(|b| b.successors.clone()).collect();
+        assert_eq!(
+            successors,
+            [vec![1], vec![4, 2], vec![3], vec![1], vec![], vec![]]
+        );
+        assert_eq!(
+            graphs[0].reachable_blocks(),
+            [true, true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn call_graph_and_dot() {
+        let program = program(
+            "function Foo.main 0
+call Foo.bar 0
+call Foo.bar 0
+call Math.abs 1
+return
+function Foo.bar 0
+push constant 0
+return",
+        );
+        let call_graph = CallGraph::new(&program);
+        assert_eq!(call_graph.functions, ["Foo.main", "Foo.bar", "Math.abs"]);
+        assert_eq!(
+            call_graph.calls,
+            [
+                ("Foo.main".to_string(), "Foo.bar".to_string()),
+                ("Foo.main".to_string(), "Math.abs".to_string()),
+            ]
+        );
+
+        let dot = to_dot(&program);
+        assert!(dot.contains(
+            &"    \"Foo.vm:Foo.bar:0\" [label=\"push constant 0\\lreturn\\l\"];".to_string()
+        ));
+        assert!(dot.contains(&"    \"Foo.vm:Foo.main:0\" -> \"Foo.vm:Foo.main:1\";".to_string()));
+        assert!(dot.contains(&"  \"Foo.main\" -> \"Math.abs\";".to_string()));
+    }
+
+    #[test]
+    fn dot_separates_files() {
+        let files = [
+            ("Foo.vm", "push constant 1"),
+            ("Bar.vm", "push constant 2\nfunction Foo.main 0\nreturn"),
+            ("Baz.vm", "function Foo.main 0\nreturn"),
+        ];
+        let program = Program::new(
+            files
+                .iter()
+                .map(|(file_name, source)| {
+                    VmFile::new(file_name, source.lines().map(|l| l.to_string()).collect())
+                })
+                .collect(),
+        );
+        let dot = to_dot(&program);
+        let clusters: Vec<&String> = dot
+            .iter()
+            .filter(|line| line.starts_with("  subgraph"))
+            .collect();
+        assert_eq!(
+            clusters,
+            [
+                "  subgraph \"cluster_Foo.vm:(top level)\" {",
+                "  subgraph \"cluster_Bar.vm:(top level)\" {",
+                "  subgraph \"cluster_Bar.vm:Foo.main\" {",
+                "  subgraph \"cluster_Baz.vm:Foo.main\" {",
+            ]
+        );
+        assert!(dot.contains(&"    label=\"Bar.vm (top level)\";".to_string()));
+        assert!(dot
+            .contains(&"    \"Foo.vm:(top level):0\" [label=\"push constant 1\\l\"];".to_string()));
+        assert!(dot
+            .contains(&"    \"Bar.vm:(top level):0\" [label=\"push constant 2\\l\"];".to_string()));
+    }
+}
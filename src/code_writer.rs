@@ -1,12 +1,15 @@
-use std::{fs::OpenOptions, io::prelude::*};
+use crate::parser::{Command, CommandType};
+use crate::translator::Translator;
+use std::{fs::OpenOptions, io::prelude::*, path::Path};
 mod arithmetic_code_generator;
-mod arithmetic_command;
+pub(crate) mod arithmetic_command;
 mod constant;
-mod helper;
+pub(crate) mod helper;
 mod pop_code_generator;
 mod push_code_generator;
 mod return_address_generator;
-mod segment;
+pub(crate) mod segment;
+pub mod source_map;
 
 pub struct CodeWriter {
     file_name: String,
@@ -14,6 +17,8 @@ pub struct CodeWriter {
     symbol_count: usize,
     function_name_stack: Vec<String>,
     return_address_generator: return_address_generator::ReturnAddressGenerator,
+    source_locations: Vec<source_map::SourceLocation>,
+    annotate: bool,
 }
 
 impl CodeWriter {
@@ -24,32 +29,105 @@ impl CodeWriter {
             symbol_count: 0,
             function_name_stack: vec!["null".to_string()],
             return_address_generator: return_address_generator::ReturnAddressGenerator::new(),
+            source_locations: vec![],
+            annotate: false,
         }
     }
 
-    pub fn output(&self, file_name: &str) {
+    // 各コマンドの前に元のVMコマンドをコメントで書く
+    pub fn set_annotate(&mut self, annotate: bool) {
+        self.annotate = annotate;
+    }
+
+    // ブートストラップ: SP=256; call Sys.init 0
+    pub fn write_init(&mut self) {
+        self.generated_code.append(&mut vec![
+            "@256".to_string(),
+            "D=A".to_string(),
+            "@SP".to_string(),
+            "M=D".to_string(),
+        ]);
+        self.write_call("Sys.init", "0");
+    }
+
+    pub fn code(&self) -> &[String] {
+        &self.generated_code
+    }
+
+    pub fn mappings(&self) -> Vec<source_map::SourceMapping> {
+        source_map::generate_mappings(&self.generated_code, &self.source_locations)
+    }
+
+    pub fn source_map(&self) -> Vec<String> {
+        source_map::generate_source_map(&self.generated_code, &self.source_locations)
+    }
+}
+
+fn write_lines(file_name: &str, lines: &[String]) {
+    let mut output = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(file_name)
+        .unwrap();
+    for line in lines {
+        writeln!(output, "{}", line).unwrap();
+    }
+}
+
+impl Translator for CodeWriter {
+    fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    fn output(&self, file_name: &str) {
         println!("{:#?}", self.generated_code);
-        let mut output = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(file_name)
-            .unwrap();
-        for line in &self.generated_code {
-            writeln!(output, "{}", line).unwrap();
+        write_lines(file_name, &self.generated_code);
+        // Foo.asm に対して Foo.map.json
+        let map_file_name = Path::new(file_name).with_extension("map.json");
+        write_lines(&map_file_name.to_string_lossy(), &self.source_map());
+    }
+
+    fn begin_command(&mut self, command: &Command) {
+        let function = match command.command_type {
+            CommandType::FUNCTION => command.arg1.clone(),
+            _ => Some(self.function_name_stack.last().unwrap().clone())
+                .filter(|function| function != "null"),
+        };
+        self.source_locations.push(source_map::SourceLocation {
+            file_name: self.file_name.clone(),
+            line: command.line,
+            function,
+            command: command.to_string(),
+            code_index: self.generated_code.len(),
+        });
+
+        if self.annotate {
+            if command.command_type == CommandType::FUNCTION {
+                self.generated_code
+                    .push(format!("// function {}", command.arg1.as_deref().unwrap()));
+            }
+            let file_name = Path::new(&self.file_name).file_name().unwrap();
+            self.generated_code.push(format!(
+                "// {}:{} {}",
+                file_name.to_string_lossy(),
+                command.line,
+                command
+            ));
         }
     }
 
-    pub fn push(&mut self, segment: &str, index: &str) {
+    fn push(&mut self, segment: &str, index: &str) {
         let mut new_code = push_code_generator::generate_push_code(segment, index, &self.file_name);
         self.generated_code.append(&mut new_code);
     }
 
-    pub fn pop(&mut self, segment: &str, index: &str) {
+    fn pop(&mut self, segment: &str, index: &str) {
         let mut new_code = pop_code_generator::generate_pop_code(segment, index, &self.file_name);
         self.generated_code.append(&mut new_code);
     }
 
-    pub fn write_label(&mut self, label_name: &str) {
+    fn write_label(&mut self, label_name: &str) {
         let mut new_code = vec![format!(
             "({}${})",
             self.function_name_stack.last().unwrap(),
@@ -58,7 +136,7 @@ impl CodeWriter {
         self.generated_code.append(&mut new_code)
     }
 
-    pub fn write_go_to(&mut self, label_name: &str) {
+    fn write_go_to(&mut self, label_name: &str) {
         let mut new_code = vec![
             format!(
                 "@{}${}",
@@ -70,7 +148,7 @@ impl CodeWriter {
         self.generated_code.append(&mut new_code)
     }
 
-    pub fn write_if_go_to(&mut self, label_name: &str) {
+    fn write_if_go_to(&mut self, label_name: &str) {
         let mut new_code = vec![
             "@SP".to_string(),
             "AM=M-1".to_string(),
@@ -86,7 +164,7 @@ impl CodeWriter {
         self.generated_code.append(&mut new_code);
     }
 
-    pub fn write_call(&mut self, function_name: &str, n_arg: &str) {
+    fn write_call(&mut self, function_name: &str, n_arg: &str) {
         let return_address = self.return_address_generator.generate_new_return_address();
         let mut new_code: Vec<String> = vec![];
 
@@ -137,7 +215,7 @@ impl CodeWriter {
         self.generated_code.append(&mut new_code);
     }
 
-    pub fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
         use arithmetic_command::{ArithmeticCommand, ArithmeticCommand::*};
         let mut new_code = match ArithmeticCommand::from_str(arithmetic_command) {
             Some(ADD) => arithmetic_code_generator::add(),
@@ -163,7 +241,7 @@ impl CodeWriter {
         self.generated_code.append(&mut new_code);
     }
 
-    pub fn write_function(&mut self, function_name: &str, num_locals: &str) {
+    fn write_function(&mut self, function_name: &str, num_locals: &str) {
         let mut new_code: Vec<String> = vec![];
         self.function_name_stack.push(function_name.to_string());
         new_code.push(format!("({})", function_name));
@@ -176,7 +254,7 @@ impl CodeWriter {
         self.generated_code.append(&mut new_code);
     }
 
-    pub fn write_return(&mut self) {
+    fn write_return(&mut self) {
         let mut new_code = vec![
             "@LCL".to_string(),
             "D=M".to_string(),
@@ -288,6 +366,67 @@ mod test {
         assert_eq!(code_writer.generated_code, expected_result)
     }
 
+    #[test]
+    fn source_map_records_vm_locations() {
+        use crate::program::{Program, VmFile};
+        let source = "// comment\nfunction Foo.bar 0\nlabel L\npush constant 7\nreturn";
+        let program = Program::new(vec![VmFile::new(
+            "Foo.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let mut code_writer = CodeWriter::new("Foo.vm".to_string());
+        program.translate(&mut code_writer);
+        let source_map = code_writer.source_map();
+        assert_eq!(source_map.len(), 6);
+        assert_eq!(
+            source_map[2],
+            "    {\"start\": 0, \"end\": 7, \"file\": \"Foo.vm\", \"line\": 4, \"function\": \"Foo.bar\", \"command\": \"push constant 7\"},"
+        );
+        assert!(source_map[3].starts_with("    {\"start\": 7, \"end\": 63, "));
+    }
+
+    #[test]
+    fn annotated_output() {
+        use crate::program::{Program, VmFile};
+        let source = "push constant 1\nfunction Foo.bar 0\nlabel L";
+        let program = Program::new(vec![VmFile::new(
+            "dir/Foo.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let mut plain = CodeWriter::new("dir/Foo.vm".to_string());
+        program.translate(&mut plain);
+        let mut annotated = CodeWriter::new("dir/Foo.vm".to_string());
+        annotated.set_annotate(true);
+        program.translate(&mut annotated);
+
+        let mut expected_result = vec!["// Foo.vm:1 push constant 1".to_string()];
+        expected_result.extend(plain.generated_code[..7].iter().cloned());
+        expected_result.push("// function Foo.bar".to_string());
+        expected_result.push("// Foo.vm:2 function Foo.bar 0".to_string());
+        expected_result.push("(Foo.bar)".to_string());
+        expected_result.push("// Foo.vm:3 label L".to_string());
+        expected_result.push("(Foo.bar$L)".to_string());
+        assert_eq!(annotated.generated_code, expected_result);
+        // コメントはROMのアドレスを変えない
+        assert_eq!(annotated.source_map(), plain.source_map());
+    }
+
+    #[test]
+    fn push_static_uses_file_name() {
+        let expected_result = vec![
+            "@MyClass.3".to_string(),
+            "D=M".to_string(),
+            "@SP".to_string(),
+            "A=M".to_string(),
+            "M=D".to_string(),
+            "@SP".to_string(),
+            "M=M+1".to_string(),
+        ];
+        let mut code_writer = CodeWriter::new("path/to/MyClass.vm".to_string());
+        code_writer.push("static", "3");
+        assert_eq!(code_writer.generated_code, expected_result)
+    }
+
     #[test]
     fn write_label() {
         let expected_result = ["(null$b)".to_string()];
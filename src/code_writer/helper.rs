@@ -1,11 +1,38 @@
-pub fn camel_case_filename_without_extention(filename: &str) -> String {
-    let splited_filename: Vec<&str> = filename.rsplit("/").collect();
-    let without_extention = splited_filename[0].replace(".vm", "").to_lowercase();
-    let mut file_name_char = without_extention.chars();
-    match file_name_char.next() {
-        None => String::new(),
-        Some(c) => c.to_uppercase().collect::<String>() + file_name_char.as_str(),
+// static変数の名前に使うので、ファイル名はそのままの大文字小文字で使う
+pub fn filename_without_extention(filename: &str) -> String {
+    let splited_filename: Vec<&str> = filename.rsplit('/').collect();
+    let file_name = splited_filename[0];
+    file_name
+        .strip_suffix(".vm")
+        .unwrap_or(file_name)
+        .to_string()
+}
+
+// アセンブラやCの識別子に使えない文字を_と16進数に置き換える
+pub fn mangle_symbol(name: &str) -> String {
+    let mut mangled = String::new();
+    for c in name.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => mangled.push(c),
+            '_' => mangled.push_str("__"),
+            _ => mangled.push_str(&format!("_{:02x}", c as u32)),
+        }
+    }
+    mangled
+}
+
+// JSONの文字列リテラル
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
     }
+    format!("\"{}\"", escaped)
 }
 
 #[cfg(test)]
@@ -13,9 +40,19 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_camel_case_filename_without_extention() {
-        let expected_result = "Filename";
-        let result = camel_case_filename_without_extention("../path/to/filename.vm");
+    fn test_filename_without_extention() {
+        let expected_result = "filename";
+        let result = filename_without_extention("../path/to/filename.vm");
         assert_eq!(result, expected_result)
     }
+
+    #[test]
+    fn filename_keeps_case() {
+        assert_eq!(filename_without_extention("MyClass.vm"), "MyClass");
+    }
+
+    #[test]
+    fn test_mangle_symbol() {
+        assert_eq!(mangle_symbol("Main.main$LOOP_1"), "Main_2emain_24LOOP__1");
+    }
 }
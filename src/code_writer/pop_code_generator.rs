@@ -1,5 +1,5 @@
 use crate::code_writer::constant::{POINTER_BASE_ADDRESS, TEMP_BASE_ADDRESS};
-use crate::code_writer::helper::camel_case_filename_without_extention;
+use crate::code_writer::helper::filename_without_extention;
 use crate::code_writer::segment::Segment;
 
 pub fn generate_pop_code(segment: &str, index: &str, file_name: &str) -> Vec<String> {
@@ -13,7 +13,7 @@ pub fn generate_pop_code(segment: &str, index: &str, file_name: &str) -> Vec<Str
         Some(Segment::POINTER) => pop_pointer_and_temp(index, POINTER_BASE_ADDRESS),
         Some(Segment::TEMP) => pop_pointer_and_temp(index, TEMP_BASE_ADDRESS),
         Some(Segment::STATIC) => {
-            let constant_name = camel_case_filename_without_extention(file_name);
+            let constant_name = filename_without_extention(file_name);
             pop_static(index, &constant_name)
         }
         _ => vec![],
@@ -12,6 +12,13 @@ pub enum Segment {
 
 impl Segment {
     pub fn from_str(s: &str) -> Option<Segment> {
+        match Segment::parse(s) {
+            None => panic!("Invalid Segment"),
+            segment => segment,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Segment> {
         match s {
             "argument" => Some(Segment::ARGUMENT),
             "local" => Some(Segment::LOCAL),
@@ -21,7 +28,7 @@ impl Segment {
             "that" => Some(Segment::THAT),
             "pointer" => Some(Segment::POINTER),
             "temp" => Some(Segment::TEMP),
-            _ => panic!("Invalid Segment"),
+            _ => None,
         }
     }
 
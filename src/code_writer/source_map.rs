@@ -0,0 +1,125 @@
+use super::helper::json_string;
+
+// 生成したコードの位置と元のVMコマンドの対応
+pub struct SourceLocation {
+    pub file_name: String,
+    pub line: usize,
+    pub function: Option<String>,
+    pub command: String,
+    // generated_codeの何行目からか
+    pub code_index: usize,
+}
+
+// ラベルやコメントはROMに置かれない
+fn is_instruction(line: &str) -> bool {
+    !(line.is_empty() || line.starts_with('(') || line.starts_with("//"))
+}
+
+// ROMアドレスの範囲 [start, end) とVMの位置
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMapping {
+    pub start: usize,
+    pub end: usize,
+    pub file_name: String,
+    pub line: usize,
+    pub function: Option<String>,
+    pub command: String,
+}
+
+pub fn generate_mappings(code: &[String], locations: &[SourceLocation]) -> Vec<SourceMapping> {
+    let mut rom_addresses = vec![0];
+    for line in code {
+        let address = rom_addresses.last().unwrap() + is_instruction(line) as usize;
+        rom_addresses.push(address);
+    }
+
+    let mut mappings = vec![];
+    for (i, location) in locations.iter().enumerate() {
+        let end_index = locations
+            .get(i + 1)
+            .map_or(code.len(), |next| next.code_index);
+        let start = rom_addresses[location.code_index];
+        let end = rom_addresses[end_index];
+        if start == end {
+            continue;
+        }
+        mappings.push(SourceMapping {
+            start,
+            end,
+            file_name: location.file_name.clone(),
+            line: location.line,
+            function: location.function.clone(),
+            command: location.command.clone(),
+        });
+    }
+    mappings
+}
+
+// ROMアドレスの範囲ごとにVMの位置を書いたJSON
+pub fn generate_source_map(code: &[String], locations: &[SourceLocation]) -> Vec<String> {
+    let mappings = generate_mappings(code, locations);
+    let mut lines = vec!["{".to_string(), "  \"mappings\": [".to_string()];
+    for (i, mapping) in mappings.iter().enumerate() {
+        lines.push(format!(
+            "    {{\"start\": {}, \"end\": {}, \"file\": {}, \"line\": {}, \"function\": {}, \"command\": {}}}{}",
+            mapping.start,
+            mapping.end,
+            json_string(&mapping.file_name),
+            mapping.line,
+            mapping
+                .function
+                .as_deref()
+                .map_or("null".to_string(), json_string),
+            json_string(&mapping.command),
+            if i + 1 < mappings.len() { "," } else { "" }
+        ));
+    }
+    lines.push("  ]".to_string());
+    lines.push("}".to_string());
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn location(
+        line: usize,
+        function: Option<&str>,
+        command: &str,
+        code_index: usize,
+    ) -> SourceLocation {
+        SourceLocation {
+            file_name: "dir/Foo.vm".to_string(),
+            line,
+            function: function.map(|f| f.to_string()),
+            command: command.to_string(),
+            code_index,
+        }
+    }
+
+    #[test]
+    fn maps_rom_ranges() {
+        let code: Vec<String> = ["@1", "D=A", "(Foo.bar)", "(Foo.bar$L)", "@SP", "\"x\""]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let locations = [
+            location(1, None, "push constant 1", 0),
+            location(2, Some("Foo.bar"), "function Foo.bar 0", 2),
+            location(3, Some("Foo.bar"), "label L", 3),
+            location(4, Some("Foo.bar"), "add\"", 4),
+        ];
+        assert_eq!(
+            generate_source_map(&code, &locations),
+            [
+                "{",
+                "  \"mappings\": [",
+                "    {\"start\": 0, \"end\": 2, \"file\": \"dir/Foo.vm\", \"line\": 1, \"function\": null, \"command\": \"push constant 1\"},",
+                "    {\"start\": 2, \"end\": 4, \"file\": \"dir/Foo.vm\", \"line\": 4, \"function\": \"Foo.bar\", \"command\": \"add\\\"\"}",
+                "  ]",
+                "}",
+            ]
+        );
+    }
+}
@@ -0,0 +1,574 @@
+use crate::code_writer::helper::filename_without_extention;
+use crate::code_writer::segment::Segment;
+use crate::parser::CommandType;
+use crate::program::TOP_LEVEL;
+use crate::vm_interpreter::VmInterpreter;
+use std::io::{self, prelude::*};
+
+// continueなどで止まらないプログラムのために1回に実行する上限
+const RESUME_STEP_LIMIT: usize = 10_000_000;
+// backtraceで辿るフレームの上限
+const BACKTRACE_LIMIT: usize = 100;
+
+const HELP: [&str; 14] = [
+    "break|b FUNCTION|FILE:LINE|LABEL  set a breakpoint",
+    "watch ADDRESS                     stop when RAM[ADDRESS] changes",
+    "delete NUMBER                     delete a breakpoint or watchpoint",
+    "continue|c                        run until a breakpoint, watchpoint or halt",
+    "step|s                            execute one VM command",
+    "next|n                            execute one VM command, stepping over calls",
+    "finish                            run until the current function returns",
+    "where                             show the next VM command",
+    "segments|info                     show the segments of the current frame",
+    "backtrace|bt                      show the call stack",
+    "x ADDRESS [COUNT]                 show RAM",
+    "set ADDRESS VALUE                 write RAM",
+    "help                              show this help",
+    "quit|q                            exit the debugger",
+];
+
+struct Breakpoint {
+    number: usize,
+    description: String,
+    command_indices: Vec<usize>,
+}
+
+struct Watchpoint {
+    number: usize,
+    address: i16,
+    value: i16,
+}
+
+pub struct Debugger {
+    interpreter: VmInterpreter,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_number: usize,
+    // 実行中の関数呼び出しの深さ
+    depth: usize,
+}
+
+impl Debugger {
+    pub fn new(interpreter: VmInterpreter) -> Debugger {
+        Debugger {
+            interpreter,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            next_number: 1,
+            depth: 0,
+        }
+    }
+
+    pub fn repl(&mut self, input: &mut dyn BufRead) {
+        println!("{}", self.location());
+        loop {
+            print!("(vmdb) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            if matches!(line.trim(), "quit" | "q") {
+                break;
+            }
+            for output in self.execute(&line) {
+                println!("{}", output);
+            }
+        }
+    }
+
+    // 1行のコマンドを実行して表示する内容を返す
+    pub fn execute(&mut self, line: &str) -> Vec<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |i: usize| words.get(i).and_then(|word| word.parse::<i16>().ok());
+        match words.as_slice() {
+            [] => vec![],
+            ["break" | "b", target] => self.add_breakpoint(target),
+            ["watch", _] => match number(1) {
+                Some(address) => self.add_watchpoint(address),
+                None => vec![format!("Invalid address: {}", words[1])],
+            },
+            ["delete", _] => self.delete(words[1]),
+            ["continue" | "c"] => self.resume(None),
+            ["step" | "s"] => self.resume(Some(usize::MAX)),
+            ["next" | "n"] => self.resume(Some(self.depth)),
+            ["finish"] => self.resume(self.depth.checked_sub(1)),
+            ["where"] => vec![self.location()],
+            ["segments" | "info"] => self.segments(),
+            ["backtrace" | "bt"] => self.backtrace(),
+            ["x", _] | ["x", _, _] => {
+                match (number(1), words.get(2).map_or(Some(1), |_| number(2))) {
+                    (Some(address), Some(count)) => (address..address.saturating_add(count))
+                        .map(|a| format!("RAM[{}] = {}", a, self.interpreter.get(a)))
+                        .collect(),
+                    _ => vec!["Usage: x ADDRESS [COUNT]".to_string()],
+                }
+            }
+            ["set", _, _] => match (number(1), number(2)) {
+                (Some(address), Some(value)) => {
+                    self.interpreter.set(address, value);
+                    for watchpoint in &mut self.watchpoints {
+                        watchpoint.value = self.interpreter.get(watchpoint.address);
+                    }
+                    vec![]
+                }
+                _ => vec!["Usage: set ADDRESS VALUE".to_string()],
+            },
+            ["help"] => HELP.iter().map(|line| line.to_string()).collect(),
+            _ => vec![format!("Unknown command: {}", line.trim())],
+        }
+    }
+
+    fn describe(&self, command_index: usize) -> String {
+        let command = &self.interpreter.commands()[command_index];
+        let function_name = match self.interpreter.function_name(command_index) {
+            "null" => TOP_LEVEL,
+            function_name => function_name,
+        };
+        format!(
+            "{}:{} {}: {}",
+            self.interpreter.file_name(command_index),
+            command.line,
+            function_name,
+            command
+        )
+    }
+
+    // 次に実行するコマンド
+    fn location(&self) -> String {
+        if self.interpreter.halted {
+            return "The program is not running".to_string();
+        }
+        self.describe(self.interpreter.pc)
+    }
+
+    fn resolve(&self, target: &str) -> Vec<usize> {
+        if let Some(i) = self.interpreter.function_address(target) {
+            return vec![i];
+        }
+        // ファイル名:行番号 は、その行以降の最初のコマンドに止める
+        if let Some((file_name, line)) = target.rsplit_once(':') {
+            if let Ok(line) = line.parse::<usize>() {
+                let stem = filename_without_extention(file_name);
+                return (0..self.interpreter.commands().len())
+                    .find(|&i| {
+                        filename_without_extention(self.interpreter.file_name(i)) == stem
+                            && self.interpreter.commands()[i].line >= line
+                    })
+                    .into_iter()
+                    .collect();
+            }
+        }
+        self.interpreter.label_addresses(target)
+    }
+
+    fn add_breakpoint(&mut self, target: &str) -> Vec<String> {
+        let command_indices = self.resolve(target);
+        if command_indices.is_empty() {
+            return vec![format!("No function, file:line or label named {}", target)];
+        }
+        let number = self.next_number;
+        self.next_number += 1;
+        let message = match command_indices.as_slice() {
+            [i] => format!("Breakpoint {} at {}", number, self.describe(*i)),
+            _ => format!(
+                "Breakpoint {} at {} locations",
+                number,
+                command_indices.len()
+            ),
+        };
+        self.breakpoints.push(Breakpoint {
+            number,
+            description: target.to_string(),
+            command_indices,
+        });
+        vec![message]
+    }
+
+    fn add_watchpoint(&mut self, address: i16) -> Vec<String> {
+        let number = self.next_number;
+        self.next_number += 1;
+        self.watchpoints.push(Watchpoint {
+            number,
+            address,
+            value: self.interpreter.get(address),
+        });
+        vec![format!("Watchpoint {}: RAM[{}]", number, address)]
+    }
+
+    fn delete(&mut self, number: &str) -> Vec<String> {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.number.to_string() != number);
+        self.watchpoints.retain(|w| w.number.to_string() != number);
+        match self.breakpoints.len() + self.watchpoints.len() < count {
+            true => vec![],
+            false => vec![format!("No breakpoint number {}", number)],
+        }
+    }
+
+    // 1コマンド実行し、呼び出しの深さを追う
+    fn step(&mut self) {
+        let command = self.interpreter.commands()[self.interpreter.pc].clone();
+        self.interpreter.step();
+        match command.command_type {
+            // ネイティブのOSの関数はフレームを作らないので、関数の先頭に移ったときだけ深くなる
+            CommandType::CALL
+                if !self.interpreter.halted
+                    && self
+                        .interpreter
+                        .function_address(command.arg1.as_deref().unwrap_or_default())
+                        == Some(self.interpreter.pc) =>
+            {
+                self.depth += 1
+            }
+            CommandType::RETURN => self.depth = self.depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    // 呼び出しの深さがstop_depth以下になるか、ブレークポイントなどで止まるまで実行する
+    fn resume(&mut self, stop_depth: Option<usize>) -> Vec<String> {
+        if self.interpreter.halted {
+            return vec![self.location()];
+        }
+        for _ in 0..RESUME_STEP_LIMIT {
+            let current = self.interpreter.pc;
+            self.step();
+            if self.interpreter.halted {
+                return vec![format!(
+                    "Program halted after {} steps",
+                    self.interpreter.steps
+                )];
+            }
+
+            let mut messages = vec![];
+            for watchpoint in &mut self.watchpoints {
+                let value = self.interpreter.get(watchpoint.address);
+                if value != watchpoint.value {
+                    messages.push(format!(
+                        "Watchpoint {}: RAM[{}] {} -> {}",
+                        watchpoint.number, watchpoint.address, watchpoint.value, value
+                    ));
+                    watchpoint.value = value;
+                }
+            }
+            let pc = self.interpreter.pc;
+            for breakpoint in &self.breakpoints {
+                if breakpoint.command_indices.contains(&pc) {
+                    messages.push(format!(
+                        "Breakpoint {}, {}",
+                        breakpoint.number, breakpoint.description
+                    ));
+                }
+            }
+            // Sys.waitなどで同じcallを繰り返している間は次のコマンドに進んでいない
+            let waiting = self.interpreter.pc == current;
+            if !messages.is_empty()
+                || (!waiting && stop_depth.is_some_and(|depth| self.depth <= depth))
+            {
+                messages.push(self.location());
+                return messages;
+            }
+        }
+        vec![
+            format!("Stopped after {} steps", RESUME_STEP_LIMIT),
+            self.location(),
+        ]
+    }
+
+    // 現在の関数が参照するインデックスの最大値+1
+    fn referenced_count(&self, segment: Segment) -> usize {
+        let commands = self.interpreter.commands();
+        let function_name = self.interpreter.function_name(self.interpreter.pc);
+        (0..commands.len())
+            .filter(|&i| self.interpreter.function_name(i) == function_name)
+            .filter(|&i| {
+                matches!(
+                    commands[i].command_type,
+                    CommandType::PUSH | CommandType::POP
+                ) && Segment::parse(commands[i].arg1.as_deref().unwrap_or_default()).as_ref()
+                    == Some(&segment)
+            })
+            .filter_map(|i| commands[i].arg2.as_deref()?.parse::<usize>().ok())
+            .map(|index| index + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn segment_values(&self, base: i16, count: usize) -> String {
+        (0..count)
+            .map(|i| format!(" {}", self.interpreter.get(base.wrapping_add(i as i16))))
+            .collect()
+    }
+
+    fn segments(&self) -> Vec<String> {
+        if self.interpreter.halted {
+            return vec![self.location()];
+        }
+        let pc = self.interpreter.pc;
+        // localの数はfunctionコマンドで宣言された数
+        let num_locals = self
+            .interpreter
+            .function_address(self.interpreter.function_name(pc))
+            .and_then(|i| {
+                self.interpreter.commands()[i]
+                    .arg2
+                    .as_deref()?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .unwrap_or(0);
+        let local_count = num_locals.max(self.referenced_count(Segment::LOCAL));
+
+        let mut lines = vec![];
+        for (name, register, count) in [
+            ("local", 1, local_count),
+            ("argument", 2, self.referenced_count(Segment::ARGUMENT)),
+            ("this", 3, self.referenced_count(Segment::THIS)),
+            ("that", 4, self.referenced_count(Segment::THAT)),
+        ] {
+            let base = self.interpreter.get(register);
+            lines.push(format!(
+                "{} (RAM[{}] = {}):{}",
+                name,
+                register,
+                base,
+                self.segment_values(base, count)
+            ));
+        }
+        lines.push(format!("pointer:{}", self.segment_values(3, 2)));
+        lines.push(format!("temp:{}", self.segment_values(5, 8)));
+        let statics = self
+            .interpreter
+            .static_variables(self.interpreter.file_name(pc))
+            .into_iter()
+            .map(|(name, address)| format!(" {}={}", name, self.interpreter.get(address as i16)))
+            .collect::<String>();
+        lines.push(format!("static:{}", statics));
+        lines
+    }
+
+    // 保存されたフレームを辿って呼び出し元を求める
+    fn backtrace(&self) -> Vec<String> {
+        if self.interpreter.halted {
+            return vec![self.location()];
+        }
+        let mut lines = vec![format!("#0 {}", self.describe(self.interpreter.pc))];
+        let mut command_index = self.interpreter.pc;
+        let mut frame = self.interpreter.get(1);
+        while lines.len() < BACKTRACE_LIMIT
+            && self.interpreter.function_name(command_index) != "null"
+        {
+            let return_address = self.interpreter.get(frame.wrapping_sub(5));
+            match self.interpreter.return_command(return_address) {
+                Some(i) => {
+                    // 戻り先の1つ前がcallコマンド
+                    command_index = i - 1;
+                    lines.push(format!("#{} {}", lines.len(), self.describe(command_index)));
+                    frame = self.interpreter.get(frame.wrapping_sub(4));
+                }
+                None => break,
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::{Program, VmFile};
+
+    fn debugger() -> Debugger {
+        let main = "push constant 3
+call Main.double 1
+pop static 0
+label END
+goto END
+function Main.double 1
+push argument 0
+call Main.twice 1
+pop local 0
+push local 0
+return";
+        let twice = "function Main.twice 0
+push argument 0
+push argument 0
+add
+return";
+        let program = Program::new(vec![
+            VmFile::new("Main.vm", main.lines().map(|l| l.to_string()).collect()),
+            VmFile::new("Twice.vm", twice.lines().map(|l| l.to_string()).collect()),
+        ]);
+        let mut debugger = Debugger::new(VmInterpreter::from_program(&program));
+        for (address, value) in [(0, 256), (1, 300), (2, 400)] {
+            debugger.execute(&format!("set {} {}", address, value));
+        }
+        debugger
+    }
+
+    #[test]
+    fn breakpoints_and_backtrace() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("break Main.twice"),
+            ["Breakpoint 1 at Twice.vm:1 Main.twice: function Main.twice 0"]
+        );
+        assert_eq!(
+            debugger.execute("continue"),
+            [
+                "Breakpoint 1, Main.twice",
+                "Twice.vm:1 Main.twice: function Main.twice 0"
+            ]
+        );
+        assert_eq!(
+            debugger.execute("bt"),
+            [
+                "#0 Twice.vm:1 Main.twice: function Main.twice 0",
+                "#1 Main.vm:8 Main.double: call Main.twice 1",
+                "#2 Main.vm:2 (top level): call Main.double 1",
+            ]
+        );
+        assert_eq!(
+            debugger.execute("finish"),
+            ["Main.vm:9 Main.double: pop local 0"]
+        );
+        assert_eq!(
+            debugger.execute("segments"),
+            [
+                "local (RAM[1] = 262): 0",
+                "argument (RAM[2] = 256): 3",
+                "this (RAM[3] = 0):",
+                "that (RAM[4] = 0):",
+                "pointer: 0 0",
+                "temp: 0 0 0 0 0 0 0 0",
+                "static: Main.0=0",
+            ]
+        );
+    }
+
+    #[test]
+    fn starts_from_sys_init() {
+        let sys = "function Sys.init 0
+push constant 2
+push constant 3
+call Main.add 2
+pop static 0
+label END
+goto END";
+        let main = "function Main.add 0
+push argument 0
+push argument 1
+add
+return";
+        let program = Program::new(vec![
+            VmFile::new("Main.vm", main.lines().map(|l| l.to_string()).collect()),
+            VmFile::new("Sys.vm", sys.lines().map(|l| l.to_string()).collect()),
+        ]);
+        let mut debugger = Debugger::new(VmInterpreter::prepare(&program, &[(3, 3000)]));
+        assert_eq!(
+            debugger.execute("where"),
+            ["Sys.vm:1 Sys.init: function Sys.init 0"]
+        );
+        debugger.execute("break Main.add");
+        assert_eq!(
+            debugger.execute("c"),
+            [
+                "Breakpoint 1, Main.add",
+                "Main.vm:1 Main.add: function Main.add 0"
+            ]
+        );
+        assert_eq!(
+            debugger.execute("x 0 4"),
+            [
+                "RAM[0] = 268",
+                "RAM[1] = 268",
+                "RAM[2] = 261",
+                "RAM[3] = 3000"
+            ]
+        );
+        assert_eq!(
+            debugger.execute("bt"),
+            [
+                "#0 Main.vm:1 Main.add: function Main.add 0",
+                "#1 Sys.vm:4 Sys.init: call Main.add 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn step_next_and_watchpoints() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("break Main.vm:3"),
+            ["Breakpoint 1 at Main.vm:3 (top level): pop static 0"]
+        );
+        assert_eq!(
+            debugger.execute("step"),
+            ["Main.vm:2 (top level): call Main.double 1"]
+        );
+        assert_eq!(
+            debugger.execute("step"),
+            ["Main.vm:6 Main.double: function Main.double 1"]
+        );
+        assert_eq!(
+            debugger.execute("n"),
+            ["Main.vm:7 Main.double: push argument 0"]
+        );
+        assert_eq!(debugger.execute("watch 16"), ["Watchpoint 2: RAM[16]"]);
+        assert_eq!(
+            debugger.execute("c"),
+            [
+                "Breakpoint 1, Main.vm:3",
+                "Main.vm:3 (top level): pop static 0"
+            ]
+        );
+        assert_eq!(debugger.execute("x 256"), ["RAM[256] = 6"]);
+        assert_eq!(
+            debugger.execute("c"),
+            [
+                "Watchpoint 2: RAM[16] 0 -> 6",
+                "Main.vm:4 (top level): label END"
+            ]
+        );
+        assert_eq!(debugger.execute("delete 2"), Vec::<String>::new());
+        assert_eq!(debugger.execute("delete 2"), ["No breakpoint number 2"]);
+        assert_eq!(
+            debugger.execute("frobnicate"),
+            ["Unknown command: frobnicate"]
+        );
+    }
+
+    #[test]
+    fn next_over_native_calls() {
+        let source = "push constant 6
+push constant 7
+call Math.multiply 2
+pop static 0
+push constant 3
+call Sys.wait 1
+pop temp 0
+label END
+goto END";
+        let program = Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let mut debugger = Debugger::new(VmInterpreter::prepare(&program, &[(0, 256)]));
+        debugger.execute("n");
+        debugger.execute("n");
+        assert_eq!(
+            debugger.execute("n"),
+            ["Main.vm:4 (top level): pop static 0"]
+        );
+        assert_eq!(debugger.execute("x 256"), ["RAM[256] = 42"]);
+        debugger.execute("n");
+        debugger.execute("n");
+        assert_eq!(debugger.execute("n"), ["Main.vm:7 (top level): pop temp 0"]);
+        assert_eq!(
+            debugger.execute("step"),
+            ["Main.vm:8 (top level): label END"]
+        );
+        assert_eq!(debugger.depth, 0);
+    }
+}
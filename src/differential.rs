@@ -0,0 +1,331 @@
+// VMインタプリタと、翻訳したHackをエミュレータで動かした結果を1コマンドずつ比べる
+use crate::hack_emulator::{HackEmulator, HackProgram};
+use crate::parser::CommandType;
+use crate::program::Program;
+use crate::vm_interpreter::VmInterpreter;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// 1つのVMコマンドに使うHackの命令数の上限
+const HACK_STEP_LIMIT: usize = 10000;
+
+// 両方を同じ状態から始めて、VMコマンドを実行するたびにSPやスタック、
+// セグメントなど書き込まれたメモリを比べる。比べたコマンド数を返す
+pub fn compare(program: &Program, ram: &[(i16, i16)], limit: usize) -> Result<usize, String> {
+    let hack_program = HackProgram::new(program)?;
+    let mut interpreter = VmInterpreter::from_program(program);
+    let mut emulator = HackEmulator::new(hack_program.rom.clone());
+    if let (Some(vm_entry), Some(&hack_entry)) = (
+        interpreter.function_address("Sys.init"),
+        hack_program.labels.get("Sys.init"),
+    ) {
+        interpreter.boot(vm_entry);
+        emulator.boot(hack_entry);
+    }
+    for &(address, value) in ram {
+        interpreter.set(address, value);
+        emulator.set(address, value);
+    }
+
+    // コマンドごとのコードのROMアドレス。コードのないコマンドは次のコマンドの位置
+    let ranges: HashMap<(&str, usize), (usize, usize)> = hack_program
+        .mappings
+        .iter()
+        .map(|m| ((m.file_name.as_str(), m.line), (m.start, m.end)))
+        .collect();
+    let commands = interpreter.commands().to_vec();
+    let mut rom_starts = vec![hack_program.rom.len(); commands.len() + 1];
+    let mut has_code = vec![false; commands.len()];
+    for i in (0..commands.len()).rev() {
+        match ranges.get(&(interpreter.file_name(i), commands[i].line)) {
+            Some(&(start, _)) => {
+                rom_starts[i] = start;
+                has_code[i] = true;
+            }
+            None => rom_starts[i] = rom_starts[i + 1],
+        }
+    }
+    let ret_address = hack_program.symbols.get("RET").map(|&a| a as usize);
+
+    let mut touched: BTreeSet<usize> = interpreter.writes.iter().map(|&(a, _)| a).collect();
+    touched.extend(emulator.writes.iter().map(|&(a, _)| a));
+    // 戻り先はインタプリタでは何番目のcallか、HackではROMアドレスなので比べない
+    let mut return_addresses: HashSet<usize> = HashSet::new();
+    let mut compared = 0;
+    while !interpreter.halted && interpreter.steps < limit {
+        let current = interpreter.pc;
+        let command = &commands[current];
+        interpreter.step();
+        if command.command_type == CommandType::LABEL {
+            continue;
+        }
+        // 戻り先がないreturnや存在しないラベルへのジャンプはHackと動作が揃わない
+        if interpreter.halted && interpreter.pc < commands.len() {
+            break;
+        }
+        if interpreter.halted
+            && matches!(
+                command.command_type,
+                CommandType::GOTO | CommandType::IF | CommandType::CALL | CommandType::RETURN
+            )
+        {
+            break;
+        }
+        for (i, &(address, _)) in interpreter.writes.iter().enumerate() {
+            let is_return_address = match command.command_type {
+                CommandType::CALL => i == 0,
+                CommandType::RETURN => Some(address) == ret_address,
+                _ => false,
+            };
+            match is_return_address {
+                true => return_addresses.insert(address),
+                false => return_addresses.remove(&address),
+            };
+            touched.insert(address);
+        }
+
+        let location = format!(
+            "step {} {}:{} {}",
+            interpreter.steps,
+            interpreter.file_name(current),
+            command.line,
+            command
+        );
+        let target = rom_starts[interpreter.pc.min(commands.len())];
+        let mut hack_steps = 0;
+        while (hack_steps == 0 && has_code[current]) || emulator.pc as usize != target {
+            if emulator.halted() || hack_steps >= HACK_STEP_LIMIT {
+                return Err(format!(
+                    "{}: Hack is at PC={} but the next command starts at {}",
+                    location, emulator.pc, target
+                ));
+            }
+            emulator.step();
+            touched.extend(emulator.writes.iter().map(|&(a, _)| a));
+            hack_steps += 1;
+        }
+
+        for &address in &touched {
+            let vm_value = interpreter.ram[address];
+            let hack_value = emulator.ram[address];
+            if vm_value != hack_value && !return_addresses.contains(&address) {
+                return Err(format!(
+                    "{}: RAM[{}] is {} in the VM interpreter but {} in Hack",
+                    location, address, vm_value, hack_value
+                ));
+            }
+        }
+        compared += 1;
+    }
+    Ok(compared)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator::{GeneratedProgram, Random};
+    use crate::program::VmFile;
+
+    fn program(source: &str) -> Program {
+        Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )])
+    }
+
+    // SP LCL ARG THIS THAT
+    const RAM: [(i16, i16); 5] = [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)];
+
+    fn assert_same(source: &str) {
+        let commands = source.lines().count();
+        let result = compare(&program(source), &RAM, 1000);
+        assert!(result.is_ok(), "{}\n{}", source, result.unwrap_err());
+        assert!(result.unwrap() > 0 || commands == 0);
+    }
+
+    #[test]
+    fn arithmetic_commands() {
+        for command in ["add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not"] {
+            for (x, y) in [(7, 7), (3, 9), (9, 3), (32767, 1), (0, 0)] {
+                assert_same(&format!(
+                    "push constant {}\npush constant {}\nneg\n{}",
+                    x, y, command
+                ));
+            }
+        }
+        // 差がオーバーフローする比較
+        assert_same("push constant 32767\npush constant 2\nadd\npush constant 2\nlt");
+        assert_same("push constant 20000\nneg\npush constant 20000\ngt");
+    }
+
+    #[test]
+    fn memory_commands() {
+        for (segment, index) in [
+            ("local", 3),
+            ("argument", 2),
+            ("this", 5),
+            ("that", 1),
+            ("pointer", 0),
+            ("pointer", 1),
+            ("temp", 7),
+            ("static", 4),
+        ] {
+            assert_same(&format!(
+                "push constant 42\npop {0} {1}\npush {0} {1}\npush {0} {1}\nadd",
+                segment, index
+            ));
+        }
+    }
+
+    #[test]
+    fn program_flow_commands() {
+        assert_same(
+            "push constant 0
+pop local 0
+label LOOP
+push local 0
+push constant 1
+add
+pop local 0
+push local 0
+push constant 5
+lt
+if-goto LOOP
+goto END
+push constant 99
+label END",
+        );
+    }
+
+    #[test]
+    fn function_commands() {
+        assert_same(
+            "function Sys.init 0
+push constant 4
+call Main.fact 1
+pop static 0
+label HALT
+goto HALT
+function Main.fact 2
+push argument 0
+push constant 2
+lt
+if-goto BASE
+push argument 0
+push argument 0
+push constant 1
+sub
+call Main.fact 1
+call Main.multiply 2
+return
+label BASE
+push constant 1
+return
+function Main.multiply 1
+push constant 0
+pop local 0
+label LOOP
+push argument 1
+push constant 0
+eq
+if-goto END
+push local 0
+push argument 0
+add
+pop local 0
+push argument 1
+push constant 1
+sub
+pop argument 1
+goto LOOP
+label END
+push local 0
+return",
+        );
+        let source = "function Sys.init 0\ncall Main.f 0\nlabel HALT\ngoto HALT\nfunction Main.f 0\npush constant 3\nreturn";
+        assert_eq!(compare(&program(source), &[], 100), Ok(100));
+    }
+
+    // スタックが足りなくならない、前方にだけジャンプするプログラム
+    fn random_program(random: &mut Random) -> String {
+        let segments = [
+            ("local", 8),
+            ("argument", 8),
+            ("this", 8),
+            ("that", 8),
+            ("pointer", 2),
+            ("temp", 8),
+            ("static", 8),
+        ];
+        let operators = ["add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not"];
+        let mut lines = vec![];
+        let mut depth = 0;
+        let mut labels = 0;
+        for _ in 0..40 {
+            match random.below(6) {
+                0 | 1 => {
+                    let value = [0, 1, 2, 255, 16384, 32767][random.below(6)];
+                    lines.push(format!("push constant {}", value));
+                    depth += 1;
+                }
+                2 => {
+                    let (segment, size) = segments[random.below(segments.len())];
+                    // thisとthatのベースを変えないようにpointerは読むだけ
+                    lines.push(format!("push {} {}", segment, random.below(size)));
+                    depth += 1;
+                }
+                3 if depth > 0 => {
+                    let (segment, size) = segments[random.below(segments.len())];
+                    let segment = if segment == "pointer" {
+                        "temp"
+                    } else {
+                        segment
+                    };
+                    lines.push(format!("pop {} {}", segment, random.below(size)));
+                    depth -= 1;
+                }
+                4 if depth > 1 => {
+                    let operator = operators[random.below(operators.len())];
+                    lines.push(operator.to_string());
+                    if !["neg", "not"].contains(&operator) {
+                        depth -= 1;
+                    }
+                }
+                5 if depth > 0 => {
+                    lines.push(format!("if-goto L{}", labels));
+                    lines.push(format!("push constant {}", random.below(100)));
+                    lines.push(format!("label L{}", labels));
+                    labels += 1;
+                    depth -= 1;
+                }
+                _ => (),
+            }
+        }
+        lines.join("\n")
+    }
+
+    #[test]
+    fn random_programs() {
+        let mut random = Random::new(0x2545f4914f6cdd1d);
+        for _ in 0..200 {
+            assert_same(&random_program(&mut random));
+        }
+    }
+
+    // 関数呼び出しを含むプログラム。失敗したら最小の再現例にして表示する
+    #[test]
+    fn generated_programs() {
+        let mut random = Random::new(42);
+        let fails = |p: &GeneratedProgram| compare(&p.to_program(), &[], 2000).is_err();
+        for _ in 0..100 {
+            let program = GeneratedProgram::generate(&mut random);
+            if fails(&program) {
+                let minimal = program.minimize(fails);
+                panic!(
+                    "{}\n{}",
+                    minimal.to_vm().join("\n"),
+                    compare(&minimal.to_program(), &[], 2000).unwrap_err()
+                );
+            }
+        }
+    }
+}
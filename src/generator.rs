@@ -0,0 +1,496 @@
+// テスト用にランダムなVMプログラムを作る。どの文もスタックの深さを変えず、
+// 式は値を1つだけ積むので、スタックは常に釣り合う
+use crate::program::{Program, VmFile};
+
+const MAX_FUNCTIONS: usize = 4;
+const MAX_ARGUMENTS: usize = 3;
+const MAX_LOCALS: usize = 3;
+const MAX_STATEMENTS: usize = 5;
+const MAX_EXPRESSION_DEPTH: usize = 3;
+// 各関数は第0引数で残りの呼び出しの深さを受け取り、0なら何もせずに返る
+const RECURSION_DEPTH: i16 = 2;
+
+const CONSTANTS: [i16; 8] = [0, 1, 2, 3, 255, 1000, 16384, 32767];
+const UNARY_OPERATORS: [&str; 2] = ["neg", "not"];
+const BINARY_OPERATORS: [&str; 7] = ["add", "sub", "eq", "gt", "lt", "and", "or"];
+// thisとthatが指すヒープの範囲
+const HEAP_BASES: [i16; 3] = [2048, 3000, 4000];
+
+// 再現できるように固定のシードを使う xorshift
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random(seed.max(1))
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+
+    fn choose<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    CONSTANT(i16),
+    PUSH(&'static str, usize),
+    UNARY(&'static str, Box<Expression>),
+    BINARY(&'static str, Box<Expression>, Box<Expression>),
+    // 呼び出す関数の番号と、深さを除いた引数
+    CALL(usize, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    POP(&'static str, usize, Expression),
+    // this/thatのベースをヒープ内のアドレスにする
+    POINTER(usize, i16),
+    // 式が0でなければ中の文を実行する
+    IF(Expression, Vec<Statement>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedFunction {
+    // 深さを含めた引数の数
+    pub n_args: usize,
+    pub n_locals: usize,
+    pub body: Vec<Statement>,
+    pub result: Expression,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedProgram {
+    pub functions: Vec<GeneratedFunction>,
+}
+
+fn function_name(index: usize) -> String {
+    format!("Main.f{}", index)
+}
+
+struct Scope<'a> {
+    n_args: usize,
+    n_locals: usize,
+    // 呼び出せる関数の引数の数
+    functions: &'a [usize],
+}
+
+impl Scope<'_> {
+    fn segments(&self) -> Vec<(&'static str, usize)> {
+        let mut segments = vec![("temp", 8), ("static", 8), ("this", 8), ("that", 8)];
+        segments.push(("argument", self.n_args));
+        if self.n_locals > 0 {
+            segments.push(("local", self.n_locals));
+        }
+        segments
+    }
+}
+
+fn generate_expression(random: &mut Random, scope: &Scope, depth: usize) -> Expression {
+    let kind = match depth {
+        0 => random.below(2),
+        _ => random.below(5),
+    };
+    match kind {
+        0 => Expression::CONSTANT(random.choose(&CONSTANTS)),
+        1 => {
+            let (segment, size) = random.choose(&scope.segments());
+            Expression::PUSH(segment, random.below(size))
+        }
+        2 => Expression::UNARY(
+            random.choose(&UNARY_OPERATORS),
+            Box::new(generate_expression(random, scope, depth - 1)),
+        ),
+        3 => Expression::BINARY(
+            random.choose(&BINARY_OPERATORS),
+            Box::new(generate_expression(random, scope, depth - 1)),
+            Box::new(generate_expression(random, scope, depth - 1)),
+        ),
+        _ => {
+            let function = random.below(scope.functions.len());
+            let args = (1..scope.functions[function])
+                .map(|_| generate_expression(random, scope, depth - 1))
+                .collect();
+            Expression::CALL(function, args)
+        }
+    }
+}
+
+fn generate_statements(random: &mut Random, scope: &Scope, depth: usize) -> Vec<Statement> {
+    let mut statements = vec![];
+    for _ in 0..random.below(MAX_STATEMENTS + 1) {
+        let statement = match random.below(6) {
+            0 if depth > 0 => Statement::IF(
+                generate_expression(random, scope, MAX_EXPRESSION_DEPTH),
+                generate_statements(random, scope, depth - 1),
+            ),
+            1 => Statement::POINTER(random.below(2), random.choose(&HEAP_BASES)),
+            _ => {
+                let (segment, index) = match random.choose(&scope.segments()) {
+                    // 第0引数の深さは書き換えない
+                    ("argument", 1) => ("temp", random.below(8)),
+                    ("argument", size) => ("argument", random.below(size - 1) + 1),
+                    (segment, size) => (segment, random.below(size)),
+                };
+                Statement::POP(
+                    segment,
+                    index,
+                    generate_expression(random, scope, MAX_EXPRESSION_DEPTH),
+                )
+            }
+        };
+        statements.push(statement);
+    }
+    statements
+}
+
+impl GeneratedProgram {
+    pub fn generate(random: &mut Random) -> GeneratedProgram {
+        let n_args: Vec<usize> = (0..random.below(MAX_FUNCTIONS) + 1)
+            .map(|_| random.below(MAX_ARGUMENTS) + 1)
+            .collect();
+        let mut functions = vec![];
+        for &n in &n_args {
+            let scope = Scope {
+                n_args: n,
+                n_locals: random.below(MAX_LOCALS + 1),
+                functions: &n_args,
+            };
+            functions.push(GeneratedFunction {
+                n_args: scope.n_args,
+                n_locals: scope.n_locals,
+                body: generate_statements(random, &scope, 2),
+                result: generate_expression(random, &scope, MAX_EXPRESSION_DEPTH),
+            });
+        }
+        GeneratedProgram { functions }
+    }
+
+    // Sys.initでthis/thatをヒープに向けてから最初の関数を呼んで、終わったら止まる
+    pub fn to_vm(&self) -> Vec<String> {
+        let mut lines = vec!["function Sys.init 0".to_string()];
+        for (index, base) in [(0, HEAP_BASES[0]), (1, HEAP_BASES[1])] {
+            lines.push(format!("push constant {}", base));
+            lines.push(format!("pop pointer {}", index));
+        }
+        lines.push(format!("push constant {}", RECURSION_DEPTH));
+        for _ in 1..self.functions[0].n_args {
+            lines.push("push constant 0".to_string());
+        }
+        lines.push(format!(
+            "call {} {}",
+            function_name(0),
+            self.functions[0].n_args
+        ));
+        lines.push("pop temp 0".to_string());
+        lines.push("label HALT".to_string());
+        lines.push("goto HALT".to_string());
+
+        for (i, function) in self.functions.iter().enumerate() {
+            let mut writer = FunctionWriter {
+                program: self,
+                lines: &mut lines,
+                labels: 0,
+            };
+            writer.write_function(i, function);
+        }
+        lines
+    }
+
+    pub fn to_program(&self) -> Program {
+        Program::new(vec![VmFile::new("Main.vm", self.to_vm())])
+    }
+
+    // 1段階だけ小さくしたプログラムの候補
+    pub fn shrink(&self) -> Vec<GeneratedProgram> {
+        let mut candidates = vec![];
+        // 最初の関数以外を消して、その呼び出しは0にする
+        for removed in 1..self.functions.len() {
+            let functions = self
+                .functions
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != removed)
+                .map(|(_, function)| GeneratedFunction {
+                    n_args: function.n_args,
+                    n_locals: function.n_locals,
+                    body: remove_calls_in_statements(&function.body, removed),
+                    result: remove_calls(&function.result, removed),
+                })
+                .collect();
+            candidates.push(GeneratedProgram { functions });
+        }
+        for (i, function) in self.functions.iter().enumerate() {
+            let mut replace = |function: GeneratedFunction| {
+                let mut program = self.clone();
+                program.functions[i] = function;
+                candidates.push(program);
+            };
+            for body in shrink_statements(&function.body) {
+                replace(GeneratedFunction {
+                    body,
+                    ..function.clone()
+                });
+            }
+            for result in shrink_expression(&function.result) {
+                replace(GeneratedFunction {
+                    result,
+                    ..function.clone()
+                });
+            }
+        }
+        candidates
+    }
+
+    // failsが真のまま、これ以上小さくできなくなるまで縮める
+    pub fn minimize(&self, fails: impl Fn(&GeneratedProgram) -> bool) -> GeneratedProgram {
+        let mut program = self.clone();
+        while let Some(smaller) = program.shrink().into_iter().find(|p| fails(p)) {
+            program = smaller;
+        }
+        program
+    }
+}
+
+fn remove_calls(expression: &Expression, removed: usize) -> Expression {
+    match expression {
+        Expression::CALL(function, _) if *function == removed => Expression::CONSTANT(0),
+        Expression::CALL(function, args) => Expression::CALL(
+            function - (*function > removed) as usize,
+            args.iter().map(|arg| remove_calls(arg, removed)).collect(),
+        ),
+        Expression::UNARY(operator, x) => {
+            Expression::UNARY(operator, Box::new(remove_calls(x, removed)))
+        }
+        Expression::BINARY(operator, x, y) => Expression::BINARY(
+            operator,
+            Box::new(remove_calls(x, removed)),
+            Box::new(remove_calls(y, removed)),
+        ),
+        _ => expression.clone(),
+    }
+}
+
+fn remove_calls_in_statements(statements: &[Statement], removed: usize) -> Vec<Statement> {
+    statements
+        .iter()
+        .map(|statement| match statement {
+            Statement::POP(segment, index, value) => {
+                Statement::POP(segment, *index, remove_calls(value, removed))
+            }
+            Statement::IF(condition, body) => Statement::IF(
+                remove_calls(condition, removed),
+                remove_calls_in_statements(body, removed),
+            ),
+            _ => statement.clone(),
+        })
+        .collect()
+}
+
+fn shrink_expression(expression: &Expression) -> Vec<Expression> {
+    let mut candidates = vec![];
+    match expression {
+        Expression::CONSTANT(0) => (),
+        Expression::CONSTANT(n) => {
+            candidates.push(Expression::CONSTANT(0));
+            if n / 2 != 0 {
+                candidates.push(Expression::CONSTANT(n / 2));
+            }
+        }
+        Expression::PUSH(_, _) => candidates.push(Expression::CONSTANT(0)),
+        Expression::UNARY(operator, x) => {
+            candidates.push(*x.clone());
+            for x in shrink_expression(x) {
+                candidates.push(Expression::UNARY(operator, Box::new(x)));
+            }
+        }
+        Expression::BINARY(operator, x, y) => {
+            candidates.push(*x.clone());
+            candidates.push(*y.clone());
+            for x in shrink_expression(x) {
+                candidates.push(Expression::BINARY(operator, Box::new(x), y.clone()));
+            }
+            for y in shrink_expression(y) {
+                candidates.push(Expression::BINARY(operator, x.clone(), Box::new(y)));
+            }
+        }
+        Expression::CALL(function, args) => {
+            candidates.push(Expression::CONSTANT(0));
+            for i in 0..args.len() {
+                for arg in shrink_expression(&args[i]) {
+                    let mut args = args.clone();
+                    args[i] = arg;
+                    candidates.push(Expression::CALL(*function, args));
+                }
+            }
+        }
+    }
+    candidates
+}
+
+fn shrink_statements(statements: &[Statement]) -> Vec<Vec<Statement>> {
+    let mut candidates = vec![];
+    for i in 0..statements.len() {
+        let replace = |replacement: Vec<Statement>| {
+            let mut statements = statements.to_vec();
+            statements.splice(i..i + 1, replacement);
+            statements
+        };
+        candidates.push(replace(vec![]));
+        match &statements[i] {
+            Statement::POP(segment, index, value) => {
+                for value in shrink_expression(value) {
+                    candidates.push(replace(vec![Statement::POP(segment, *index, value)]));
+                }
+            }
+            Statement::POINTER(_, _) => (),
+            Statement::IF(condition, body) => {
+                candidates.push(replace(body.clone()));
+                for condition in shrink_expression(condition) {
+                    candidates.push(replace(vec![Statement::IF(condition, body.clone())]));
+                }
+                for body in shrink_statements(body) {
+                    candidates.push(replace(vec![Statement::IF(condition.clone(), body)]));
+                }
+            }
+        }
+    }
+    candidates
+}
+
+struct FunctionWriter<'a> {
+    program: &'a GeneratedProgram,
+    lines: &'a mut Vec<String>,
+    labels: usize,
+}
+
+impl FunctionWriter<'_> {
+    fn write_function(&mut self, index: usize, function: &GeneratedFunction) {
+        self.lines.push(format!(
+            "function {} {}",
+            function_name(index),
+            function.n_locals
+        ));
+        // 深さが残っていなければ0を返す
+        self.lines.push("push argument 0".to_string());
+        self.lines.push("push constant 0".to_string());
+        self.lines.push("gt".to_string());
+        self.lines.push("if-goto BODY".to_string());
+        self.lines.push("push constant 0".to_string());
+        self.lines.push("return".to_string());
+        self.lines.push("label BODY".to_string());
+        for statement in &function.body {
+            self.write_statement(statement);
+        }
+        self.write_expression(&function.result);
+        self.lines.push("return".to_string());
+    }
+
+    fn write_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::POP(segment, index, value) => {
+                self.write_expression(value);
+                self.lines.push(format!("pop {} {}", segment, index));
+            }
+            Statement::POINTER(index, base) => {
+                self.lines.push(format!("push constant {}", base));
+                self.lines.push(format!("pop pointer {}", index));
+            }
+            Statement::IF(condition, body) => {
+                let label = format!("SKIP{}", self.labels);
+                self.labels += 1;
+                self.write_expression(condition);
+                self.lines.push("not".to_string());
+                self.lines.push(format!("if-goto {}", label));
+                for statement in body {
+                    self.write_statement(statement);
+                }
+                self.lines.push(format!("label {}", label));
+            }
+        }
+    }
+
+    fn write_expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::CONSTANT(value) => self.lines.push(format!("push constant {}", value)),
+            Expression::PUSH(segment, index) => {
+                self.lines.push(format!("push {} {}", segment, index))
+            }
+            Expression::UNARY(operator, x) => {
+                self.write_expression(x);
+                self.lines.push(operator.to_string());
+            }
+            Expression::BINARY(operator, x, y) => {
+                self.write_expression(x);
+                self.write_expression(y);
+                self.lines.push(operator.to_string());
+            }
+            Expression::CALL(function, args) => {
+                self.lines.push("push argument 0".to_string());
+                self.lines.push("push constant 1".to_string());
+                self.lines.push("sub".to_string());
+                for arg in args {
+                    self.write_expression(arg);
+                }
+                self.lines.push(format!(
+                    "call {} {}",
+                    function_name(*function),
+                    self.program.functions[*function].n_args
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::stack_depth;
+    use crate::validator;
+
+    #[test]
+    fn generates_valid_programs() {
+        let mut random = Random::new(1);
+        for _ in 0..100 {
+            let program = GeneratedProgram::generate(&mut random).to_program();
+            let mut errors = validator::validate(&program);
+            errors.append(&mut validator::check_calls(&program, &[]));
+            errors.append(&mut stack_depth::check(&program));
+            assert_eq!(errors, Vec::<String>::new());
+            let mut parser = Parser::new(
+                program.files[0]
+                    .commands
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect(),
+            );
+            assert_eq!(
+                parser.collect_commands().len(),
+                program.files[0].commands.len()
+            );
+        }
+    }
+
+    #[test]
+    fn minimize_to_smallest_failure() {
+        let mut random = Random::new(7);
+        let program = (0..)
+            .map(|_| GeneratedProgram::generate(&mut random))
+            .find(|p| p.functions.len() == MAX_FUNCTIONS)
+            .unwrap();
+        // 関数が2つ以上あると失敗するとみなす
+        let minimal = program.minimize(|p| p.functions.len() >= 2);
+        assert_eq!(minimal.functions.len(), 2);
+        for function in &minimal.functions {
+            assert!(function.body.is_empty());
+            assert_eq!(function.result, Expression::CONSTANT(0));
+        }
+        assert!(minimal.to_vm().len() < program.to_vm().len());
+    }
+}
@@ -0,0 +1,540 @@
+use crate::code_writer::helper::filename_without_extention;
+use crate::code_writer::source_map::SourceMapping;
+use crate::hack_emulator::{HackEmulator, HackProgram};
+use crate::program::{Program, TOP_LEVEL};
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+// continueなどで止まらないプログラムのために1回に実行する上限
+const RESUME_STEP_LIMIT: usize = 100_000_000;
+// スタックはRAM[256]から
+const STACK_BASE_ADDRESS: i16 = 256;
+// 辿るフレームの上限
+const FRAME_LIMIT: usize = 100;
+// write_callが積む順
+const SAVED_FRAME: [&str; 5] = [
+    "return address",
+    "saved LCL",
+    "saved ARG",
+    "saved THIS",
+    "saved THAT",
+];
+
+const HELP: [&str; 15] = [
+    "break|b ROM|FUNCTION|FILE:LINE|LABEL  set a breakpoint",
+    "delete NUMBER                         delete a breakpoint",
+    "continue|c                            run until a breakpoint or halt",
+    "stepi|si [COUNT]                      execute Hack instructions",
+    "step|s                                execute one VM command",
+    "next|n                                execute one VM command, stepping over calls",
+    "finish                                run until the current function returns",
+    "where                                 show the Hack PC and the VM command",
+    "registers|regs                        show A, D and the VM pointers",
+    "stack                                 decode RAM[256..SP]",
+    "frame                                 decode the current frame",
+    "x ADDRESS [COUNT]                     show RAM",
+    "set ADDRESS VALUE                     write RAM",
+    "help                                  show this help",
+    "quit|q                                exit the debugger",
+];
+
+struct Frame {
+    function: String,
+    lcl: i16,
+    arg: i16,
+    num_args: i16,
+    num_locals: i16,
+}
+
+pub struct HackDebugger {
+    emulator: HackEmulator,
+    // ROMアドレスごとのアセンブリ
+    instructions: Vec<String>,
+    labels: HashMap<String, u16>,
+    mappings: Vec<SourceMapping>,
+    // ROMアドレスごとのmappingsの位置
+    mapping_indices: Vec<Option<usize>>,
+    breakpoints: Vec<(usize, u16)>,
+    next_number: usize,
+    // 実行中の関数呼び出しの深さ
+    depth: usize,
+}
+
+impl HackDebugger {
+    pub fn new(program: &Program, ram: &[(i16, i16)]) -> Result<HackDebugger, String> {
+        let hack_program = HackProgram::new(program)?;
+        Ok(HackDebugger {
+            emulator: HackEmulator::prepare(&hack_program, ram),
+            mapping_indices: hack_program.mapping_indices(),
+            instructions: hack_program.instructions,
+            labels: hack_program.labels,
+            mappings: hack_program.mappings,
+            breakpoints: vec![],
+            next_number: 1,
+            depth: 0,
+        })
+    }
+
+    pub fn repl(&mut self, input: &mut dyn BufRead) {
+        println!("{}", self.location());
+        loop {
+            print!("(hackdb) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            if matches!(line.trim(), "quit" | "q") {
+                break;
+            }
+            for output in self.execute(&line) {
+                println!("{}", output);
+            }
+        }
+    }
+
+    // 1行のコマンドを実行して表示する内容を返す
+    pub fn execute(&mut self, line: &str) -> Vec<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |i: usize| words.get(i).and_then(|word| word.parse::<i16>().ok());
+        match words.as_slice() {
+            [] => vec![],
+            ["break" | "b", target] => self.add_breakpoint(target),
+            ["delete", _] => {
+                let count = self.breakpoints.len();
+                self.breakpoints.retain(|(n, _)| n.to_string() != words[1]);
+                match self.breakpoints.len() < count {
+                    true => vec![],
+                    false => vec![format!("No breakpoint number {}", words[1])],
+                }
+            }
+            ["continue" | "c"] => self.resume(|_| false),
+            ["stepi" | "si"] | ["stepi" | "si", _] => {
+                let count = words.get(1).map_or(Some(1), |_| number(1));
+                match count {
+                    Some(count) => {
+                        let mut steps = 0;
+                        self.resume(move |_| {
+                            steps += 1;
+                            steps >= count
+                        })
+                    }
+                    None => vec!["Usage: stepi [COUNT]".to_string()],
+                }
+            }
+            ["step" | "s"] => self.resume(|debugger| debugger.at_command_start()),
+            ["next" | "n"] => {
+                let depth = self.depth;
+                self.resume(move |debugger| debugger.at_command_start() && debugger.depth <= depth)
+            }
+            ["finish"] => {
+                let depth = self.depth;
+                self.resume(move |debugger| debugger.at_command_start() && debugger.depth < depth)
+            }
+            ["where"] => vec![self.location()],
+            ["registers" | "regs"] => vec![format!(
+                "A={} D={} PC={} SP={} LCL={} ARG={} THIS={} THAT={}",
+                self.emulator.a,
+                self.emulator.d,
+                self.emulator.pc,
+                self.emulator.get(0),
+                self.emulator.get(1),
+                self.emulator.get(2),
+                self.emulator.get(3),
+                self.emulator.get(4)
+            )],
+            ["stack"] => self.stack(),
+            ["frame"] => self.frame(),
+            ["x", _] | ["x", _, _] => {
+                match (number(1), words.get(2).map_or(Some(1), |_| number(2))) {
+                    (Some(address), Some(count)) => (address..address.saturating_add(count))
+                        .map(|a| format!("RAM[{}] = {}", a, self.emulator.get(a)))
+                        .collect(),
+                    _ => vec!["Usage: x ADDRESS [COUNT]".to_string()],
+                }
+            }
+            ["set", _, _] => match (number(1), number(2)) {
+                (Some(address), Some(value)) => {
+                    self.emulator.set(address, value);
+                    vec![]
+                }
+                _ => vec!["Usage: set ADDRESS VALUE".to_string()],
+            },
+            ["help"] => HELP.iter().map(|line| line.to_string()).collect(),
+            _ => vec![format!("Unknown command: {}", line.trim())],
+        }
+    }
+
+    fn mapping_at(&self, rom_address: u16) -> Option<&SourceMapping> {
+        let index = self.mapping_indices.get(rom_address as usize).copied()??;
+        Some(&self.mappings[index])
+    }
+
+    // VMコマンドの最初の命令にいるかどうか
+    fn at_command_start(&self) -> bool {
+        self.mapping_at(self.emulator.pc)
+            .is_some_and(|mapping| mapping.start == self.emulator.pc as usize)
+    }
+
+    fn describe(mapping: &SourceMapping) -> String {
+        format!(
+            "{}:{} {}: {}",
+            mapping.file_name,
+            mapping.line,
+            mapping.function.as_deref().unwrap_or(TOP_LEVEL),
+            mapping.command
+        )
+    }
+
+    // 次に実行する命令とVMコマンド
+    fn location(&self) -> String {
+        if self.emulator.halted() {
+            return "The program is not running".to_string();
+        }
+        let pc = self.emulator.pc;
+        format!(
+            "PC={} {:<12} [{}]",
+            pc,
+            self.instructions[pc as usize],
+            self.mapping_at(pc)
+                .map_or("?".to_string(), HackDebugger::describe)
+        )
+    }
+
+    fn add_breakpoint(&mut self, target: &str) -> Vec<String> {
+        let function_start = format!("function {} ", target);
+        let file_line = target
+            .rsplit_once(':')
+            .and_then(|(file_name, line)| Some((file_name, line.parse::<usize>().ok()?)));
+        let rom_address = if let Ok(address) = target.parse::<u16>() {
+            Some(address)
+        } else if let Some(mapping) = self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.command.starts_with(&function_start))
+        {
+            Some(mapping.start as u16)
+        } else if let Some((file_name, line)) = file_line {
+            // その行以降の最初のコマンドに止める
+            let stem = filename_without_extention(file_name);
+            self.mappings
+                .iter()
+                .find(|mapping| {
+                    filename_without_extention(&mapping.file_name) == stem && mapping.line >= line
+                })
+                .map(|mapping| mapping.start as u16)
+        } else {
+            self.labels.get(target).copied()
+        };
+
+        match rom_address {
+            Some(address) if (address as usize) < self.instructions.len() => {
+                let number = self.next_number;
+                self.next_number += 1;
+                self.breakpoints.push((number, address));
+                vec![format!("Breakpoint {} at PC={}", number, address)]
+            }
+            _ => vec![format!(
+                "No ROM address, function, file:line or label named {}",
+                target
+            )],
+        }
+    }
+
+    // stopがtrueを返すか、ブレークポイントか停止まで1命令ずつ実行する
+    fn resume<F: FnMut(&HackDebugger) -> bool>(&mut self, mut stop: F) -> Vec<String> {
+        if self.emulator.halted() {
+            return vec![self.location()];
+        }
+        for _ in 0..RESUME_STEP_LIMIT {
+            self.step();
+            if self.emulator.halted() {
+                return vec![format!(
+                    "Program halted after {} instructions",
+                    self.emulator.steps
+                )];
+            }
+            let pc = self.emulator.pc;
+            if let Some((number, _)) = self.breakpoints.iter().find(|(_, a)| *a == pc) {
+                return vec![format!("Breakpoint {}", number), self.location()];
+            }
+            if stop(self) {
+                return vec![self.location()];
+            }
+        }
+        vec![
+            format!("Stopped after {} instructions", RESUME_STEP_LIMIT),
+            self.location(),
+        ]
+    }
+
+    // 1命令実行し、callやreturnのコードから飛び出したら呼び出しの深さを変える
+    fn step(&mut self) {
+        let before = self
+            .mapping_at(self.emulator.pc)
+            .map(|mapping| (mapping.start, mapping.end, mapping.command.clone()));
+        self.emulator.step();
+        if let Some((start, end, command)) = before {
+            let pc = self.emulator.pc as usize;
+            if pc < start || end <= pc {
+                if command.starts_with("call ") {
+                    self.depth += 1;
+                } else if command == "return" {
+                    self.depth = self.depth.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    fn num_locals(&self, function: &str) -> i16 {
+        let function_start = format!("function {} ", function);
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.command.starts_with(&function_start))
+            .and_then(|mapping| mapping.command.rsplit(' ').next()?.parse::<i16>().ok())
+            .unwrap_or(0)
+    }
+
+    // LCLから保存されたフレームを辿る
+    fn frames(&self) -> Vec<Frame> {
+        let mut frames = vec![];
+        let mut function = self
+            .mapping_at(self.emulator.pc)
+            .and_then(|mapping| mapping.function.clone());
+        let mut lcl = self.emulator.get(1);
+        let mut arg = self.emulator.get(2);
+        while let Some(name) = function {
+            // 壊れたLCLでも引き算が溢れないように、スタックの外なら辿らない
+            let saved = match lcl.checked_sub(5) {
+                Some(saved) if saved >= STACK_BASE_ADDRESS && arg <= saved => saved,
+                _ => break,
+            };
+            if frames.len() >= FRAME_LIMIT {
+                break;
+            }
+            frames.push(Frame {
+                num_locals: self.num_locals(&name),
+                function: name,
+                lcl,
+                arg,
+                num_args: saved - arg,
+            });
+            // 戻り先の1つ前はcallコマンドの最後の命令
+            let return_address = self.emulator.get(saved);
+            function = self
+                .mapping_at((return_address as u16).wrapping_sub(1))
+                .filter(|mapping| mapping.command.starts_with("call "))
+                .and_then(|mapping| mapping.function.clone());
+            arg = self.emulator.get(saved + 2);
+            lcl = self.emulator.get(saved + 1);
+        }
+        frames
+    }
+
+    fn frame_lines(&self, frame: &Frame) -> Vec<(i16, String)> {
+        let mut lines = vec![];
+        for i in 0..frame.num_args {
+            lines.push((
+                frame.arg + i,
+                format!("argument {} of {}", i, frame.function),
+            ));
+        }
+        for (i, name) in SAVED_FRAME.iter().enumerate() {
+            lines.push((
+                frame.lcl - 5 + i as i16,
+                format!("{} of {}", name, frame.function),
+            ));
+        }
+        for i in 0..frame.num_locals {
+            lines.push((
+                frame.lcl.wrapping_add(i),
+                format!("local {} of {}", i, frame.function),
+            ));
+        }
+        lines
+    }
+
+    fn show(&self, address: i16, description: &str) -> String {
+        let value = self.emulator.get(address);
+        let mut line = format!("RAM[{}] = {:<6} {}", address, value, description);
+        // 戻り先はどのcallの後かも示す
+        if description.starts_with("return address") {
+            if let Some(mapping) = self.mapping_at((value as u16).wrapping_sub(1)) {
+                line.push_str(&format!(" (after {})", HackDebugger::describe(mapping)));
+            }
+        }
+        line.trim_end().to_string()
+    }
+
+    fn stack(&self) -> Vec<String> {
+        let mut descriptions: HashMap<i16, String> = HashMap::new();
+        for frame in self.frames() {
+            descriptions.extend(self.frame_lines(&frame));
+        }
+        (STACK_BASE_ADDRESS..self.emulator.get(0))
+            .map(|address| {
+                let description = descriptions.get(&address).map_or("", |d| d.as_str());
+                self.show(address, description)
+            })
+            .collect()
+    }
+
+    fn frame(&self) -> Vec<String> {
+        match self.frames().first() {
+            Some(frame) => self
+                .frame_lines(frame)
+                .iter()
+                .map(|(address, description)| self.show(*address, description))
+                .collect(),
+            None => vec!["No frame".to_string()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::VmFile;
+
+    fn debugger() -> HackDebugger {
+        let source = "push constant 3
+call Main.double 1
+pop temp 0
+function Main.double 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+return";
+        let program = Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let mut debugger = HackDebugger::new(&program, &[]).unwrap();
+        for (address, value) in [(0, 256), (1, 300), (2, 400)] {
+            debugger.execute(&format!("set {} {}", address, value));
+        }
+        debugger
+    }
+
+    #[test]
+    fn step_by_instruction_and_command() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("where"),
+            ["PC=0 @3           [Main.vm:1 (top level): push constant 3]"]
+        );
+        assert_eq!(
+            debugger.execute("si 2"),
+            ["PC=2 @SP          [Main.vm:1 (top level): push constant 3]"]
+        );
+        assert_eq!(
+            debugger.execute("step"),
+            ["PC=7 @Return_address.1 [Main.vm:2 (top level): call Main.double 1]"]
+        );
+        assert_eq!(
+            debugger.execute("next"),
+            ["PC=56 @5           [Main.vm:3 (top level): pop temp 0]"]
+        );
+        assert_eq!(debugger.execute("x 256"), ["RAM[256] = 6"]);
+    }
+
+    #[test]
+    fn breakpoints_and_stack() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("break Main.vm:8"),
+            ["Breakpoint 1 at PC=105"]
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            [
+                "Breakpoint 1",
+                "PC=105 @LCL         [Main.vm:8 Main.double: pop local 0]"
+            ]
+        );
+        assert_eq!(
+            debugger.execute("stack"),
+            [
+                "RAM[256] = 3      argument 0 of Main.double",
+                "RAM[257] = 56     return address of Main.double (after Main.vm:2 (top level): call Main.double 1)",
+                "RAM[258] = 300    saved LCL of Main.double",
+                "RAM[259] = 400    saved ARG of Main.double",
+                "RAM[260] = 0      saved THIS of Main.double",
+                "RAM[261] = 0      saved THAT of Main.double",
+                "RAM[262] = 0      local 0 of Main.double",
+                "RAM[263] = 6",
+            ]
+        );
+        assert_eq!(debugger.execute("frame").len(), 7);
+        assert_eq!(
+            debugger.execute("finish"),
+            ["PC=56 @5           [Main.vm:3 (top level): pop temp 0]"]
+        );
+    }
+
+    #[test]
+    fn starts_from_sys_init() {
+        let main = "function Main.add 0
+push argument 0
+push argument 1
+add
+return";
+        let sys = "function Sys.init 0
+push constant 2
+push constant 3
+call Main.add 2
+label LOOP
+goto LOOP";
+        let program = Program::new(
+            [("Main.vm", main), ("Sys.vm", sys)]
+                .iter()
+                .map(|(file_name, source)| {
+                    VmFile::new(file_name, source.lines().map(|l| l.to_string()).collect())
+                })
+                .collect(),
+        );
+        let mut debugger = HackDebugger::new(&program, &[(3, 3000)]).unwrap();
+        assert_eq!(
+            debugger.execute("where"),
+            ["PC=86 @2           [Sys.vm:2 Sys.init: push constant 2]"]
+        );
+        debugger.execute("b Main.add");
+        assert_eq!(debugger.execute("c")[0], "Breakpoint 1");
+        assert_eq!(
+            debugger.execute("x 0 4"),
+            [
+                "RAM[0] = 268",
+                "RAM[1] = 268",
+                "RAM[2] = 261",
+                "RAM[3] = 3000"
+            ]
+        );
+        assert_eq!(
+            debugger.execute("stack")[5..7],
+            [
+                "RAM[261] = 2      argument 0 of Main.add",
+                "RAM[262] = 3      argument 1 of Main.add"
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_frame_pointers() {
+        let mut debugger = debugger();
+        debugger.execute("break Main.double");
+        debugger.execute("c");
+        for lcl in [-32768, -32764, 260] {
+            debugger.execute(&format!("set 1 {}", lcl));
+            assert_eq!(debugger.execute("frame"), ["No frame"]);
+            assert_eq!(debugger.execute("stack").len(), 6);
+        }
+        // RAMの端にあるフレームでも溢れない
+        debugger.execute("set 1 32767");
+        debugger.execute("set 2 32760");
+        let frame = debugger.execute("frame");
+        assert_eq!(frame.len(), 8);
+        assert_eq!(frame[7], "RAM[32767] = 0      local 0 of Main.double");
+    }
+}
@@ -0,0 +1,412 @@
+// Hackのアセンブラと、機械語を実行するCPUエミュレータ
+use crate::code_writer::source_map::SourceMapping;
+use crate::code_writer::CodeWriter;
+use crate::program::Program;
+use crate::static_allocation::STATIC_BASE_ADDRESS;
+use std::collections::HashMap;
+
+pub const RAM_SIZE: usize = 32768;
+
+const PREDEFINED_SYMBOLS: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+// aビットを除いたcompのビット (zx nx zy ny f no)
+const COMPUTATIONS: [(&str, u16); 18] = [
+    ("0", 0b101010),
+    ("1", 0b111111),
+    ("-1", 0b111010),
+    ("D", 0b001100),
+    ("A", 0b110000),
+    ("!D", 0b001101),
+    ("!A", 0b110001),
+    ("-D", 0b001111),
+    ("-A", 0b110011),
+    ("D+1", 0b011111),
+    ("A+1", 0b110111),
+    ("D-1", 0b001110),
+    ("A-1", 0b110010),
+    ("D+A", 0b000010),
+    ("D-A", 0b010011),
+    ("A-D", 0b000111),
+    ("D&A", 0b000000),
+    ("D|A", 0b010101),
+];
+
+const JUMPS: [&str; 8] = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+fn is_instruction(line: &str) -> bool {
+    !(line.is_empty() || line.starts_with('(') || line.starts_with("//"))
+}
+
+fn encode_computation(comp: &str) -> Option<u16> {
+    let a_bit = comp.contains('M') as u16;
+    let comp = comp.replace('M', "A");
+    // 交換できる演算は逆順でも受け付ける
+    let swapped = match comp.split_once(['+', '&', '|']) {
+        Some((x, y)) if x == "A" && y == "D" => {
+            format!("D{}A", &comp[1..2])
+        }
+        _ => comp.clone(),
+    };
+    COMPUTATIONS
+        .iter()
+        .find(|(mnemonic, _)| *mnemonic == comp || *mnemonic == swapped)
+        .map(|(_, bits)| a_bit << 12 | bits << 6)
+}
+
+fn encode_c_instruction(instruction: &str) -> Option<u16> {
+    let (dest, rest) = match instruction.split_once('=') {
+        Some((dest, rest)) => (dest, rest),
+        None => ("", instruction),
+    };
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+    if !dest.chars().all(|c| "AMD".contains(c)) {
+        return None;
+    }
+    let dest_bits = (dest.contains('A') as u16) << 5
+        | (dest.contains('D') as u16) << 4
+        | (dest.contains('M') as u16) << 3;
+    let jump_bits = JUMPS.iter().position(|&j| j == jump)? as u16;
+    Some(0b111 << 13 | encode_computation(comp)? | dest_bits | jump_bits)
+}
+
+// ラベルを解決して機械語に変換する。シンボルのアドレスも返す
+pub fn assemble(code: &[String]) -> Result<(Vec<u16>, HashMap<String, u16>), String> {
+    let mut symbols: HashMap<String, u16> = PREDEFINED_SYMBOLS
+        .iter()
+        .map(|&(name, address)| (name.to_string(), address))
+        .chain((0..16).map(|i| (format!("R{}", i), i)))
+        .collect();
+
+    let mut address = 0;
+    for line in code {
+        let line = line.trim();
+        if let Some(label) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            symbols.insert(label.to_string(), address);
+        } else if is_instruction(line) {
+            address += 1;
+        }
+    }
+    // ROMは32K語
+    if address > 0x8000 {
+        return Err(format!(
+            "Program does not fit in ROM: {} instructions",
+            address
+        ));
+    }
+
+    let mut rom = vec![];
+    let mut next_variable = STATIC_BASE_ADDRESS as u16;
+    for line in code.iter().map(|line| line.trim()) {
+        if !is_instruction(line) {
+            continue;
+        }
+        let instruction = match line.strip_prefix('@') {
+            Some(value) => match value.parse::<u16>() {
+                Ok(value) if value < 0x8000 => value,
+                Ok(_) => return Err(format!("Constant out of range: {}", line)),
+                // A命令で読めるのは15ビットまで
+                Err(_) => match symbols.get(value) {
+                    Some(&address) if address >= 0x8000 => {
+                        return Err(format!(
+                            "Program does not fit in ROM: {} is at {}",
+                            value, address
+                        ))
+                    }
+                    Some(&address) => address,
+                    None if next_variable >= 0x8000 => {
+                        return Err(format!("Too many variables: {}", value))
+                    }
+                    None => {
+                        symbols.insert(value.to_string(), next_variable);
+                        next_variable += 1;
+                        next_variable - 1
+                    }
+                },
+            },
+            None => encode_c_instruction(line).ok_or(format!("Invalid instruction: {}", line))?,
+        };
+        rom.push(instruction);
+    }
+    Ok((rom, symbols))
+}
+
+// VMプログラムを翻訳してアセンブルしたもの
+pub struct HackProgram {
+    pub rom: Vec<u16>,
+    pub symbols: HashMap<String, u16>,
+    // ROMアドレスごとのアセンブリ
+    pub instructions: Vec<String>,
+    pub labels: HashMap<String, u16>,
+    pub mappings: Vec<SourceMapping>,
+}
+
+impl HackProgram {
+    pub fn new(program: &Program) -> Result<HackProgram, String> {
+        let mut code_writer = CodeWriter::new(program.files[0].file_name.clone());
+        program.translate(&mut code_writer);
+        let (rom, symbols) = assemble(code_writer.code())?;
+        let labels = code_writer
+            .code()
+            .iter()
+            .filter_map(|line| line.strip_prefix('(')?.strip_suffix(')'))
+            .map(|label| (label.to_string(), symbols[label]))
+            .collect();
+        let instructions = code_writer
+            .code()
+            .iter()
+            .filter(|line| is_instruction(line))
+            .cloned()
+            .collect();
+        Ok(HackProgram {
+            rom,
+            symbols,
+            instructions,
+            labels,
+            mappings: code_writer.mappings(),
+        })
+    }
+
+    // ROMアドレスごとに対応するmappingsの位置
+    pub fn mapping_indices(&self) -> Vec<Option<usize>> {
+        let mut indices = vec![None; self.rom.len()];
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            for index in &mut indices[mapping.start..mapping.end] {
+                *index = Some(i);
+            }
+        }
+        indices
+    }
+}
+
+pub struct HackEmulator {
+    pub rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: u16,
+    pub steps: usize,
+    // 直前のstepで書き込んだアドレスと値
+    pub writes: Vec<(usize, i16)>,
+}
+
+impl HackEmulator {
+    pub fn new(rom: Vec<u16>) -> HackEmulator {
+        HackEmulator {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            steps: 0,
+            writes: vec![],
+        }
+    }
+
+    // Sys.initがあればブートストラップ後の状態から始め、RAMの初期値を書く
+    pub fn prepare(hack_program: &HackProgram, ram: &[(i16, i16)]) -> HackEmulator {
+        let mut emulator = HackEmulator::new(hack_program.rom.clone());
+        if let Some(&entry) = hack_program.labels.get("Sys.init") {
+            emulator.boot(entry);
+        }
+        for &(address, value) in ram {
+            emulator.set(address, value);
+        }
+        emulator
+    }
+
+    // ブートストラップの SP=256; call Sys.init を実行した後と同じ状態にする
+    pub fn boot(&mut self, entry: u16) {
+        self.set(0, 261);
+        self.set(1, 261);
+        self.set(2, 256);
+        self.pc = entry;
+    }
+
+    // ROMの外に出たら停止とみなす
+    pub fn halted(&self) -> bool {
+        self.pc as usize >= self.rom.len()
+    }
+
+    pub fn get(&self, address: i16) -> i16 {
+        self.ram[address as u16 as usize & 0x7fff]
+    }
+
+    pub fn set(&mut self, address: i16, value: i16) {
+        let address = address as u16 as usize & 0x7fff;
+        self.ram[address] = value;
+        self.writes.push((address, value));
+    }
+
+    pub fn run(&mut self, limit: Option<usize>) {
+        while !self.halted() && limit.is_none_or(|limit| self.steps < limit) {
+            self.step();
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.halted() {
+            return;
+        }
+        let instruction = self.rom[self.pc as usize];
+        self.steps += 1;
+        self.writes.clear();
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+            return;
+        }
+
+        let y = match instruction & 0x1000 {
+            0 => self.a,
+            _ => self.get(self.a),
+        };
+        let out = alu(self.d, y, (instruction >> 6) & 0b111111);
+        if instruction & 0b1000 != 0 {
+            self.set(self.a, out);
+        }
+        let jump = match instruction & 0b111 {
+            0b001 => out > 0,
+            0b010 => out == 0,
+            0b011 => out >= 0,
+            0b100 => out < 0,
+            0b101 => out != 0,
+            0b110 => out <= 0,
+            0b111 => true,
+            _ => false,
+        };
+        let next_pc = match jump {
+            true => self.a as u16,
+            false => self.pc + 1,
+        };
+        if instruction & 0b100000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b10000 != 0 {
+            self.d = out;
+        }
+        self.pc = next_pc;
+    }
+
+    // 0でないRAMの値をアドレス順に返す
+    pub fn dump(&self) -> Vec<(usize, i16)> {
+        self.ram
+            .iter()
+            .enumerate()
+            .filter(|(_, &value)| value != 0)
+            .map(|(address, &value)| (address, value))
+            .collect()
+    }
+}
+
+fn alu(x: i16, y: i16, control: u16) -> i16 {
+    let bit = |i: u16| control >> (5 - i) & 1 == 1;
+    let x = if bit(0) { 0 } else { x };
+    let x = if bit(1) { !x } else { x };
+    let y = if bit(2) { 0 } else { y };
+    let y = if bit(3) { !y } else { y };
+    let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+    if bit(5) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn assemble_instructions() {
+        let (rom, symbols) = assemble(&lines(
+            "@2\nD=A\n(LOOP)\n@i\nAM=M-1\nM=M+D\nMD=M-D\nD;JNE\n@LOOP\n0;JMP\n@SCREEN",
+        ))
+        .unwrap();
+        assert_eq!(
+            rom,
+            [
+                0b0000000000000010,
+                0b1110110000010000,
+                0b0000000000010000,
+                0b1111110010101000,
+                0b1111000010001000,
+                0b1111000111011000,
+                0b1110001100000101,
+                0b0000000000000010,
+                0b1110101010000111,
+                0b0100000000000000,
+            ]
+        );
+        assert_eq!(symbols["LOOP"], 2);
+        assert_eq!(symbols["i"], 16);
+        assert!(assemble(&lines("D=X")).is_err());
+
+        let mut code = vec!["D=0".to_string(); 0x7fff];
+        code.extend(["@END".to_string(), "(END)".to_string()]);
+        // 最後の命令の後のラベルは0x8000になり、A命令で表せない
+        assert_eq!(
+            assemble(&code).err(),
+            Some("Program does not fit in ROM: END is at 32768".to_string())
+        );
+        code[0x7fff] = "D=0".to_string();
+        assert!(assemble(&code).is_ok());
+        code.push("D=0".to_string());
+        assert_eq!(
+            assemble(&code).err(),
+            Some("Program does not fit in ROM: 32769 instructions".to_string())
+        );
+        let variables: Vec<String> = (0..0x8000).map(|i| format!("@v{}", i)).collect();
+        assert_eq!(
+            assemble(&variables).err(),
+            Some("Too many variables: v32752".to_string())
+        );
+    }
+
+    #[test]
+    fn run_program() {
+        // RAM[0] + RAM[1] を RAM[2] に、大きい方を RAM[3] に
+        let source = "@R0
+D=M
+@R1
+D=D+M
+@R2
+M=D
+@R0
+D=M
+@R1
+D=D-M
+@FIRST
+D;JGT
+@R1
+D=M
+@R3
+M=D
+@END
+0;JMP
+(FIRST)
+@R0
+D=M
+@R3
+M=D
+(END)";
+        let (rom, _) = assemble(&lines(source)).unwrap();
+        let mut emulator = HackEmulator::new(rom);
+        emulator.set(0, 7);
+        emulator.set(1, -5);
+        emulator.run(Some(100));
+        assert!(emulator.halted());
+        assert_eq!(emulator.dump(), [(0, 7), (1, -5), (2, 2), (3, 7)]);
+    }
+}
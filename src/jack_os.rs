@@ -0,0 +1,741 @@
+// VMインタプリタで定義されていないOSの関数が呼ばれたときに代わりに実行する
+// Jack OSの実装。公式のVMEmulatorと同じく、OSの.vmファイルなしで動かせる
+use crate::keyboard::KEYBOARD_ADDRESS;
+use crate::screen::{HEIGHT, SCREEN_ADDRESS, WIDTH};
+use font::FONT;
+mod font;
+pub mod link;
+
+const HEAP_BASE: i16 = 2048;
+const HEAP_END: i16 = SCREEN_ADDRESS as i16;
+const TEXT_ROWS: usize = 23;
+const TEXT_COLUMNS: usize = 64;
+
+const NEWLINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+
+const FUNCTIONS: [&str; 48] = [
+    "Math.init",
+    "Math.abs",
+    "Math.multiply",
+    "Math.divide",
+    "Math.min",
+    "Math.max",
+    "Math.sqrt",
+    "Memory.init",
+    "Memory.peek",
+    "Memory.poke",
+    "Memory.alloc",
+    "Memory.deAlloc",
+    "Array.new",
+    "Array.dispose",
+    "String.new",
+    "String.dispose",
+    "String.length",
+    "String.charAt",
+    "String.setCharAt",
+    "String.appendChar",
+    "String.eraseLastChar",
+    "String.intValue",
+    "String.setInt",
+    "String.backSpace",
+    "String.doubleQuote",
+    "String.newLine",
+    "Output.init",
+    "Output.moveCursor",
+    "Output.printChar",
+    "Output.printString",
+    "Output.printInt",
+    "Output.println",
+    "Output.backSpace",
+    "Screen.init",
+    "Screen.clearScreen",
+    "Screen.setColor",
+    "Screen.drawPixel",
+    "Screen.drawLine",
+    "Screen.drawRectangle",
+    "Screen.drawCircle",
+    "Keyboard.init",
+    "Keyboard.keyPressed",
+    "Keyboard.readChar",
+    "Keyboard.readLine",
+    "Keyboard.readInt",
+    "Sys.halt",
+    "Sys.error",
+    "Sys.wait",
+];
+
+pub fn is_os_function(name: &str) -> bool {
+    FUNCTIONS.contains(&name)
+}
+
+pub trait Memory {
+    fn get(&self, address: i16) -> i16;
+    fn set(&mut self, address: i16, value: i16);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NativeResult {
+    RETURN(i16),
+    // 同じ呼び出しをもう一度実行する (キー入力やSys.waitを待つ)
+    WAIT,
+    HALT,
+    // Sys.errorと同じくERRnを出力して止まる
+    ERROR(i16),
+}
+
+use NativeResult::*;
+
+pub struct JackOs {
+    // 空いているヒープの (先頭, 大きさ) と確保した領域の大きさ
+    free: Vec<(i16, i16)>,
+    allocated: Vec<(i16, i16)>,
+    black: bool,
+    text: Vec<Vec<char>>,
+    row: usize,
+    column: usize,
+    // 押されたまま離されていないキー
+    pressed: Option<i16>,
+    // readLineで入力中の文字
+    line: Option<Vec<i16>>,
+    waiting: Option<i16>,
+}
+
+impl Default for JackOs {
+    fn default() -> JackOs {
+        JackOs {
+            free: vec![(HEAP_BASE, HEAP_END - HEAP_BASE)],
+            allocated: vec![],
+            black: true,
+            text: vec![vec![' '; TEXT_COLUMNS]; TEXT_ROWS],
+            row: 0,
+            column: 0,
+            pressed: None,
+            line: None,
+            waiting: None,
+        }
+    }
+}
+
+impl JackOs {
+    // Outputで書いた文字。末尾の空白と空行は除く
+    pub fn output(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .text
+            .iter()
+            .map(|row| row.iter().collect::<String>().trim_end().to_string())
+            .collect();
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        lines
+    }
+
+    pub fn call(&mut self, name: &str, args: &[i16], memory: &mut dyn Memory) -> NativeResult {
+        let arg = |i: usize| args.get(i).copied().unwrap_or_default();
+        match name {
+            "Math.abs" => RETURN(arg(0).wrapping_abs()),
+            "Math.multiply" => RETURN(arg(0).wrapping_mul(arg(1))),
+            "Math.divide" if arg(1) == 0 => ERROR(3),
+            "Math.divide" => RETURN(arg(0).wrapping_div(arg(1))),
+            "Math.min" => RETURN(arg(0).min(arg(1))),
+            "Math.max" => RETURN(arg(0).max(arg(1))),
+            "Math.sqrt" if arg(0) < 0 => ERROR(4),
+            "Math.sqrt" => RETURN((0..=181).rev().find(|y| y * y <= arg(0)).unwrap()),
+
+            "Memory.peek" => RETURN(memory.get(arg(0))),
+            "Memory.poke" => {
+                memory.set(arg(0), arg(1));
+                RETURN(0)
+            }
+            "Memory.alloc" if arg(0) <= 0 => ERROR(5),
+            "Memory.alloc" => self.alloc(arg(0)).map_or(ERROR(6), RETURN),
+            "Memory.deAlloc" | "Array.dispose" | "String.dispose" => {
+                self.de_alloc(arg(0));
+                RETURN(0)
+            }
+            "Array.new" if arg(0) <= 0 => ERROR(2),
+            "Array.new" => self.alloc(arg(0)).map_or(ERROR(6), RETURN),
+
+            "String.new" => self.string_new(arg(0), memory),
+            "String.length" => RETURN(memory.get(arg(0).wrapping_add(1))),
+            "String.charAt" => match self.char_address(arg(0), arg(1), memory) {
+                Some(address) => RETURN(memory.get(address)),
+                None => ERROR(15),
+            },
+            "String.setCharAt" => match self.char_address(arg(0), arg(1), memory) {
+                Some(address) => {
+                    memory.set(address, arg(2));
+                    RETURN(0)
+                }
+                None => ERROR(16),
+            },
+            "String.appendChar" => {
+                let string = arg(0);
+                let length = memory.get(string.wrapping_add(1));
+                if length >= memory.get(string) {
+                    return ERROR(17);
+                }
+                memory.set(string.wrapping_add(2).wrapping_add(length), arg(1));
+                memory.set(string.wrapping_add(1), length + 1);
+                RETURN(string)
+            }
+            "String.eraseLastChar" => {
+                let length = memory.get(arg(0).wrapping_add(1));
+                if length <= 0 {
+                    return ERROR(18);
+                }
+                memory.set(arg(0).wrapping_add(1), length - 1);
+                RETURN(0)
+            }
+            "String.intValue" => RETURN(int_value(&string_chars(arg(0), memory))),
+            "String.setInt" => {
+                let digits = arg(1).to_string();
+                if digits.len() as i16 > memory.get(arg(0)) {
+                    return ERROR(19);
+                }
+                for (i, c) in digits.chars().enumerate() {
+                    memory.set(arg(0).wrapping_add(2 + i as i16), c as i16);
+                }
+                memory.set(arg(0).wrapping_add(1), digits.len() as i16);
+                RETURN(0)
+            }
+            "String.backSpace" => RETURN(BACKSPACE),
+            "String.doubleQuote" => RETURN(34),
+            "String.newLine" => RETURN(NEWLINE),
+
+            "Output.init" => {
+                self.text = JackOs::default().text;
+                self.row = 0;
+                self.column = 0;
+                RETURN(0)
+            }
+            "Output.moveCursor" => {
+                let (row, column) = (arg(0), arg(1));
+                if !(0..TEXT_ROWS as i16).contains(&row)
+                    || !(0..TEXT_COLUMNS as i16).contains(&column)
+                {
+                    return ERROR(20);
+                }
+                self.row = row as usize;
+                self.column = column as usize;
+                RETURN(0)
+            }
+            "Output.printChar" => {
+                self.print_char(arg(0), memory);
+                RETURN(0)
+            }
+            "Output.printString" => {
+                for c in string_chars(arg(0), memory) {
+                    self.print_char(c, memory);
+                }
+                RETURN(0)
+            }
+            "Output.printInt" => {
+                self.print_str(&arg(0).to_string(), memory);
+                RETURN(0)
+            }
+            "Output.println" => {
+                self.print_char(NEWLINE, memory);
+                RETURN(0)
+            }
+            "Output.backSpace" => {
+                self.print_char(BACKSPACE, memory);
+                RETURN(0)
+            }
+
+            "Screen.clearScreen" => {
+                for address in SCREEN_ADDRESS..KEYBOARD_ADDRESS as usize {
+                    memory.set(address as i16, 0);
+                }
+                RETURN(0)
+            }
+            "Screen.setColor" => {
+                self.black = arg(0) != 0;
+                RETURN(0)
+            }
+            "Screen.drawPixel" if !on_screen(arg(0), arg(1)) => ERROR(7),
+            "Screen.drawPixel" => {
+                self.draw_pixel(arg(0), arg(1), memory);
+                RETURN(0)
+            }
+            "Screen.drawLine" if !on_screen(arg(0), arg(1)) || !on_screen(arg(2), arg(3)) => {
+                ERROR(8)
+            }
+            "Screen.drawLine" => {
+                self.draw_line(arg(0), arg(1), arg(2), arg(3), memory);
+                RETURN(0)
+            }
+            "Screen.drawRectangle"
+                if !on_screen(arg(0), arg(1))
+                    || !on_screen(arg(2), arg(3))
+                    || arg(0) > arg(2)
+                    || arg(1) > arg(3) =>
+            {
+                ERROR(9)
+            }
+            "Screen.drawRectangle" => {
+                for y in arg(1)..=arg(3) {
+                    self.draw_line(arg(0), y, arg(2), y, memory);
+                }
+                RETURN(0)
+            }
+            "Screen.drawCircle" if !on_screen(arg(0), arg(1)) => ERROR(12),
+            "Screen.drawCircle" => {
+                let (x, y, r) = (arg(0), arg(1), arg(2));
+                if !(0..=181).contains(&r) || !on_screen(x - r, y - r) || !on_screen(x + r, y + r) {
+                    return ERROR(13);
+                }
+                for dy in -r..=r {
+                    let (r2, dy2) = (r as i32 * r as i32, dy as i32 * dy as i32);
+                    let dx = (0..=r)
+                        .rev()
+                        .find(|&dx| dx as i32 * dx as i32 + dy2 <= r2)
+                        .unwrap();
+                    self.draw_line(x - dx, y + dy, x + dx, y + dy, memory);
+                }
+                RETURN(0)
+            }
+
+            "Keyboard.keyPressed" => RETURN(memory.get(KEYBOARD_ADDRESS)),
+            "Keyboard.readChar" => match self.read_key(memory) {
+                Some(key) => {
+                    self.print_char(key, memory);
+                    RETURN(key)
+                }
+                None => WAIT,
+            },
+            "Keyboard.readLine" | "Keyboard.readInt" => {
+                let mut line = match self.line.take() {
+                    Some(line) => line,
+                    None => {
+                        for c in string_chars(arg(0), memory) {
+                            self.print_char(c, memory);
+                        }
+                        vec![]
+                    }
+                };
+                match self.read_key(memory) {
+                    Some(NEWLINE) => {
+                        self.print_char(NEWLINE, memory);
+                        if name == "Keyboard.readInt" {
+                            return RETURN(int_value(&line));
+                        }
+                        let string = match self.string_new(line.len() as i16, memory) {
+                            RETURN(string) => string,
+                            error => return error,
+                        };
+                        for (i, &c) in line.iter().enumerate() {
+                            memory.set(string + 2 + i as i16, c);
+                        }
+                        memory.set(string + 1, line.len() as i16);
+                        return RETURN(string);
+                    }
+                    Some(BACKSPACE) if line.is_empty() => (),
+                    Some(BACKSPACE) => {
+                        line.pop();
+                        self.print_char(BACKSPACE, memory);
+                    }
+                    Some(key) => {
+                        self.print_char(key, memory);
+                        line.push(key);
+                    }
+                    None => (),
+                }
+                self.line = Some(line);
+                WAIT
+            }
+
+            "Sys.halt" => HALT,
+            "Sys.error" => ERROR(arg(0)),
+            "Sys.wait" if arg(0) < 0 => ERROR(1),
+            // 1ミリ秒を1ステップとして待つ
+            "Sys.wait" => {
+                let remaining = self.waiting.take().unwrap_or(arg(0));
+                match remaining {
+                    0 => RETURN(0),
+                    _ => {
+                        self.waiting = Some(remaining - 1);
+                        WAIT
+                    }
+                }
+            }
+            _ => RETURN(0),
+        }
+    }
+
+    // Sys.errorの出力
+    pub fn print_error(&mut self, code: i16, memory: &mut dyn Memory) {
+        self.print_str(&format!("ERR{}", code), memory);
+    }
+
+    fn alloc(&mut self, size: i16) -> Option<i16> {
+        let i = self.free.iter().position(|&(_, free)| free >= size)?;
+        let (start, free) = self.free[i];
+        match free == size {
+            true => {
+                self.free.remove(i);
+            }
+            false => self.free[i] = (start + size, free - size),
+        }
+        self.allocated.push((start, size));
+        Some(start)
+    }
+
+    fn de_alloc(&mut self, address: i16) {
+        if let Some(i) = self.allocated.iter().position(|&(a, _)| a == address) {
+            let block = self.allocated.remove(i);
+            let position = self.free.partition_point(|&(start, _)| start < block.0);
+            self.free.insert(position, block);
+            // 隣り合う空き領域をまとめる
+            let mut merged: Vec<(i16, i16)> = vec![];
+            for &(start, size) in &self.free {
+                match merged.last_mut() {
+                    Some(last) if last.0 + last.1 == start => last.1 += size,
+                    _ => merged.push((start, size)),
+                }
+            }
+            self.free = merged;
+        }
+    }
+
+    // 文字列は [最大長, 長さ, 文字...] の形でヒープに置く
+    fn string_new(&mut self, max_length: i16, memory: &mut dyn Memory) -> NativeResult {
+        if max_length < 0 {
+            return ERROR(14);
+        }
+        match self.alloc(max_length.saturating_add(2)) {
+            Some(string) => {
+                memory.set(string, max_length);
+                memory.set(string + 1, 0);
+                RETURN(string)
+            }
+            None => ERROR(6),
+        }
+    }
+
+    fn char_address(&self, string: i16, index: i16, memory: &dyn Memory) -> Option<i16> {
+        let length = memory.get(string.wrapping_add(1));
+        (0..length)
+            .contains(&index)
+            .then(|| string.wrapping_add(2 + index))
+    }
+
+    fn print_str(&mut self, s: &str, memory: &mut dyn Memory) {
+        for c in s.chars() {
+            self.print_char(c as i16, memory);
+        }
+    }
+
+    // テキストに書き、画面にもOutput.vmと同じく8x11ピクセルで描く
+    fn print_char(&mut self, c: i16, memory: &mut dyn Memory) {
+        match c {
+            NEWLINE => {
+                self.column = 0;
+                self.row = (self.row + 1) % TEXT_ROWS;
+            }
+            BACKSPACE => {
+                if self.column > 0 {
+                    self.column -= 1;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.column = TEXT_COLUMNS - 1;
+                }
+                self.text[self.row][self.column] = ' ';
+                self.draw_char(' ' as i16, memory);
+            }
+            _ => {
+                self.draw_char(c, memory);
+                let c = char::from_u32(c as u32)
+                    .filter(|c| (' '..='~').contains(c))
+                    .unwrap_or(' ');
+                self.text[self.row][self.column] = c;
+                self.column += 1;
+                if self.column == TEXT_COLUMNS {
+                    self.print_char(NEWLINE, memory);
+                }
+            }
+        }
+    }
+
+    // 2文字で1ワードなので、偶数列は下位、奇数列は上位の8ビットに描く
+    fn draw_char(&self, c: i16, memory: &mut dyn Memory) {
+        let glyph = match c {
+            32..=126 => FONT[c as usize - 32],
+            _ => FONT[95],
+        };
+        let address = SCREEN_ADDRESS as i16 + self.row as i16 * 352 + self.column as i16 / 2;
+        for row in 0..11 {
+            let bits = match row {
+                1..=9 => (glyph[(row - 1) / 3] >> ((row - 1) % 3 * 5) & 0x1f) << 1,
+                _ => 0,
+            };
+            let address = address + row as i16 * 32;
+            let word = memory.get(address);
+            let word = match self.column % 2 {
+                0 => word & !0xff | bits,
+                _ => word & 0xff | bits << 8,
+            };
+            memory.set(address, word);
+        }
+    }
+
+    // キーが押されてから離されたときにそのキーを返す
+    fn read_key(&mut self, memory: &dyn Memory) -> Option<i16> {
+        let key = memory.get(KEYBOARD_ADDRESS);
+        match (self.pressed, key) {
+            (Some(pressed), 0) => {
+                self.pressed = None;
+                Some(pressed)
+            }
+            (_, 0) => None,
+            (_, key) => {
+                self.pressed = Some(key);
+                None
+            }
+        }
+    }
+
+    fn draw_pixel(&self, x: i16, y: i16, memory: &mut dyn Memory) {
+        let address = (SCREEN_ADDRESS as i16) + y * 32 + x / 16;
+        let bit = 1i16 << (x % 16);
+        let word = memory.get(address);
+        let word = match self.black {
+            true => word | bit,
+            false => word & !bit,
+        };
+        memory.set(address, word);
+    }
+
+    fn draw_line(&self, x1: i16, y1: i16, x2: i16, y2: i16, memory: &mut dyn Memory) {
+        let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+        let (sx, sy) = ((x2 - x1).signum(), (y2 - y1).signum());
+        let (mut x, mut y, mut error) = (x1, y1, dx + dy);
+        loop {
+            self.draw_pixel(x, y, memory);
+            if x == x2 && y == y2 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += sx;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+}
+
+fn on_screen(x: i16, y: i16) -> bool {
+    (0..WIDTH as i16).contains(&x) && (0..HEIGHT as i16).contains(&y)
+}
+
+fn string_chars(string: i16, memory: &dyn Memory) -> Vec<i16> {
+    let length = memory.get(string.wrapping_add(1));
+    (0..length.max(0))
+        .map(|i| memory.get(string.wrapping_add(2 + i)))
+        .collect()
+}
+
+// 先頭の-と続く数字だけを読む
+fn int_value(chars: &[i16]) -> i16 {
+    let (negative, digits) = match chars.first() {
+        Some(&c) if c == '-' as i16 => (true, &chars[1..]),
+        _ => (false, chars),
+    };
+    let value = digits
+        .iter()
+        .take_while(|&&c| ('0' as i16..='9' as i16).contains(&c))
+        .fold(0i16, |value, &c| {
+            value.wrapping_mul(10).wrapping_add(c - '0' as i16)
+        });
+    match negative {
+        true => value.wrapping_neg(),
+        false => value,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keyboard::KeyScript;
+    use crate::program::{Program, VmFile};
+    use crate::screen;
+    use crate::vm_interpreter::{VmInterpreter, RAM_SIZE};
+
+    impl Memory for Vec<i16> {
+        fn get(&self, address: i16) -> i16 {
+            self[address as u16 as usize & 0x7fff]
+        }
+
+        fn set(&mut self, address: i16, value: i16) {
+            self[address as u16 as usize & 0x7fff] = value;
+        }
+    }
+
+    #[test]
+    fn math_functions() {
+        let mut os = JackOs::default();
+        let mut ram = vec![0; RAM_SIZE];
+        assert_eq!(
+            os.call("Math.multiply", &[-7, 300], &mut ram),
+            RETURN(-2100)
+        );
+        assert_eq!(os.call("Math.divide", &[-7, 2], &mut ram), RETURN(-3));
+        assert_eq!(os.call("Math.divide", &[1, 0], &mut ram), ERROR(3));
+        assert_eq!(os.call("Math.sqrt", &[32767], &mut ram), RETURN(181));
+        assert_eq!(os.call("Math.sqrt", &[-1], &mut ram), ERROR(4));
+        assert_eq!(os.call("Math.abs", &[-32768], &mut ram), RETURN(-32768));
+        assert!(is_os_function("Output.printString"));
+        assert!(!is_os_function("Main.main"));
+    }
+
+    #[test]
+    fn memory_and_strings() {
+        let mut os = JackOs::default();
+        let mut ram = vec![0; RAM_SIZE];
+        assert_eq!(os.call("Memory.alloc", &[10], &mut ram), RETURN(2048));
+        assert_eq!(os.call("Array.new", &[5], &mut ram), RETURN(2058));
+        os.call("Memory.deAlloc", &[2048], &mut ram);
+        // 解放した領域を先に使う
+        assert_eq!(os.call("Memory.alloc", &[4], &mut ram), RETURN(2048));
+        assert_eq!(os.call("Memory.alloc", &[0], &mut ram), ERROR(5));
+        assert_eq!(os.call("Memory.alloc", &[20000], &mut ram), ERROR(6));
+
+        let string = match os.call("String.new", &[3], &mut ram) {
+            RETURN(string) => string,
+            result => panic!("{:?}", result),
+        };
+        for c in "-42".chars() {
+            assert_eq!(
+                os.call("String.appendChar", &[string, c as i16], &mut ram),
+                RETURN(string)
+            );
+        }
+        assert_eq!(
+            os.call("String.appendChar", &[string, 48], &mut ram),
+            ERROR(17)
+        );
+        assert_eq!(os.call("String.length", &[string], &mut ram), RETURN(3));
+        assert_eq!(os.call("String.intValue", &[string], &mut ram), RETURN(-42));
+        assert_eq!(os.call("String.charAt", &[string, 3], &mut ram), ERROR(15));
+        os.call("String.setInt", &[string, 123], &mut ram);
+        assert_eq!(os.call("String.charAt", &[string, 2], &mut ram), RETURN(51));
+        assert_eq!(
+            os.call("String.setInt", &[string, -1234], &mut ram),
+            ERROR(19)
+        );
+    }
+
+    #[test]
+    fn output_and_screen() {
+        let mut os = JackOs::default();
+        let mut ram = vec![0; RAM_SIZE];
+        os.call("Output.printInt", &[-5], &mut ram);
+        os.call("Output.println", &[], &mut ram);
+        os.call("Output.moveCursor", &[2, 3], &mut ram);
+        os.call("Output.printChar", &[65], &mut ram);
+        os.call("Output.backSpace", &[], &mut ram);
+        os.call("Output.printChar", &[66], &mut ram);
+        assert_eq!(os.output(), ["-5", "", "   B"]);
+        // Bの2行目 ####. は奇数列なので上位の8ビットに描く
+        let b = SCREEN_ADDRESS + 2 * 352 + 32 + 1;
+        assert_eq!(ram[b - 32], 0);
+        assert_eq!(ram[b], 0b11110 << 8);
+        // -5 の中央の行
+        assert_eq!(ram[SCREEN_ADDRESS + 4 * 32], 62 | 32 << 8);
+        assert_eq!(os.call("Output.moveCursor", &[23, 0], &mut ram), ERROR(20));
+        os.call("Screen.clearScreen", &[], &mut ram);
+
+        os.call("Screen.drawLine", &[0, 0, 3, 3], &mut ram);
+        assert!((0..4).all(|i| screen::pixel(&ram, i, i)));
+        assert!(!screen::pixel(&ram, 1, 0));
+        // 横に長い線は1列に1ピクセルずつ、y = 20 + (x - 10) * 40 / 90 の近くを通る
+        os.call("Screen.drawLine", &[10, 20, 100, 60], &mut ram);
+        for x in 10..=100 {
+            let ys: Vec<usize> = (0..screen::HEIGHT)
+                .filter(|&y| screen::pixel(&ram, x, y))
+                .collect();
+            let expected = 20.0 + (x as f64 - 10.0) * 40.0 / 90.0;
+            assert_eq!(ys.len(), 1);
+            assert!((ys[0] as f64 - expected).abs() <= 0.5, "{} {:?}", x, ys);
+        }
+        os.call("Screen.drawRectangle", &[16, 10, 31, 11], &mut ram);
+        assert_eq!(ram[SCREEN_ADDRESS + 10 * 32 + 1], -1);
+        assert_eq!(ram[SCREEN_ADDRESS + 11 * 32 + 1], -1);
+        os.call("Screen.setColor", &[0], &mut ram);
+        os.call("Screen.drawPixel", &[16, 10], &mut ram);
+        assert_eq!(ram[SCREEN_ADDRESS + 10 * 32 + 1], -2);
+        assert_eq!(os.call("Screen.drawPixel", &[512, 0], &mut ram), ERROR(7));
+        assert_eq!(
+            os.call("Screen.drawCircle", &[100, 100, 5], &mut ram),
+            RETURN(0)
+        );
+        assert_eq!(
+            os.call("Screen.drawCircle", &[3, 100, 5], &mut ram),
+            ERROR(13)
+        );
+    }
+
+    #[test]
+    fn read_line_from_keys() {
+        let mut os = JackOs::default();
+        let mut ram = vec![0; RAM_SIZE];
+        let mut keys = KeyScript::default();
+        keys.type_string(0, "12x", 4);
+        keys.press(12, 129);
+        keys.press(14, 0);
+        keys.type_string(16, "3\n", 4);
+        let mut result = WAIT;
+        for cycle in 0..100 {
+            for event in keys.take_until(cycle) {
+                ram[KEYBOARD_ADDRESS as usize] = event.key;
+            }
+            result = os.call("Keyboard.readInt", &[0], &mut ram);
+            if result != WAIT {
+                break;
+            }
+        }
+        assert_eq!(result, RETURN(123));
+        assert_eq!(os.output(), ["123"]);
+    }
+
+    #[test]
+    fn interpreter_runs_without_os_files() {
+        let source = "function Main.main 1
+push constant 6
+push constant 7
+call Math.multiply 2
+pop static 0
+push constant 2
+call String.new 1
+push constant 72
+call String.appendChar 2
+push constant 105
+call String.appendChar 2
+pop local 0
+push local 0
+call Output.printString 1
+pop temp 0
+push constant 1
+call Sys.wait 1
+pop temp 0
+push constant 0
+return";
+        let program = Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let mut interpreter = VmInterpreter::from_program(&program);
+        interpreter.boot(interpreter.function_address("Main.main").unwrap());
+        interpreter.run(Some(1000));
+        assert!(interpreter.halted);
+        // アセンブラと同じくRAM[16]は定義されていないMath.multiplyが使う
+        assert_eq!(interpreter.ram[17], 42);
+        assert_eq!(interpreter.os().output(), ["Hi"]);
+        assert_eq!(interpreter.ram[2048..2052], [2, 2, 72, 105]);
+    }
+}
@@ -0,0 +1,100 @@
+// Output.vmと同じフォント。1文字は3ワードで、1ワードに5ピクセルの行を3行ずつ下の桁から詰める
+// 11行のうち上下の1行は空けて、2行目から9行を描く
+pub const FONT: [[i16; 3]; 96] = [
+    [0, 0, 0],             // ' '
+    [4228, 132, 4],        // '!'
+    [10570, 0, 0],         // '"'
+    [32074, 11242, 10],    // '#'
+    [6084, 16014, 4],      // '$'
+    [8803, 25668, 24],     // '%'
+    [5414, 9890, 22],      // '&'
+    [2180, 0, 0],          // "'"
+    [2184, 4162, 8],       // '('
+    [8322, 4360, 2],       // ')'
+    [21632, 4782, 0],      // '*'
+    [4224, 4255, 0],       // '+'
+    [0, 4288, 2],          // ','
+    [0, 31, 0],            // '-'
+    [0, 6144, 6],          // '.'
+    [8704, 1092, 0],       // '/'
+    [26158, 18037, 14],    // '0'
+    [4292, 4228, 14],      // '1'
+    [16942, 2184, 31],     // '2'
+    [4383, 17928, 14],     // '3'
+    [10632, 9193, 8],      // '4'
+    [15423, 17936, 14],    // '5'
+    [1100, 17967, 14],     // '6'
+    [8735, 2116, 2],       // '7'
+    [17966, 17966, 14],    // '8'
+    [17966, 8734, 6],      // '9'
+    [6336, 6336, 0],       // ':'
+    [6336, 4288, 2],       // ';'
+    [2184, 4161, 8],       // '<'
+    [31744, 992, 0],       // '='
+    [8322, 4368, 2],       // '>'
+    [16942, 136, 4],       // '?'
+    [16942, 22198, 14],    // '@'
+    [17966, 17983, 17],    // 'A'
+    [17967, 17967, 15],    // 'B'
+    [1582, 17441, 14],     // 'C'
+    [17703, 9777, 7],      // 'D'
+    [1087, 1071, 31],      // 'E'
+    [1087, 1071, 1],       // 'F'
+    [1582, 17981, 30],     // 'G'
+    [17969, 17983, 17],    // 'H'
+    [4238, 4228, 14],      // 'I'
+    [8476, 9480, 6],       // 'J'
+    [5425, 9379, 17],      // 'K'
+    [1057, 1057, 31],      // 'L'
+    [22385, 17973, 17],    // 'M'
+    [20017, 18229, 17],    // 'N'
+    [17966, 17969, 14],    // 'O'
+    [17967, 1071, 1],      // 'P'
+    [17966, 9905, 22],     // 'Q'
+    [17967, 9391, 17],     // 'R'
+    [1086, 16910, 15],     // 'S'
+    [4255, 4228, 4],       // 'T'
+    [17969, 17969, 14],    // 'U'
+    [17969, 10801, 4],     // 'V'
+    [17969, 22197, 10],    // 'W'
+    [10801, 17732, 17],    // 'X'
+    [17969, 4234, 4],      // 'Y'
+    [8735, 1092, 31],      // 'Z'
+    [2126, 2114, 14],      // '['
+    [2080, 16644, 0],      // '\\'
+    [8462, 8456, 14],      // ']'
+    [17732, 0, 0],         // '^'
+    [0, 0, 31],            // '_'
+    [8322, 0, 0],          // '`'
+    [14336, 18384, 30],    // 'a'
+    [13345, 17971, 15],    // 'b'
+    [14336, 17441, 14],    // 'c'
+    [23056, 17977, 30],    // 'd'
+    [14336, 2033, 14],     // 'e'
+    [2636, 2119, 2],       // 'f'
+    [30720, 31281, 464],   // 'g'
+    [13345, 17971, 17],    // 'h'
+    [6148, 4228, 14],      // 'i'
+    [12296, 8456, 201],    // 'j'
+    [9249, 5221, 9],       // 'k'
+    [4230, 4228, 14],      // 'l'
+    [11264, 18101, 17],    // 'm'
+    [13312, 17971, 17],    // 'n'
+    [14336, 17969, 14],    // 'o'
+    [15360, 15921, 33],    // 'p'
+    [30720, 31281, 528],   // 'q'
+    [13312, 1075, 1],      // 'r'
+    [14336, 16833, 15],    // 's'
+    [7234, 18498, 12],     // 't'
+    [17408, 26161, 22],    // 'u'
+    [17408, 10801, 4],     // 'v'
+    [17408, 22193, 10],    // 'w'
+    [17408, 10378, 17],    // 'x'
+    [17408, 31281, 464],   // 'y'
+    [31744, 2184, 31],     // 'z'
+    [4232, 4226, 8],       // '{'
+    [4228, 4228, 4],       // '|'
+    [4226, 4232, 2],       // '}'
+    [2048, 277, 0],        // '~'
+    [32767, 32767, 32767], // box
+];
@@ -0,0 +1,347 @@
+// 翻訳するプログラムに同梱のOSの.vmファイルを必要な分だけ加える
+use super::is_os_function;
+use crate::code_writer::helper::filename_without_extention;
+use crate::parser::CommandType;
+use crate::program::{Program, VmFile};
+use std::collections::BTreeSet;
+
+const CLASSES: [(&str, &str); 8] = [
+    ("Math", include_str!("Math.vm")),
+    ("Memory", include_str!("Memory.vm")),
+    ("Array", include_str!("Array.vm")),
+    ("String", include_str!("String.vm")),
+    ("Output", include_str!("Output.vm")),
+    ("Screen", include_str!("Screen.vm")),
+    ("Keyboard", include_str!("Keyboard.vm")),
+    ("Sys", include_str!("Sys.vm")),
+];
+
+fn os_file(class: &str) -> VmFile {
+    let (_, source) = CLASSES.iter().find(|(name, _)| *name == class).unwrap();
+    VmFile::new(
+        &format!("{}.vm", class),
+        source.lines().map(|l| l.to_string()).collect(),
+    )
+}
+
+// 定義されていないOSの関数を呼んでいるクラス
+fn missing_classes(program: &Program) -> BTreeSet<&'static str> {
+    let commands = || program.files.iter().flat_map(|file| file.commands.iter());
+    let defined: BTreeSet<&str> = commands()
+        .filter(|command| command.command_type == CommandType::FUNCTION)
+        .map(|command| command.arg1.as_deref().unwrap_or_default())
+        .collect();
+    let existing: BTreeSet<String> = program
+        .files
+        .iter()
+        .map(|file| filename_without_extention(&file.file_name))
+        .collect();
+    let mut missing = BTreeSet::new();
+    for command in commands() {
+        let function_name = command.arg1.as_deref().unwrap_or_default();
+        if command.command_type != CommandType::CALL
+            || defined.contains(function_name)
+            || !is_os_function(function_name)
+        {
+            continue;
+        }
+        let class = function_name.split('.').next().unwrap_or_default();
+        // 同じ名前のファイルがあればOSのクラスで置き換えない
+        if let Some(&(name, _)) = CLASSES.iter().find(|(name, _)| *name == class) {
+            if !existing.contains(name) {
+                missing.insert(name);
+            }
+        }
+    }
+    missing
+}
+
+// OSのクラスどうしの呼び出しもたどり、加えたファイル名を返す
+pub fn link(program: &mut Program) -> Vec<String> {
+    let mut linked = vec![];
+    loop {
+        let missing = missing_classes(program);
+        if missing.is_empty() {
+            return linked;
+        }
+        for (class, _) in CLASSES.iter().filter(|(name, _)| missing.contains(name)) {
+            let file = os_file(class);
+            linked.push(file.file_name.clone());
+            program.files.push(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code_writer::CodeWriter;
+    use crate::differential;
+    use crate::hack_emulator::{assemble, HackEmulator, HackProgram};
+    use crate::jack_os::font::FONT;
+    use crate::keyboard::{KeyScript, KEYBOARD_ADDRESS};
+    use crate::screen::SCREEN_ADDRESS;
+    use crate::stack_depth;
+    use crate::validator;
+    use crate::vm_interpreter::VmInterpreter;
+
+    fn program(source: &str) -> Program {
+        Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )])
+    }
+
+    // Sys.initから始めてSys.haltに着くまで動かす
+    fn run_hack(program: &Program, keys: &mut KeyScript) -> (HackProgram, HackEmulator) {
+        let hack_program = HackProgram::new(program).unwrap();
+        let mut emulator = HackEmulator::new(hack_program.rom.clone());
+        emulator.boot(hack_program.labels["Sys.init"]);
+        let halt = hack_program.labels["Sys.halt"];
+        while emulator.pc != halt {
+            assert!(emulator.steps < 5000000);
+            for event in keys.take_until(emulator.steps) {
+                emulator.set(KEYBOARD_ADDRESS, event.key);
+            }
+            emulator.step();
+        }
+        (hack_program, emulator)
+    }
+
+    const CALCULATION: &str = "function Main.main 0
+push constant 1000
+push constant 7
+neg
+call Math.divide 2
+pop static 0
+push constant 123
+push constant 45
+call Math.multiply 2
+pop static 1
+push constant 30000
+call Math.sqrt 1
+pop static 2
+push constant 5
+call String.new 1
+push constant 45
+call String.appendChar 2
+push constant 52
+call String.appendChar 2
+push constant 50
+call String.appendChar 2
+call String.intValue 1
+pop static 3
+push constant 0
+return";
+
+    #[test]
+    fn links_os_classes_on_demand() {
+        let mut linked_program = program(CALCULATION);
+        let linked = link(&mut linked_program);
+        // Math.divideなどがSys.errorを呼び、Sys.initが全てのクラスを初期化する
+        assert_eq!(
+            linked,
+            [
+                "Math.vm",
+                "String.vm",
+                "Memory.vm",
+                "Sys.vm",
+                "Output.vm",
+                "Screen.vm",
+                "Keyboard.vm",
+                "Array.vm"
+            ]
+        );
+        assert!(validator::validate(&linked_program).is_empty());
+        assert!(stack_depth::check(&linked_program).is_empty());
+        assert!(validator::check_calls(&linked_program, &[]).is_empty());
+        assert!(link(&mut linked_program).is_empty());
+
+        // OSを呼ばないプログラムや、同じ名前のクラスがある場合は加えない
+        assert!(link(&mut program("push constant 1\npop static 0")).is_empty());
+        let mut own_math = Program::new(vec![
+            VmFile::new("Main.vm", vec!["call Math.abs 1".to_string()]),
+            VmFile::new("Math.vm", vec!["function Math.abs 0".to_string()]),
+        ]);
+        assert!(link(&mut own_math).is_empty());
+    }
+
+    #[test]
+    fn linked_os_runs_on_hack() {
+        let mut linked_program = program(CALCULATION);
+        link(&mut linked_program);
+        let (hack_program, emulator) = run_hack(&linked_program, &mut KeyScript::default());
+        let statics: Vec<i16> = (0..4)
+            .map(|i| emulator.ram[hack_program.symbols[&format!("Main.{}", i)] as usize])
+            .collect();
+        assert_eq!(statics, [-142, 5535, 173, -42]);
+        assert_eq!(
+            differential::compare(&linked_program, &[], 20000),
+            Ok(20000)
+        );
+    }
+
+    #[test]
+    fn bootstrap_starts_linked_os_from_pc_0() {
+        let mut linked_program = program(CALCULATION);
+        link(&mut linked_program);
+        let mut code_writer = CodeWriter::new("Main.vm".to_string());
+        code_writer.write_init();
+        linked_program.translate(&mut code_writer);
+        let (rom, symbols) = assemble(code_writer.code()).unwrap();
+        let mut emulator = HackEmulator::new(rom);
+        while emulator.pc != symbols["Sys.halt"] {
+            assert!(emulator.steps < 5000000);
+            emulator.step();
+        }
+        let statics: Vec<i16> = (0..4)
+            .map(|i| emulator.ram[symbols[&format!("Main.{}", i)] as usize])
+            .collect();
+        assert_eq!(statics, [-142, 5535, 173, -42]);
+    }
+
+    #[test]
+    fn linked_os_draws_like_native_os() {
+        let source = "function Main.main 0
+push constant 10
+push constant 20
+push constant 100
+push constant 60
+call Screen.drawLine 4
+pop temp 0
+push constant 3
+push constant 40
+push constant 200
+push constant 45
+call Screen.drawRectangle 4
+pop temp 0
+push constant 300
+push constant 100
+push constant 30
+call Screen.drawCircle 3
+pop temp 0
+push constant 0
+call Screen.setColor 1
+pop temp 0
+push constant 310
+push constant 100
+push constant 300
+push constant 80
+call Screen.drawLine 4
+pop temp 0
+push constant 0
+return";
+        let mut interpreter = VmInterpreter::from_program(&program(source));
+        interpreter.boot(interpreter.function_address("Main.main").unwrap());
+        interpreter.run(Some(1000));
+        assert!(interpreter.halted);
+
+        let mut linked_program = program(source);
+        link(&mut linked_program);
+        let (_, emulator) = run_hack(&linked_program, &mut KeyScript::default());
+        let screen = SCREEN_ADDRESS..KEYBOARD_ADDRESS as usize;
+        assert!(emulator.ram[screen.clone()].iter().any(|&word| word != 0));
+        let differs = screen
+            .clone()
+            .find(|&i| emulator.ram[i] != interpreter.ram[i]);
+        assert_eq!(differs, None);
+    }
+
+    #[test]
+    fn linked_os_prints_like_native_os() {
+        let source = "function Main.main 0
+push constant 3
+call String.new 1
+push constant 72
+call String.appendChar 2
+push constant 105
+call String.appendChar 2
+push constant 33
+call String.appendChar 2
+call Output.printString 1
+pop temp 0
+push constant 12345
+neg
+call Output.printInt 1
+pop temp 0
+call Output.println 0
+pop temp 0
+push constant 200
+call Output.printChar 1
+pop temp 0
+push constant 126
+call Output.printChar 1
+pop temp 0
+call Output.backSpace 0
+pop temp 0
+push constant 22
+push constant 62
+call Output.moveCursor 2
+pop temp 0
+push constant 4
+call String.new 1
+push constant 119
+call String.appendChar 2
+push constant 114
+call String.appendChar 2
+push constant 97
+call String.appendChar 2
+push constant 112
+call String.appendChar 2
+call Output.printString 1
+pop temp 0
+push constant 0
+return";
+        let mut interpreter = VmInterpreter::from_program(&program(source));
+        interpreter.boot(interpreter.function_address("Main.main").unwrap());
+        interpreter.run(Some(1000));
+        assert!(interpreter.halted);
+        // 最後の行の端で折り返して先頭の行に戻る
+        let output = interpreter.os().output();
+        assert_eq!(output[0], "ap!-12345");
+        assert_eq!(output[22].trim(), "wr");
+
+        let mut linked_program = program(source);
+        link(&mut linked_program);
+        let (_, emulator) = run_hack(&linked_program, &mut KeyScript::default());
+        let screen = SCREEN_ADDRESS..KEYBOARD_ADDRESS as usize;
+        assert!(emulator.ram[screen.clone()].iter().any(|&word| word != 0));
+        let differs = screen
+            .clone()
+            .find(|&i| emulator.ram[i] != interpreter.ram[i]);
+        assert_eq!(differs, None);
+    }
+
+    #[test]
+    fn native_font_matches_output_vm() {
+        let words: Vec<i16> = os_file("Output")
+            .commands
+            .iter()
+            .skip_while(|command| command.arg1.as_deref() != Some("Output.init"))
+            .take_while(|command| command.arg1.as_deref() != Some("Output.decodeFont"))
+            .filter(|command| command.command_type == CommandType::PUSH)
+            .map(|command| command.arg2.as_deref().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(words[words.len() - 288..], FONT.concat());
+    }
+
+    #[test]
+    fn linked_os_reads_keyboard() {
+        let source = "function Main.main 0
+push constant 0
+call String.new 1
+call Keyboard.readInt 1
+pop static 0
+push constant 0
+return";
+        let mut linked_program = program(source);
+        link(&mut linked_program);
+        let mut keys = KeyScript::default();
+        keys.type_string(2000000, "-4x", 20000);
+        keys.press(2060000, 129);
+        keys.press(2070000, 0);
+        keys.type_string(2080000, "7\n", 20000);
+        let (hack_program, emulator) = run_hack(&linked_program, &mut keys);
+        assert_eq!(emulator.ram[hack_program.symbols["Main.0"] as usize], -47);
+    }
+}
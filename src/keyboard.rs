@@ -0,0 +1,214 @@
+// キーボードのメモリマップ (RAM[24576]) に決まったサイクルでキーコードを書くスクリプト
+pub const KEYBOARD_ADDRESS: i16 = 24576;
+
+// Jackの特殊キーのコード
+const SPECIAL_KEYS: [(&str, i16); 13] = [
+    ("newline", 128),
+    ("backspace", 129),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyEvent {
+    pub cycle: usize,
+    // 0はキーを離した状態
+    pub key: i16,
+}
+
+// キーの名前、f1〜f12、数値のコード、1文字のいずれか
+pub fn key_code(name: &str) -> Option<i16> {
+    if let Some(&(_, code)) = SPECIAL_KEYS.iter().find(|(key, _)| *key == name) {
+        return Some(code);
+    }
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<i16>().ok()) {
+        return (1..=12).contains(&n).then_some(140 + n);
+    }
+    if name == "release" {
+        return Some(0);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if (' '..='~').contains(&c) => Some(c as i16),
+        _ => name.parse().ok(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+    next: usize,
+}
+
+impl KeyScript {
+    // 1行に1つ、"サイクル キー" か "サイクル type 間隔 文字列" を書く
+    //   100 a
+    //   200 release
+    //   1000 type 500 HELLO\n
+    pub fn parse(lines: &[String]) -> Result<KeyScript, String> {
+        let mut script = KeyScript::default();
+        for (i, line) in lines.iter().enumerate() {
+            let line = line.split("//").next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| format!("line {}: {}: {}", i + 1, line, message);
+            let (cycle, rest) = line.split_once(' ').ok_or(error("key is not provided"))?;
+            let cycle = cycle
+                .parse::<usize>()
+                .map_err(|_| error("cycle is not a number"))?;
+            match rest.trim_start().strip_prefix("type ") {
+                Some(typed) => {
+                    let (interval, text) = typed
+                        .trim_start()
+                        .split_once(' ')
+                        .ok_or(error("text is not provided"))?;
+                    let interval = match interval.parse::<usize>() {
+                        Ok(interval) if interval >= 2 => interval,
+                        _ => return Err(error("interval must be a number of at least 2")),
+                    };
+                    script.type_string(cycle, &text.replace("\\n", "\n"), interval);
+                }
+                None => {
+                    let key = key_code(rest.trim()).ok_or(error("unknown key"))?;
+                    script.press(cycle, key);
+                }
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn press(&mut self, cycle: usize, key: i16) {
+        // 同じサイクルのイベントは書いた順に適用する
+        let position = self.events.partition_point(|event| event.cycle <= cycle);
+        self.events.insert(position, KeyEvent { cycle, key });
+    }
+
+    // 1文字ずつintervalサイクルごとに押し、半分の時間で離す
+    pub fn type_string(&mut self, start: usize, text: &str, interval: usize) {
+        for (i, c) in text.chars().enumerate() {
+            let key = match c {
+                '\n' => 128,
+                c => c as i16,
+            };
+            self.press(start + i * interval, key);
+            self.press(start + i * interval + interval / 2, 0);
+        }
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    // まだ適用していない次のイベントのサイクル
+    pub fn next_cycle(&self) -> Option<usize> {
+        self.events.get(self.next).map(|event| event.cycle)
+    }
+
+    // cycleまでのイベントを順に取り出す
+    pub fn take_until(&mut self, cycle: usize) -> Vec<KeyEvent> {
+        let start = self.next;
+        while self.next_cycle().is_some_and(|next| next <= cycle) {
+            self.next += 1;
+        }
+        self.events[start..self.next].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hack_emulator::{HackEmulator, HackProgram};
+    use crate::program::{Program, VmFile};
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn key_codes() {
+        assert_eq!(key_code("a"), Some(97));
+        assert_eq!(key_code("newline"), Some(128));
+        assert_eq!(key_code("f12"), Some(152));
+        assert_eq!(key_code("f13"), None);
+        assert_eq!(key_code("65"), Some(65));
+        assert_eq!(key_code("release"), Some(0));
+        assert_eq!(key_code("enter"), None);
+    }
+
+    #[test]
+    fn parse_script() {
+        let mut script = KeyScript::parse(&lines(
+            "// comment
+300 release
+100 a
+10 type 4 Hi\\n",
+        ))
+        .unwrap();
+        let events: Vec<(usize, i16)> = script.events().iter().map(|e| (e.cycle, e.key)).collect();
+        assert_eq!(
+            events,
+            [
+                (10, 72),
+                (12, 0),
+                (14, 105),
+                (16, 0),
+                (18, 128),
+                (20, 0),
+                (100, 97),
+                (300, 0)
+            ]
+        );
+        assert_eq!(script.take_until(14).len(), 3);
+        assert_eq!(script.next_cycle(), Some(16));
+        assert_eq!(script.take_until(1000).len(), 5);
+        assert_eq!(script.next_cycle(), None);
+
+        assert_eq!(
+            KeyScript::parse(&lines("x a")).unwrap_err(),
+            "line 1: x a: cycle is not a number"
+        );
+        assert_eq!(
+            KeyScript::parse(&lines("1 enter")).unwrap_err(),
+            "line 1: 1 enter: unknown key"
+        );
+    }
+
+    #[test]
+    fn program_reads_keyboard() {
+        // キーが押されるまで待ち、そのコードをstatic 0に書く
+        let source = "push constant 24576
+pop pointer 1
+label WAIT
+push that 0
+push constant 0
+eq
+if-goto WAIT
+push that 0
+pop static 0";
+        let program = Program::new(vec![VmFile::new("Main.vm", lines(source))]);
+        let hack_program = HackProgram::new(&program).unwrap();
+        let mut emulator = HackEmulator::new(hack_program.rom.clone());
+        emulator.set(0, 256);
+        let mut script = KeyScript::default();
+        script.press(500, key_code("k").unwrap());
+        while !emulator.halted() && emulator.steps < 10000 {
+            for event in script.take_until(emulator.steps) {
+                emulator.set(KEYBOARD_ADDRESS, event.key);
+            }
+            emulator.step();
+        }
+        assert!(emulator.halted());
+        assert!(emulator.steps > 500);
+        assert_eq!(emulator.ram[hack_program.symbols["Main.0"] as usize], 107);
+    }
+}
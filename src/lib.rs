@@ -1,2 +1,27 @@
+#![allow(clippy::upper_case_acronyms)]
+pub mod c_writer;
+pub mod cfg;
 pub mod code_writer;
+pub mod debugger;
+pub mod differential;
+#[cfg(any(test, feature = "generator"))]
+pub mod generator;
+pub mod hack_debugger;
+pub mod hack_emulator;
+pub mod jack_os;
+pub mod keyboard;
+pub mod llvm_writer;
 pub mod parser;
+pub mod profiler;
+pub mod program;
+pub mod riscv_writer;
+pub mod screen;
+pub mod stack_depth;
+pub mod static_allocation;
+pub mod trace;
+pub mod translator;
+pub mod validator;
+pub mod vm_interpreter;
+pub mod warnings;
+pub mod wat_writer;
+pub mod x86_writer;
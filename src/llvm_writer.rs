@@ -0,0 +1,582 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::code_writer::helper::{filename_without_extention, mangle_symbol};
+use crate::code_writer::segment::Segment;
+use crate::static_allocation::StaticAllocation;
+use crate::translator::Translator;
+use crate::x86_writer::{RUNTIME, RUNTIME_FILE_NAME};
+use std::{fs::OpenOptions, io::prelude::*, path::Path};
+
+// ポインタは型なしのptrで書くので、コンパイルにはLLVM 15以降が必要
+// (LLVM 14でも llc -opaque-pointers なら読める)
+const HEADER: &str = "@ram = global [32768 x i16] zeroinitializer
+@limit = internal global i64 -1
+@steps = internal global i64 0
+
+declare void @vm_halt() noreturn
+
+define internal ptr @address(i16 %a) {
+  %masked = and i16 %a, 32767
+  %index = zext i16 %masked to i64
+  %p = getelementptr [32768 x i16], ptr @ram, i64 0, i64 %index
+  ret ptr %p
+}
+
+define internal i16 @get(i16 %a) {
+  %p = call ptr @address(i16 %a)
+  %v = load i16, ptr %p
+  ret i16 %v
+}
+
+define internal void @set(i16 %a, i16 %v) {
+  %p = call ptr @address(i16 %a)
+  store i16 %v, ptr %p
+  ret void
+}
+
+define internal void @push(i16 %v) {
+  %sp = call i16 @get(i16 0)
+  call void @set(i16 %sp, i16 %v)
+  %next = add i16 %sp, 1
+  call void @set(i16 0, i16 %next)
+  ret void
+}
+
+define internal i16 @pop() {
+  %sp = call i16 @get(i16 0)
+  %next = sub i16 %sp, 1
+  call void @set(i16 0, i16 %next)
+  %v = call i16 @get(i16 %next)
+  ret i16 %v
+}
+
+define internal void @step() {
+  %limit = load i64, ptr @limit
+  %steps = load i64, ptr @steps
+  %unlimited = icmp slt i64 %limit, 0
+  %remaining = icmp slt i64 %steps, %limit
+  %ok = or i1 %unlimited, %remaining
+  br i1 %ok, label %count, label %halt
+count:
+  %next = add i64 %steps, 1
+  store i64 %next, ptr @steps
+  ret void
+halt:
+  call void @vm_halt()
+  unreachable
+}";
+
+struct Function {
+    name: String,
+    code: Vec<String>,
+    terminated: bool,
+}
+
+pub struct LlvmWriter {
+    file_name: String,
+    functions: Vec<Function>,
+    function_name_stack: Vec<String>,
+    return_address_count: usize,
+    temporary_count: usize,
+    variables: StaticAllocation,
+    called_functions: Vec<String>,
+}
+
+impl LlvmWriter {
+    pub fn new(file_name: String) -> LlvmWriter {
+        LlvmWriter {
+            file_name,
+            functions: vec![Function {
+                name: "vm_run".to_string(),
+                code: vec![
+                    "store i64 %limit, ptr @limit".to_string(),
+                    "store i64 0, ptr @steps".to_string(),
+                ],
+                terminated: false,
+            }],
+            function_name_stack: vec!["null".to_string()],
+            return_address_count: 0,
+            temporary_count: 0,
+            variables: StaticAllocation::default(),
+            called_functions: vec![],
+        }
+    }
+
+    pub fn generate(&self) -> Vec<String> {
+        let mut code: Vec<String> = HEADER.lines().map(|l| l.to_string()).collect();
+        for (i, function) in self.functions.iter().enumerate() {
+            code.push("".to_string());
+            code.push(match i {
+                0 => "define void @vm_run(i64 %limit) {".to_string(),
+                _ => format!(
+                    "define internal void {}() {{",
+                    function_symbol(&function.name)
+                ),
+            });
+            code.push("entry:".to_string());
+            for line in &function.code {
+                match line.ends_with(':') {
+                    true => code.push(line.clone()),
+                    false => code.push(format!("  {}", line)),
+                }
+            }
+
+            // returnせずに終わった場合はHackと同じく次の関数へ進む
+            if !function.terminated {
+                if let Some(next) = self.functions.get(i + 1) {
+                    code.push(format!("  call void {}()", function_symbol(&next.name)));
+                }
+                code.push("  ret void".to_string());
+            }
+            code.push("}".to_string());
+        }
+
+        // 定義されていない関数を呼び出した場合は停止する
+        for function_name in &self.called_functions {
+            if !self.functions.iter().any(|f| &f.name == function_name) {
+                code.push("".to_string());
+                code.push(format!(
+                    "define internal void {}() {{",
+                    function_symbol(function_name)
+                ));
+                code.push("  call void @vm_halt()".to_string());
+                code.push("  unreachable".to_string());
+                code.push("}".to_string());
+            }
+        }
+        code
+    }
+
+    fn temporary(&mut self) -> String {
+        self.temporary_count += 1;
+        format!("%t{}", self.temporary_count)
+    }
+
+    // セグメントのアドレスを計算するコードと、その結果の値を返す
+    fn segment_address(&mut self, segment: &str, index: &str) -> (Vec<String>, String) {
+        match Segment::from_str(segment) {
+            Some(Segment::LOCAL)
+            | Some(Segment::ARGUMENT)
+            | Some(Segment::THIS)
+            | Some(Segment::THAT) => {
+                let register = match Segment::from_str(segment) {
+                    Some(Segment::LOCAL) => 1,
+                    Some(Segment::ARGUMENT) => 2,
+                    Some(Segment::THIS) => 3,
+                    _ => 4,
+                };
+                let base = self.temporary();
+                let address = self.temporary();
+                (
+                    vec![
+                        format!("{} = call i16 @get(i16 {})", base, register),
+                        format!("{} = add i16 {}, {}", address, base, index),
+                    ],
+                    address,
+                )
+            }
+            Some(Segment::POINTER) => (vec![], (3 + parse(index)).to_string()),
+            Some(Segment::TEMP) => (vec![], (5 + parse(index)).to_string()),
+            Some(Segment::STATIC) => {
+                let name = format!("{}.{}", filename_without_extention(&self.file_name), index);
+                (vec![], self.variables.address(&name).to_string())
+            }
+            _ => panic!("{} has no address", segment),
+        }
+    }
+
+    fn current_label(&self, label_name: &str) -> String {
+        format!(
+            "label_{}",
+            mangle_symbol(&format!(
+                "{}${}",
+                self.function_name_stack.last().unwrap(),
+                label_name
+            ))
+        )
+    }
+
+    fn current_function(&mut self) -> &mut Function {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn append(&mut self, lines: Vec<String>) {
+        // 終端命令の後に続くコードのために新しいブロックを始める
+        if self.current_function().terminated {
+            let block = self.temporary().replace('%', "");
+            let function = self.current_function();
+            function.code.push(format!("{}:", block));
+            function.terminated = false;
+        }
+        let function = self.current_function();
+        function.code.push("call void @step()".to_string());
+        function.code.extend(lines);
+    }
+
+    fn terminate(&mut self, lines: Vec<String>) {
+        self.append(lines);
+        self.current_function().terminated = true;
+    }
+}
+
+impl Translator for LlvmWriter {
+    fn set_static_allocation(&mut self, allocation: &StaticAllocation) {
+        self.variables = allocation.clone();
+    }
+
+    fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    fn output(&self, file_name: &str) {
+        let mut output = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        for line in self.generate() {
+            writeln!(output, "{}", line).unwrap();
+        }
+
+        let runtime_file_name = Path::new(file_name).with_file_name(RUNTIME_FILE_NAME);
+        std::fs::write(runtime_file_name, RUNTIME).unwrap();
+    }
+
+    fn push(&mut self, segment: &str, index: &str) {
+        let (mut code, value) = match Segment::from_str(segment) {
+            Some(Segment::CONSTANT) => (vec![], index.to_string()),
+            _ => {
+                let (mut code, address) = self.segment_address(segment, index);
+                let value = self.temporary();
+                code.push(format!("{} = call i16 @get(i16 {})", value, address));
+                (code, value)
+            }
+        };
+        code.push(format!("call void @push(i16 {})", value));
+        self.append(code);
+    }
+
+    fn pop(&mut self, segment: &str, index: &str) {
+        if Segment::from_str(segment) == Some(Segment::CONSTANT) {
+            return;
+        }
+        let (mut code, address) = self.segment_address(segment, index);
+        let value = self.temporary();
+        code.append(&mut vec![
+            format!("call void @set(i16 13, i16 {})", address),
+            format!("{} = call i16 @pop()", value),
+            format!("call void @set(i16 {}, i16 {})", address, value),
+        ]);
+        self.append(code);
+    }
+
+    fn write_label(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        let function = self.current_function();
+        if !function.terminated {
+            function.code.push(format!("br label %{}", label));
+        }
+        function.code.push(format!("{}:", label));
+        function.terminated = false;
+    }
+
+    fn write_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.terminate(vec![format!("br label %{}", label)]);
+    }
+
+    fn write_if_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        let value = self.temporary();
+        let condition = self.temporary();
+        let next = self.temporary().replace('%', "");
+        self.append(vec![
+            format!("{} = call i16 @pop()", value),
+            format!("{} = icmp ne i16 {}, 0", condition, value),
+            format!("br i1 {}, label %{}, label %{}", condition, label, next),
+            format!("{}:", next),
+        ]);
+    }
+
+    fn write_call(&mut self, function_name: &str, n_arg: &str) {
+        self.return_address_count += 1;
+        if !self.called_functions.iter().any(|f| f == function_name) {
+            self.called_functions.push(function_name.to_string());
+        }
+
+        // return_addressの代わりに何番目のcallかをpushする
+        let mut code = vec![format!(
+            "call void @push(i16 {})",
+            self.return_address_count
+        )];
+        for register in 1..=4 {
+            let value = self.temporary();
+            code.push(format!("{} = call i16 @get(i16 {})", value, register));
+            code.push(format!("call void @push(i16 {})", value));
+        }
+
+        // ARG = SP - n_arg - 5, LCL = SP
+        let sp = self.temporary();
+        let arg = self.temporary();
+        code.append(&mut vec![
+            format!("{} = call i16 @get(i16 0)", sp),
+            format!("{} = sub i16 {}, {}", arg, sp, parse(n_arg) + 5),
+            format!("call void @set(i16 2, i16 {})", arg),
+            format!("call void @set(i16 1, i16 {})", sp),
+            format!("call void {}()", function_symbol(function_name)),
+        ]);
+        self.append(code);
+    }
+
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
+        use ArithmeticCommand::*;
+        let command = ArithmeticCommand::from_str(arithmetic_command).unwrap();
+        let result = self.temporary();
+        let x = self.temporary();
+        let mut code = vec![];
+        if command == NEG || command == NOT {
+            code.push(format!("{} = call i16 @pop()", x));
+            code.push(match command {
+                NEG => format!("{} = sub i16 0, {}", result, x),
+                _ => format!("{} = xor i16 {}, -1", result, x),
+            });
+        } else {
+            let y = self.temporary();
+            code.push(format!("{} = call i16 @pop()", y));
+            code.push(format!("{} = call i16 @pop()", x));
+            match command {
+                ADD => code.push(format!("{} = add i16 {}, {}", result, x, y)),
+                SUB => code.push(format!("{} = sub i16 {}, {}", result, x, y)),
+                AND => code.push(format!("{} = and i16 {}, {}", result, x, y)),
+                OR => code.push(format!("{} = or i16 {}, {}", result, x, y)),
+                // Hackと同じくx-yの符号で比較し、真なら-1、偽なら0にする
+                _ => {
+                    let predicate = match command {
+                        EQ => "eq",
+                        GT => "sgt",
+                        _ => "slt",
+                    };
+                    let difference = self.temporary();
+                    let condition = self.temporary();
+                    code.push(format!("{} = sub i16 {}, {}", difference, x, y));
+                    code.push(format!(
+                        "{} = icmp {} i16 {}, 0",
+                        condition, predicate, difference
+                    ));
+                    code.push(format!("{} = sext i1 {} to i16", result, condition));
+                }
+            }
+        }
+        code.push(format!("call void @push(i16 {})", result));
+        self.append(code);
+    }
+
+    fn write_function(&mut self, function_name: &str, num_locals: &str) {
+        self.function_name_stack.push(function_name.to_string());
+        self.functions.push(Function {
+            name: function_name.to_string(),
+            code: vec![],
+            terminated: false,
+        });
+        let mut code = vec![];
+        for _ in 0..parse(num_locals) {
+            code.push("call void @push(i16 0)".to_string());
+        }
+        self.append(code);
+    }
+
+    fn write_return(&mut self) {
+        let frame_address = self.variables.address("FRAME");
+        let ret_address = self.variables.address("RET");
+        let frame = self.temporary();
+        let ret_slot = self.temporary();
+        let ret = self.temporary();
+        let arg = self.temporary();
+        let value = self.temporary();
+        let sp = self.temporary();
+        let mut code = vec![
+            format!("{} = call i16 @get(i16 1)", frame),
+            format!("call void @set(i16 {}, i16 {})", frame_address, frame),
+            format!("{} = sub i16 {}, 5", ret_slot, frame),
+            format!("{} = call i16 @get(i16 {})", ret, ret_slot),
+            format!("call void @set(i16 {}, i16 {})", ret_address, ret),
+            format!("{} = call i16 @get(i16 2)", arg),
+            format!("call void @set(i16 13, i16 {})", arg),
+            format!("{} = call i16 @pop()", value),
+            format!("call void @set(i16 {}, i16 {})", arg, value),
+            format!("{} = add i16 {}, 1", sp, arg),
+            format!("call void @set(i16 0, i16 {})", sp),
+        ];
+
+        // THAT, THIS, ARG, LCLを戻す
+        for (offset, register) in [(1, 4), (2, 3), (3, 2), (4, 1)] {
+            let slot = self.temporary();
+            let saved = self.temporary();
+            code.append(&mut vec![
+                format!("{} = sub i16 {}, {}", slot, frame, offset),
+                format!("{} = call i16 @get(i16 {})", saved, slot),
+                format!("call void @set(i16 {}, i16 {})", register, saved),
+            ]);
+        }
+
+        // 戻り先へはLLVMの関数呼び出しとして戻る
+        code.push("ret void".to_string());
+        self.terminate(code);
+    }
+}
+
+fn parse(number: &str) -> i32 {
+    number.parse::<i32>().unwrap()
+}
+
+fn function_symbol(function_name: &str) -> String {
+    format!("@vm_function_{}", mangle_symbol(function_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::translator::skip_without_toolchain;
+    use crate::vm_interpreter::VmInterpreter;
+    use std::{env, process::Command};
+
+    const SOURCE: &str = "push constant 32767
+push constant 2
+add
+push constant 20000
+neg
+push constant 20000
+gt
+call Foo.double 1
+pop static 0
+label END
+goto END
+function Foo.double 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+push constant 0
+eq
+not
+if-goto SKIP
+push constant 1
+pop pointer 1
+label SKIP
+push local 0
+return";
+
+    fn translate(source: &str) -> LlvmWriter {
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        let mut llvm_writer = LlvmWriter::new("Foo.vm".to_string());
+        for command in parser.collect_commands() {
+            llvm_writer.write_command(&command);
+        }
+        llvm_writer
+    }
+
+    #[test]
+    fn functions_become_llvm_functions() {
+        let code = translate(SOURCE).generate();
+        assert!(code.contains(&"define void @vm_run(i64 %limit) {".to_string()));
+        assert!(code.contains(&"define internal void @vm_function_Foo_2edouble() {".to_string()));
+        assert!(code.contains(&"  call void @vm_function_Foo_2edouble()".to_string()));
+        assert!(code.contains(&"label_Foo_2edouble_24SKIP:".to_string()));
+    }
+
+    #[test]
+    fn code_after_terminator_starts_new_block() {
+        let llvm_writer = translate("goto A\npush constant 1\nlabel A");
+        assert_eq!(
+            llvm_writer.functions[0].code[2..],
+            [
+                "call void @step()",
+                "br label %label_null_24A",
+                "t1:",
+                "call void @step()",
+                "call void @push(i16 1)",
+                "br label %label_null_24A",
+                "label_null_24A:",
+            ]
+        );
+    }
+
+    // clang --version や llc --version に出るLLVMのメジャーバージョン
+    fn llvm_version(tool: &str) -> Option<u32> {
+        let output = Command::new(tool).arg("--version").output().ok()?;
+        let text = String::from_utf8_lossy(&output.stdout).to_string();
+        let (_, version) = text.split_once("version ")?;
+        version.split('.').next()?.parse().ok()
+    }
+
+    // LLVM 15以降のclang、なければllcとccでコンパイルする。どちらも無ければNone
+    fn compile(ir_file: &Path, runtime_file: &Path, binary: &Path) -> Option<()> {
+        if llvm_version("clang").is_some_and(|version| version >= 15) {
+            let status = Command::new("clang")
+                .arg("-O2")
+                .arg("-o")
+                .arg(binary)
+                .arg(ir_file)
+                .arg(runtime_file)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return Some(());
+        }
+        let object = binary.with_extension("o");
+        let mut llc = Command::new("llc");
+        // LLVM 14はptrを読むのにオプションが要る
+        if llvm_version("llc")? < 15 {
+            llc.arg("-opaque-pointers");
+        }
+        let status = llc
+            .args(["-O2", "-filetype=obj", "-o"])
+            .arg(&object)
+            .arg(ir_file)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(binary)
+            .arg(&object)
+            .arg(runtime_file)
+            .status()
+            .ok()?;
+        assert!(status.success());
+        Some(())
+    }
+
+    // コンパイルした結果をVMインタプリタの結果と比べる
+    #[test]
+    fn compiled_ir_matches_vm_interpreter() {
+        let dir = env::temp_dir().join(format!("llvm_writer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ir_file = dir.join("Foo.ll");
+        let binary = dir.join("Foo");
+        translate(SOURCE).output(ir_file.to_str().unwrap());
+
+        if compile(&ir_file, &dir.join(RUNTIME_FILE_NAME), &binary).is_none() {
+            return skip_without_toolchain("neither clang (LLVM 15+) nor llc and cc are installed");
+        }
+        let output = Command::new(&binary)
+            .args(["-n", "100", "0=256", "1=300", "2=400"])
+            .output()
+            .unwrap();
+
+        let mut parser = Parser::new(SOURCE.lines().map(|l| l.to_string()).collect());
+        let mut interpreter = VmInterpreter::new("Foo.vm".to_string(), parser.collect_commands());
+        interpreter.set(0, 256);
+        interpreter.set(1, 300);
+        interpreter.set(2, 400);
+        interpreter.run(Some(100));
+        let expected = interpreter
+            .dump()
+            .iter()
+            .map(|(address, value)| format!("{}: {}\n", address, value))
+            .collect::<String>();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+}
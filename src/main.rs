@@ -1,35 +1,382 @@
+#![allow(clippy::upper_case_acronyms)]
+use virtual_machine::c_writer;
+use virtual_machine::cfg;
 use virtual_machine::code_writer;
-use virtual_machine::parser;
+use virtual_machine::debugger::Debugger;
+use virtual_machine::hack_debugger::HackDebugger;
+use virtual_machine::hack_emulator::{HackEmulator, HackProgram};
+use virtual_machine::jack_os;
+use virtual_machine::keyboard::{KeyScript, KEYBOARD_ADDRESS};
+use virtual_machine::llvm_writer;
+use virtual_machine::profiler::Profiler;
+use virtual_machine::program::{Program, VmFile};
+use virtual_machine::riscv_writer;
+use virtual_machine::screen;
+use virtual_machine::stack_depth;
+use virtual_machine::static_allocation::StaticAllocation;
+use virtual_machine::trace;
+use virtual_machine::translator::Translator;
+use virtual_machine::validator;
+use virtual_machine::vm_interpreter::VmInterpreter;
+use virtual_machine::warnings;
+use virtual_machine::wat_writer;
+use virtual_machine::x86_writer;
 
 use std::{
-    env,
+    env, fs,
     fs::File,
     io,
     io::{prelude::*, BufReader},
+    path::Path,
     process,
 };
 
+enum Target {
+    HACK,
+    C,
+    X86,
+    WAT,
+    LLVM,
+    RISCV,
+}
+
+impl Target {
+    fn from_str(s: &str) -> Option<Target> {
+        match s {
+            "hack" => Some(Target::HACK),
+            "c" => Some(Target::C),
+            "x86_64" => Some(Target::X86),
+            "wat" => Some(Target::WAT),
+            "llvm" => Some(Target::LLVM),
+            "riscv" => Some(Target::RISCV),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Target::HACK => ".asm",
+            Target::C => ".c",
+            Target::X86 => ".s",
+            Target::WAT => ".wat",
+            Target::LLVM => ".ll",
+            Target::RISCV => ".s",
+        }
+    }
+}
+
+enum Mode {
+    TRANSLATE,
+    GRAPH,
+    DEBUG,
+    HACKDEBUG,
+    PROFILE,
+    RUN,
+}
+
 struct Config {
-    filename: String,
+    mode: Mode,
+    filenames: Vec<String>,
+    target: Target,
+    static_map: bool,
+    allow_external: Vec<String>,
+    warnings_as_errors: bool,
+    annotate: bool,
+    // 実行するサブコマンドで使うRAMの初期値と命令数の上限
+    ram: Vec<(i16, i16)>,
+    limit: Option<usize>,
+    collapsed_file: Option<String>,
+    // runでVMインタプリタの代わりにHackのエミュレータを使う
+    hack: bool,
+    trace_file: Option<String>,
+    // 最後に、またはscreen_everyサイクルごとにスクリーンを書き出す画像
+    screen_file: Option<String>,
+    screen_every: Option<usize>,
+    key_file: Option<String>,
+    // 定義されていないOSの関数を呼んでいれば同梱のOSを加える
+    link_os: bool,
 }
 
 impl Config {
     fn new(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 2 {
+        let mut filenames = vec![];
+        let mut target = Target::HACK;
+        let mut static_map = false;
+        let mut allow_external = vec![];
+        let mut warnings_as_errors = false;
+        let mut annotate = false;
+        let mut ram = vec![];
+        let mut limit = None;
+        let mut collapsed_file = None;
+        let mut hack = false;
+        let mut trace_file = None;
+        let mut screen_file = None;
+        let mut screen_every = None;
+        let mut key_file = None;
+        let mut link_os = false;
+        let mut rest = args.iter().skip(1).peekable();
+        // サブコマンド
+        let mode = match rest.peek().map(|arg| arg.as_str()) {
+            Some("graph") => {
+                rest.next();
+                Mode::GRAPH
+            }
+            Some("debug") => {
+                rest.next();
+                Mode::DEBUG
+            }
+            Some("hack-debug") => {
+                rest.next();
+                Mode::HACKDEBUG
+            }
+            Some("profile") => {
+                rest.next();
+                Mode::PROFILE
+            }
+            Some("run") => {
+                rest.next();
+                Mode::RUN
+            }
+            _ => Mode::TRANSLATE,
+        };
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--target" => {
+                    let name = rest.next().ok_or("Target is not provided")?;
+                    target = Target::from_str(name).ok_or("Unknown target")?;
+                }
+                "--static-map" => static_map = true,
+                "-Werror" => warnings_as_errors = true,
+                "--annotate" => annotate = true,
+                "--allow-external" => {
+                    let names = rest.next().ok_or("External functions are not provided")?;
+                    allow_external.extend(names.split(',').map(|name| name.to_string()));
+                }
+                "-n" => {
+                    let n = rest.next().ok_or("Step limit is not provided")?;
+                    limit = Some(n.parse().map_err(|_| "Invalid step limit")?);
+                }
+                "--collapsed" => {
+                    let file = rest.next().ok_or("Collapsed stack file is not provided")?;
+                    collapsed_file = Some(file.clone());
+                }
+                "--hack" => hack = true,
+                "--link-os" => link_os = true,
+                "--trace" => {
+                    let file = rest.next().ok_or("Trace file is not provided")?;
+                    trace_file = Some(file.clone());
+                }
+                "--screen" => {
+                    let file = rest.next().ok_or("Screen image file is not provided")?;
+                    screen_file = Some(file.clone());
+                }
+                "--keys" => {
+                    let file = rest.next().ok_or("Key script file is not provided")?;
+                    key_file = Some(file.clone());
+                }
+                "--screen-every" => {
+                    let n = rest.next().ok_or("Screen interval is not provided")?;
+                    match n.parse() {
+                        Ok(0) | Err(_) => return Err("Invalid screen interval"),
+                        Ok(n) => screen_every = Some(n),
+                    }
+                }
+                _ => match parse_ram_value(arg) {
+                    Some(address_value) => ram.push(address_value),
+                    None => filenames.push(arg.clone()),
+                },
+            }
+        }
+        if filenames.is_empty() {
             return Err("Filename is not provided");
         }
-        let filename = args[1].clone();
-        Ok(Config { filename })
+        Ok(Config {
+            mode,
+            filenames,
+            target,
+            static_map,
+            allow_external,
+            warnings_as_errors,
+            annotate,
+            ram,
+            limit,
+            collapsed_file,
+            hack,
+            trace_file,
+            screen_file,
+            screen_every,
+            key_file,
+            link_os,
+        })
+    }
+
+    fn has_directory(&self) -> bool {
+        self.filenames
+            .iter()
+            .any(|filename| Path::new(filename).is_dir())
+    }
+
+    // ディレクトリが渡された場合はその中の.vmファイルを全て使う
+    fn input_files(&self) -> Result<Vec<String>, io::Error> {
+        let mut files = vec![];
+        for filename in &self.filenames {
+            if Path::new(filename).is_dir() {
+                let mut entries = fs::read_dir(filename)?
+                    .map(|entry| entry.map(|e| e.path().to_string_lossy().to_string()))
+                    .collect::<Result<Vec<String>, io::Error>>()?;
+                entries.retain(|entry| entry.ends_with(".vm"));
+                entries.sort();
+                files.append(&mut entries);
+            } else {
+                files.push(filename.clone());
+            }
+        }
+        Ok(files)
+    }
+
+    // ディレクトリの場合はディレクトリ名のファイルをその中に出力する
+    fn output_file(&self) -> String {
+        let first = self.filenames[0].trim_end_matches('/');
+        if Path::new(first).is_dir() {
+            let name = Path::new(first).file_name().unwrap().to_string_lossy();
+            format!("{}/{}{}", first, name, self.target.extension())
+        } else {
+            first.replace(".vm", self.target.extension())
+        }
     }
 }
 
-fn read_lines_from_file(filename: &str) -> Result<Vec<String>, io::Error> {
-    let file = File::open(filename);
+// "256=10" のようなRAMの初期値
+fn parse_ram_value(arg: &str) -> Option<(i16, i16)> {
+    let (address, value) = arg.split_once('=')?;
+    Some((address.parse().ok()?, value.parse().ok()?))
+}
 
-    let file = match file {
-        Ok(file) => file,
-        Err(e) => return Err(e),
-    };
+// runサブコマンドで動かすVMインタプリタかHackのエミュレータ
+trait Machine {
+    fn steps(&self) -> usize;
+    fn finished(&self) -> bool;
+    fn ram(&self) -> &[i16];
+    fn dump(&self) -> Vec<(usize, i16)>;
+    fn set(&mut self, address: i16, value: i16);
+    // limitまで実行し、traceなら1ステップごとの記録を返す
+    fn run_until(&mut self, limit: Option<usize>, trace: bool) -> Vec<String>;
+    // 組み込みのOSが出力した文字
+    fn output(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl Machine for VmInterpreter {
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn finished(&self) -> bool {
+        self.halted
+    }
+
+    fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    fn dump(&self) -> Vec<(usize, i16)> {
+        VmInterpreter::dump(self)
+    }
+
+    fn set(&mut self, address: i16, value: i16) {
+        VmInterpreter::set(self, address, value)
+    }
+
+    fn run_until(&mut self, limit: Option<usize>, trace: bool) -> Vec<String> {
+        match trace {
+            true => trace::trace_vm(self, limit),
+            false => {
+                self.run(limit);
+                vec![]
+            }
+        }
+    }
+
+    fn output(&self) -> Vec<String> {
+        self.os().output()
+    }
+}
+
+struct HackMachine {
+    emulator: HackEmulator,
+    instructions: Vec<String>,
+}
+
+impl Machine for HackMachine {
+    fn steps(&self) -> usize {
+        self.emulator.steps
+    }
+
+    fn finished(&self) -> bool {
+        self.emulator.halted()
+    }
+
+    fn ram(&self) -> &[i16] {
+        &self.emulator.ram
+    }
+
+    fn dump(&self) -> Vec<(usize, i16)> {
+        self.emulator.dump()
+    }
+
+    fn set(&mut self, address: i16, value: i16) {
+        self.emulator.set(address, value)
+    }
+
+    fn run_until(&mut self, limit: Option<usize>, trace: bool) -> Vec<String> {
+        match trace {
+            true => trace::trace_hack(&mut self.emulator, &self.instructions, limit),
+            false => {
+                self.emulator.run(limit);
+                vec![]
+            }
+        }
+    }
+}
+
+// 途中のスクリーンを書き出すサイクルとキー入力のサイクルで区切って実行する
+fn run_machine(
+    machine: &mut dyn Machine,
+    keys: &mut KeyScript,
+    config: &Config,
+) -> Result<Vec<String>, io::Error> {
+    let mut records = vec![];
+    loop {
+        for event in keys.take_until(machine.steps()) {
+            machine.set(KEYBOARD_ADDRESS, event.key);
+        }
+        let snapshot = config
+            .screen_every
+            .map(|every| (machine.steps() / every + 1) * every);
+        let limit = [snapshot, config.limit, keys.next_cycle()]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+        records.append(&mut machine.run_until(limit, config.trace_file.is_some()));
+        if machine.finished() || config.limit.is_some_and(|limit| machine.steps() >= limit) {
+            break;
+        }
+        if let (Some(screen_file), true) = (&config.screen_file, snapshot == Some(machine.steps()))
+        {
+            let file_name = screen::numbered_file_name(screen_file, machine.steps());
+            screen::write_image(&file_name, machine.ram())?;
+        }
+    }
+    if let Some(screen_file) = &config.screen_file {
+        screen::write_image(screen_file, machine.ram())?;
+    }
+    Ok(records)
+}
+
+fn read_lines_from_file(filename: &str) -> Result<Vec<String>, io::Error> {
+    let file = File::open(filename)?;
     let buf = BufReader::new(file);
     Ok(buf
         .lines()
@@ -44,47 +391,178 @@ fn main() {
         process::exit(1);
     });
 
-    let commands = read_lines_from_file(&config.filename).unwrap_or_else(|err| {
+    let input_files = config.input_files().unwrap_or_else(|err| {
         println!("{}", err);
         process::exit(1)
     });
+    let mut files = vec![];
+    for filename in &input_files {
+        let lines = read_lines_from_file(filename).unwrap_or_else(|err| {
+            println!("{}", err);
+            process::exit(1)
+        });
+        files.push(VmFile::new(filename, lines));
+    }
 
-    let mut parser = parser::Parser::new(commands);
-    let mut code_writer = code_writer::CodeWriter::new(config.filename.clone());
-    while parser.has_more_commands {
-        parser.advance();
-        match parser.command_type {
-            Some(parser::CommandType::ARITHMETIC) => {
-                code_writer.run_arichmetic_command(parser.arg1.as_deref().unwrap())
-            }
-            Some(parser::CommandType::PUSH) => code_writer.push(
-                parser.arg1.as_deref().unwrap(),
-                parser.arg2.as_deref().unwrap(),
-            ),
-            Some(parser::CommandType::POP) => code_writer.pop(
-                parser.arg1.as_deref().unwrap(),
-                parser.arg2.as_deref().unwrap(),
-            ),
-            Some(parser::CommandType::LABEL) => {
-                code_writer.write_label(parser.arg1.as_deref().unwrap())
+    let mut program = Program::new(files);
+    let linked = match config.link_os {
+        true => jack_os::link::link(&mut program),
+        false => vec![],
+    };
+    program.check_static_names().unwrap_or_else(|err| {
+        println!("{}", err);
+        process::exit(1)
+    });
+
+    if let Mode::GRAPH = config.mode {
+        for line in cfg::to_dot(&program) {
+            println!("{}", line);
+        }
+        return;
+    }
+
+    let mut errors = validator::validate(&program);
+    errors.append(&mut stack_depth::check(&program));
+    // プログラム全体が揃っているディレクトリ指定の場合だけ呼び出しを検査する
+    if config.has_directory() {
+        errors.append(&mut validator::check_calls(
+            &program,
+            &config.allow_external,
+        ));
+    }
+    for warning in warnings::warnings(&program) {
+        // 同梱のOSで使わない関数の警告は出さない
+        if linked
+            .iter()
+            .any(|file_name| warning.starts_with(&format!("{}:", file_name)))
+        {
+            continue;
+        }
+        if config.warnings_as_errors {
+            errors.push(warning);
+        } else {
+            println!("warning: {}", warning);
+        }
+    }
+    if !errors.is_empty() {
+        for error in errors {
+            println!("{}", error);
+        }
+        process::exit(1);
+    }
+
+    if let Mode::DEBUG = config.mode {
+        let mut debugger = Debugger::new(VmInterpreter::prepare(&program, &config.ram));
+        debugger.repl(&mut io::stdin().lock());
+        return;
+    }
+    if let Mode::HACKDEBUG = config.mode {
+        let mut debugger = HackDebugger::new(&program, &config.ram).unwrap_or_else(|err| {
+            println!("{}", err);
+            process::exit(1)
+        });
+        debugger.repl(&mut io::stdin().lock());
+        return;
+    }
+
+    if let Mode::PROFILE = config.mode {
+        let hack_program = HackProgram::new(&program).unwrap_or_else(|err| {
+            println!("{}", err);
+            process::exit(1)
+        });
+        let mut emulator = HackEmulator::prepare(&hack_program, &config.ram);
+        let mut profiler = Profiler::new(&hack_program);
+        profiler.run(&mut emulator, config.limit);
+        for line in profiler.flat_profile() {
+            println!("{}", line);
+        }
+        if let Some(collapsed_file) = &config.collapsed_file {
+            fs::write(
+                collapsed_file,
+                profiler.collapsed_stacks().join("\n") + "\n",
+            )
+            .unwrap_or_else(|err| {
+                println!("{}", err);
+                process::exit(1)
+            });
+        }
+        return;
+    }
+
+    if let Mode::RUN = config.mode {
+        let mut machine: Box<dyn Machine> = match config.hack {
+            true => {
+                let hack_program = HackProgram::new(&program).unwrap_or_else(|err| {
+                    println!("{}", err);
+                    process::exit(1)
+                });
+                Box::new(HackMachine {
+                    emulator: HackEmulator::prepare(&hack_program, &config.ram),
+                    instructions: hack_program.instructions,
+                })
             }
-            Some(parser::CommandType::GOTO) => {
-                code_writer.write_go_to(parser.arg1.as_deref().unwrap())
+            false => Box::new(VmInterpreter::prepare(&program, &config.ram)),
+        };
+        let mut keys = match &config.key_file {
+            Some(key_file) => {
+                let lines = read_lines_from_file(key_file).unwrap_or_else(|err| {
+                    println!("{}", err);
+                    process::exit(1)
+                });
+                KeyScript::parse(&lines).unwrap_or_else(|err| {
+                    println!("{}: {}", key_file, err);
+                    process::exit(1)
+                })
             }
-            Some(parser::CommandType::IF) => {
-                code_writer.write_if_go_to(parser.arg1.as_deref().unwrap())
+            None => KeyScript::default(),
+        };
+        let records = run_machine(machine.as_mut(), &mut keys, &config).unwrap_or_else(|err| {
+            println!("{}", err);
+            process::exit(1)
+        });
+        if let Some(trace_file) = &config.trace_file {
+            let contents: String = records.iter().map(|record| record.clone() + "\n").collect();
+            fs::write(trace_file, contents).unwrap_or_else(|err| {
+                println!("{}", err);
+                process::exit(1)
+            });
+        }
+        for line in machine.output() {
+            println!("{}", line);
+        }
+        for (address, value) in machine.dump() {
+            println!("{}: {}", address, value);
+        }
+        return;
+    }
+
+    let static_allocation = StaticAllocation::new(&program).unwrap_or_else(|err| {
+        println!("{}", err);
+        process::exit(1)
+    });
+    if config.static_map {
+        for line in static_allocation.report() {
+            println!("{}", line);
+        }
+    }
+
+    let first_file = input_files[0].clone();
+    let mut translator: Box<dyn Translator> = match config.target {
+        Target::HACK => {
+            let mut code_writer = code_writer::CodeWriter::new(first_file);
+            code_writer.set_annotate(config.annotate);
+            // リンクしたOSはSys.initから始まるので、先頭にブートストラップを書く
+            if config.link_os && program.defines("Sys.init") {
+                code_writer.write_init();
             }
-            Some(parser::CommandType::CALL) => code_writer.write_call(
-                parser.arg1.as_deref().unwrap(),
-                parser.arg2.as_deref().unwrap(),
-            ),
-            Some(parser::CommandType::FUNCTION) => code_writer.write_function(
-                parser.arg1.as_deref().unwrap(),
-                parser.arg2.as_deref().unwrap(),
-            ),
-            Some(parser::CommandType::RETURN) => code_writer.write_return(),
-            None => (),
-        }
-    }
-    code_writer.output(&config.filename.replace(".vm", ".asm"));
+            Box::new(code_writer)
+        }
+        Target::C => Box::new(c_writer::CWriter::new(first_file)),
+        Target::X86 => Box::new(x86_writer::X86Writer::new(first_file)),
+        Target::WAT => Box::new(wat_writer::WatWriter::new(first_file)),
+        Target::LLVM => Box::new(llvm_writer::LlvmWriter::new(first_file)),
+        Target::RISCV => Box::new(riscv_writer::RiscvWriter::new(first_file)),
+    };
+    program.translate(translator.as_mut());
+    translator.output(&config.output_file());
 }
@@ -1,4 +1,6 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum CommandType {
     ARITHMETIC,
     PUSH,
@@ -11,25 +13,62 @@ pub enum CommandType {
     CALL,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub command_type: CommandType,
+    pub arg1: Option<String>,
+    pub arg2: Option<String>,
+    pub line: usize,
+}
+
+// 元のVMコマンドの形で表示する
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keyword = match self.command_type {
+            CommandType::ARITHMETIC => return write!(f, "{}", self.arg1.as_deref().unwrap()),
+            CommandType::PUSH => "push",
+            CommandType::POP => "pop",
+            CommandType::LABEL => "label",
+            CommandType::GOTO => "goto",
+            CommandType::IF => "if-goto",
+            CommandType::FUNCTION => "function",
+            CommandType::RETURN => "return",
+            CommandType::CALL => "call",
+        };
+        write!(f, "{}", keyword)?;
+        for arg in self.arg1.iter().chain(self.arg2.iter()) {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Parser {
     pub has_more_commands: bool,
     pub command_type: Option<CommandType>,
     pub arg1: Option<String>,
     pub arg2: Option<String>,
+    // 読めなかったコマンドの行番号と理由
+    pub errors: Vec<(usize, String)>,
     commands: Vec<String>,
+    line_numbers: Vec<usize>,
     index: usize,
 }
 
 impl Parser {
     pub fn new(commands: Vec<String>) -> Parser {
-        let actual_commands = Parser::remove_unnecessary_parts(commands);
+        let (line_numbers, actual_commands) = Parser::number_commands(commands)
+            .into_iter()
+            .unzip::<usize, String, Vec<usize>, Vec<String>>();
         Parser {
-            has_more_commands: actual_commands.len() > 0,
+            has_more_commands: !actual_commands.is_empty(),
             commands: actual_commands,
+            line_numbers,
             index: 0,
             command_type: None,
             arg1: None,
             arg2: None,
+            errors: vec![],
         }
     }
 
@@ -37,33 +76,69 @@ impl Parser {
         if self.has_more_commands {
             self.clear();
             let command = self.commands[self.index].as_str();
-            let command_type = Parser::classify_command(command);
-            self.command_type = Some(command_type);
+            self.command_type = Parser::classify_command(command);
             self.parse();
             self.index += 1;
             self.has_more_commands = self.commands.len() > self.index;
         }
     }
 
+    pub fn collect_commands(&mut self) -> Vec<Command> {
+        let mut commands = vec![];
+        while self.has_more_commands {
+            self.advance();
+            let line = self.line_numbers[self.index - 1];
+            match self.check() {
+                Ok(command_type) => commands.push(Command {
+                    command_type,
+                    arg1: self.arg1.clone(),
+                    arg2: self.arg2.clone(),
+                    line,
+                }),
+                Err(message) => self.errors.push((line, message)),
+            }
+        }
+        commands
+    }
+
+    // 直前にadvanceしたコマンドの種類と引数の数を確かめる
+    fn check(&self) -> Result<CommandType, String> {
+        let command = &self.commands[self.index - 1];
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let command_type = self
+            .command_type
+            .clone()
+            .ok_or(format!("unknown command {}", command))?;
+        let n_args = match command_type {
+            CommandType::ARITHMETIC | CommandType::RETURN => 0,
+            CommandType::LABEL | CommandType::GOTO | CommandType::IF => 1,
+            _ => 2,
+        };
+        if words.len() != n_args + 1 {
+            return Err(format!("{}: expects {} argument(s)", command, n_args));
+        }
+        Ok(command_type)
+    }
+
     fn parse(&mut self) {
         match self.command_type {
-            None => panic!("CommandType not found"),
-            Some(CommandType::RETURN) => (),
+            None | Some(CommandType::RETURN) => (),
             Some(CommandType::ARITHMETIC) => self.parse_arithmetic(),
             Some(_) => self.parse_command_with_args(),
         };
     }
 
     fn parse_arithmetic(&mut self) {
-        self.arg1 = Some(self.commands[self.index].to_string())
+        self.arg1 = self.commands[self.index]
+            .split_whitespace()
+            .next()
+            .map(|s| s.to_string());
     }
 
     fn parse_command_with_args(&mut self) {
-        let splited_commands = self.commands[self.index].split(" ").collect::<Vec<&str>>();
-        self.arg1 = Some(splited_commands[1].to_string());
-        if splited_commands.len() == 3 {
-            self.arg2 = Some(splited_commands[2].to_string());
-        }
+        let mut words = self.commands[self.index].split_whitespace().skip(1);
+        self.arg1 = words.next().map(|s| s.to_string());
+        self.arg2 = words.next().map(|s| s.to_string());
     }
 
     fn clear(&mut self) {
@@ -72,9 +147,9 @@ impl Parser {
         self.arg2 = None;
     }
 
-    fn classify_command(command: &str) -> CommandType {
-        let firtst_word = command.split(" ").collect::<Vec<&str>>()[0];
-        match firtst_word {
+    fn classify_command(command: &str) -> Option<CommandType> {
+        let firtst_word = command.split_whitespace().next().unwrap_or_default();
+        let command_type = match firtst_word {
             "add" => CommandType::ARITHMETIC,
             "sub" => CommandType::ARITHMETIC,
             "neg" => CommandType::ARITHMETIC,
@@ -93,8 +168,9 @@ impl Parser {
             "return" => CommandType::RETURN,
             "call" => CommandType::CALL,
 
-            _ => panic!("Invalid command"),
-        }
+            _ => return None,
+        };
+        Some(command_type)
     }
 
     fn remove_comments(command: String) -> String {
@@ -103,14 +179,15 @@ impl Parser {
             .to_string()
     }
 
-    fn remove_unnecessary_parts(original_commands: Vec<String>) -> Vec<String> {
+    // コメントと空行を取り除き、元の行番号(1始まり)と組にする
+    fn number_commands(original_commands: Vec<String>) -> Vec<(usize, String)> {
         let mut new_commands = Vec::new();
-        for command in original_commands {
-            let flag = command.trim().chars().nth(0);
+        for (i, command) in original_commands.into_iter().enumerate() {
+            let flag = command.trim().chars().next();
             match flag {
                 Some('/') => (),
                 None => (),
-                Some(_) => new_commands.push(Parser::remove_comments(command)),
+                Some(_) => new_commands.push((i + 1, Parser::remove_comments(command))),
             }
         }
 
@@ -119,6 +196,7 @@ impl Parser {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
     use super::*;
     #[test]
@@ -137,10 +215,26 @@ mod test {
 
         let new_commands = vec!["push constant 7".to_string(), "add".to_string()];
         assert_eq!(
-            Parser::remove_unnecessary_parts(original_commands),
+            Parser::number_commands(original_commands)
+                .into_iter()
+                .map(|(_, command)| command)
+                .collect::<Vec<String>>(),
             new_commands
         );
     }
+
+    #[test]
+    fn parser_number_commands() {
+        let original_commands = vec![
+            "//this is comment line".to_string(),
+            "push constant 7 // here also comment".to_string(),
+            "".to_string(),
+            "   add    //whitespace should be trimmed".to_string(),
+        ];
+
+        let new_commands = vec![(2, "push constant 7".to_string()), (4, "add".to_string())];
+        assert_eq!(Parser::number_commands(original_commands), new_commands);
+    }
     #[test]
     fn parser_new() {
         let original_commands = vec![
@@ -204,20 +298,80 @@ mod test {
         assert_eq!(parser.arg2, None);
     }
 
+    #[test]
+    fn parser_collect_commands() {
+        let original_commands = vec![
+            "push constant 7".to_string(),
+            "add".to_string(),
+            "".to_string(),
+            "// comment".to_string(),
+            "return".to_string(),
+        ];
+        let mut parser = Parser::new(original_commands);
+        let commands = parser.collect_commands();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(
+            commands[0],
+            Command {
+                command_type: CommandType::PUSH,
+                arg1: Some("constant".to_string()),
+                arg2: Some("7".to_string()),
+                line: 1,
+            }
+        );
+        assert_eq!(commands[2].command_type, CommandType::RETURN);
+        assert_eq!(commands[2].line, 5);
+        assert_eq!(commands[0].to_string(), "push constant 7");
+        assert_eq!(commands[1].to_string(), "add");
+        assert_eq!(commands[2].to_string(), "return");
+        assert!(!parser.has_more_commands);
+    }
+
     #[test]
     fn parser_classify_command() {
-        assert_eq!(Parser::classify_command("add"), CommandType::ARITHMETIC);
+        assert_eq!(
+            Parser::classify_command("add"),
+            Some(CommandType::ARITHMETIC)
+        );
         assert_eq!(
             Parser::classify_command("push constant 7"),
-            CommandType::PUSH
+            Some(CommandType::PUSH)
+        );
+        assert_eq!(
+            Parser::classify_command("pop constant 7"),
+            Some(CommandType::POP)
         );
-        assert_eq!(Parser::classify_command("pop constant 7"), CommandType::POP);
-        assert_eq!(Parser::classify_command("label"), CommandType::LABEL);
+        assert_eq!(Parser::classify_command("label"), Some(CommandType::LABEL));
+    }
+
+    #[test]
+    fn parser_classify_invalid_command() {
+        assert_eq!(Parser::classify_command("invalid command"), None);
     }
 
     #[test]
-    #[should_panic(expected = "Invalid command")]
-    fn parser_classify_command_should_panic_with_invalid_command() {
-        Parser::classify_command("invalid command");
+    fn parser_collects_errors_without_panicking() {
+        let original_commands = vec![
+            "invalid command".to_string(),
+            "push".to_string(),
+            "add 1".to_string(),
+            "push\tconstant   7".to_string(),
+            "label A B".to_string(),
+            "return".to_string(),
+        ];
+        let mut parser = Parser::new(original_commands);
+        let commands = parser.collect_commands();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].to_string(), "push constant 7");
+        assert_eq!(commands[0].line, 4);
+        assert_eq!(
+            parser.errors,
+            [
+                (1, "unknown command invalid command".to_string()),
+                (2, "push: expects 2 argument(s)".to_string()),
+                (3, "add 1: expects 0 argument(s)".to_string()),
+                (5, "label A B: expects 1 argument(s)".to_string()),
+            ]
+        );
     }
 }
@@ -0,0 +1,241 @@
+use crate::hack_emulator::{HackEmulator, HackProgram};
+use crate::program::TOP_LEVEL;
+use std::collections::HashMap;
+
+pub struct FunctionProfile {
+    pub name: String,
+    // その関数のコードで実行した命令数
+    pub self_cycles: usize,
+    // 呼び出し先も含めた命令数
+    pub total_cycles: usize,
+    pub calls: usize,
+}
+
+// ROMアドレスごとの情報
+#[derive(Clone, Copy)]
+struct RomInfo {
+    function: usize,
+    is_call: bool,
+    is_return: bool,
+    // 生成元のVMコマンドの範囲
+    start: usize,
+    end: usize,
+}
+
+// Hackの命令を1つずつ実行し、ソースマップからどのVM関数の命令かを調べて数える
+pub struct Profiler {
+    rom_info: Vec<Option<RomInfo>>,
+    pub functions: Vec<FunctionProfile>,
+    stack: Vec<usize>,
+    stacks: HashMap<Vec<usize>, usize>,
+    pub cycles: usize,
+}
+
+impl Profiler {
+    pub fn new(hack_program: &HackProgram) -> Profiler {
+        let mut profiler = Profiler {
+            rom_info: vec![],
+            functions: vec![],
+            stack: vec![],
+            stacks: HashMap::new(),
+            cycles: 0,
+        };
+        let mapping_indices = hack_program.mapping_indices();
+        for index in mapping_indices {
+            let info = index.map(|i| {
+                let mapping = &hack_program.mappings[i];
+                let function =
+                    profiler.function_index(mapping.function.as_deref().unwrap_or(TOP_LEVEL));
+                RomInfo {
+                    function,
+                    is_call: mapping.command.starts_with("call "),
+                    is_return: mapping.command == "return",
+                    start: mapping.start,
+                    end: mapping.end,
+                }
+            });
+            profiler.rom_info.push(info);
+        }
+        profiler
+    }
+
+    fn function_index(&mut self, name: &str) -> usize {
+        match self.functions.iter().position(|f| f.name == name) {
+            Some(i) => i,
+            None => {
+                self.functions.push(FunctionProfile {
+                    name: name.to_string(),
+                    self_cycles: 0,
+                    total_cycles: 0,
+                    calls: 0,
+                });
+                self.functions.len() - 1
+            }
+        }
+    }
+
+    fn function_at(&self, pc: u16) -> Option<usize> {
+        self.rom_info
+            .get(pc as usize)
+            .copied()
+            .flatten()
+            .map(|info| info.function)
+    }
+
+    pub fn step(&mut self, emulator: &mut HackEmulator) {
+        let pc = emulator.pc;
+        if self.stack.is_empty() {
+            self.stack.extend(self.function_at(pc));
+        }
+        self.cycles += 1;
+        if let Some(&top) = self.stack.last() {
+            self.functions[top].self_cycles += 1;
+            // 再帰している関数は1回だけ数える
+            for (i, &function) in self.stack.iter().enumerate() {
+                if !self.stack[..i].contains(&function) {
+                    self.functions[function].total_cycles += 1;
+                }
+            }
+            *self.stacks.entry(self.stack.clone()).or_default() += 1;
+        }
+
+        emulator.step();
+
+        // callやreturnのコードから飛び出したら呼び出しのスタックを変える
+        let next = self.function_at(emulator.pc);
+        if let Some(info) = self.rom_info[pc as usize] {
+            let new_pc = emulator.pc as usize;
+            if new_pc < info.start || info.end <= new_pc {
+                if let (true, Some(callee)) = (info.is_call, next) {
+                    self.functions[callee].calls += 1;
+                    self.stack.push(callee);
+                } else if info.is_return && self.stack.len() > 1 {
+                    self.stack.pop();
+                }
+            }
+        }
+        // 関数の終わりから次の関数に入った場合なども実行中の関数に合わせる
+        if let (Some(function), Some(top)) = (next, self.stack.last_mut()) {
+            *top = function;
+        }
+    }
+
+    pub fn run(&mut self, emulator: &mut HackEmulator, limit: Option<usize>) {
+        while !emulator.halted() && limit.is_none_or(|limit| self.cycles < limit) {
+            self.step(emulator);
+        }
+    }
+
+    pub fn flat_profile(&self) -> Vec<String> {
+        let mut functions: Vec<&FunctionProfile> = self
+            .functions
+            .iter()
+            .filter(|f| f.total_cycles > 0)
+            .collect();
+        functions.sort_by(|a, b| {
+            b.self_cycles
+                .cmp(&a.self_cycles)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        let mut lines = vec![
+            format!("{} cycles", self.cycles),
+            format!(
+                "{:>7} {:>10} {:>10} {:>8}  function",
+                "self%", "self", "total", "calls"
+            ),
+        ];
+        for function in functions {
+            lines.push(format!(
+                "{:>6.2}% {:>10} {:>10} {:>8}  {}",
+                function.self_cycles as f64 * 100.0 / self.cycles.max(1) as f64,
+                function.self_cycles,
+                function.total_cycles,
+                function.calls,
+                function.name
+            ));
+        }
+        lines
+    }
+
+    // flamegraph.plなどが読める "関数;関数;関数 命令数" の形式
+    pub fn collapsed_stacks(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<&str> = stack
+                    .iter()
+                    .map(|&i| self.functions[i].name.as_str())
+                    .collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::{Program, VmFile};
+
+    fn profile(source: &str) -> Profiler {
+        let program = Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let hack_program = HackProgram::new(&program).unwrap();
+        let mut emulator = HackEmulator::new(hack_program.rom.clone());
+        emulator.boot(hack_program.labels["Sys.init"]);
+        let mut profiler = Profiler::new(&hack_program);
+        profiler.run(&mut emulator, Some(10000));
+        profiler
+    }
+
+    #[test]
+    fn attributes_cycles_to_functions() {
+        let source = "function Sys.init 0
+call Main.double 0
+call Main.double 0
+pop temp 0
+pop temp 0
+label HALT
+goto HALT
+function Main.double 0
+call Main.one 0
+call Main.one 0
+add
+return
+function Main.one 0
+push constant 1
+return";
+        let profiler = profile(source);
+        assert_eq!(profiler.cycles, 10000);
+        let function = |name: &str| profiler.functions.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(function("Main.double").calls, 2);
+        assert_eq!(function("Main.one").calls, 4);
+        assert_eq!(function("Sys.init").total_cycles, 10000);
+        // 呼び出し先の命令数は呼び出し元の合計に含まれる
+        assert_eq!(
+            function("Main.double").total_cycles,
+            function("Main.double").self_cycles + function("Main.one").self_cycles
+        );
+        let self_cycles: usize = profiler.functions.iter().map(|f| f.self_cycles).sum();
+        assert_eq!(self_cycles, 10000);
+
+        let stacks = profiler.collapsed_stacks();
+        assert_eq!(stacks.len(), 3);
+        assert!(stacks[0].starts_with("Sys.init "));
+        assert!(stacks[1].starts_with("Sys.init;Main.double "));
+        assert_eq!(
+            stacks[2],
+            format!(
+                "Sys.init;Main.double;Main.one {}",
+                function("Main.one").self_cycles
+            )
+        );
+        assert_eq!(profiler.flat_profile()[0], "10000 cycles");
+        assert!(profiler.flat_profile()[2].ends_with("Sys.init"));
+    }
+}
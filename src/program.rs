@@ -0,0 +1,144 @@
+use crate::code_writer::helper::filename_without_extention;
+use crate::parser::{Command, CommandType, Parser};
+use crate::static_allocation::StaticAllocation;
+use crate::translator::Translator;
+
+pub const TOP_LEVEL: &str = "(top level)";
+
+pub struct VmFile {
+    pub file_name: String,
+    pub commands: Vec<Command>,
+    // 読めなかった行の番号と理由
+    pub errors: Vec<(usize, String)>,
+}
+
+impl VmFile {
+    pub fn new(file_name: &str, lines: Vec<String>) -> VmFile {
+        let mut parser = Parser::new(lines);
+        let commands = parser.collect_commands();
+        VmFile {
+            file_name: file_name.to_string(),
+            commands,
+            errors: parser.errors,
+        }
+    }
+
+    // functionコマンドの次から次のfunctionまでを1つの関数とする
+    // 最初のfunctionより前のコードはトップレベルとして扱う
+    pub fn functions(&self) -> Vec<(String, &[Command])> {
+        let mut functions = vec![];
+        let mut name = TOP_LEVEL.to_string();
+        let mut start = 0;
+        for (i, command) in self.commands.iter().enumerate() {
+            if command.command_type == CommandType::FUNCTION {
+                if i > start {
+                    functions.push((name, &self.commands[start..i]));
+                }
+                name = command.arg1.clone().unwrap_or_default();
+                start = i + 1;
+            }
+        }
+        if start < self.commands.len() {
+            functions.push((name, &self.commands[start..]));
+        }
+        functions
+    }
+}
+
+pub struct Program {
+    pub files: Vec<VmFile>,
+}
+
+impl Program {
+    pub fn new(files: Vec<VmFile>) -> Program {
+        Program { files }
+    }
+
+    // static変数はファイル名で区別するので、同じ名前のファイルがあると衝突する
+    pub fn check_static_names(&self) -> Result<(), String> {
+        for (i, file) in self.files.iter().enumerate() {
+            let name = filename_without_extention(&file.file_name);
+            for other in &self.files[..i] {
+                if filename_without_extention(&other.file_name) == name {
+                    return Err(format!(
+                        "Static variables of {} and {} collide as {}.N",
+                        other.file_name, file.file_name, name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 全てのファイルのコマンドをファイル名と組にして順に並べる
+    pub fn commands(&self) -> impl Iterator<Item = (&str, &Command)> + Clone {
+        self.files.iter().flat_map(|file| {
+            file.commands
+                .iter()
+                .map(move |command| (file.file_name.as_str(), command))
+        })
+    }
+
+    pub fn defines(&self, function_name: &str) -> bool {
+        self.files
+            .iter()
+            .flat_map(|file| &file.commands)
+            .any(|command| {
+                command.command_type == CommandType::FUNCTION
+                    && command.arg1.as_deref() == Some(function_name)
+            })
+    }
+
+    pub fn translate(&self, translator: &mut dyn Translator) {
+        translator.set_static_allocation(&StaticAllocation::scan(self.commands()));
+        for file in &self.files {
+            translator.set_file_name(&file.file_name);
+            for command in &file.commands {
+                translator.write_command(command);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn program(file_names: &[&str]) -> Program {
+        Program::new(
+            file_names
+                .iter()
+                .map(|file_name| VmFile::new(file_name, vec!["push static 0".to_string()]))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn files_differing_in_case_do_not_collide() {
+        assert_eq!(
+            program(&["dir/Foo.vm", "dir/foo.vm"]).check_static_names(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn same_file_name_in_different_directories_collides() {
+        assert_eq!(
+            program(&["a/Main.vm", "a/Foo.vm", "b/Foo.vm"]).check_static_names(),
+            Err("Static variables of a/Foo.vm and b/Foo.vm collide as Foo.N".to_string())
+        );
+    }
+
+    #[test]
+    fn defines_functions() {
+        let program = Program::new(vec![VmFile::new(
+            "Sys.vm",
+            vec![
+                "function Sys.init 0".to_string(),
+                "call Sys.halt 0".to_string(),
+            ],
+        )]);
+        assert!(program.defines("Sys.init"));
+        assert!(!program.defines("Sys.halt"));
+    }
+}
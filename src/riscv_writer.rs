@@ -0,0 +1,489 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::code_writer::helper::{filename_without_extention, mangle_symbol};
+use crate::code_writer::segment::Segment;
+use crate::static_allocation::StaticAllocation;
+use crate::translator::Translator;
+use std::{fs::OpenOptions, io::prelude::*};
+
+#[cfg(test)]
+mod interpreter;
+
+// a2: ramの先頭, a3: SP, a4: LCL, a5: ARG, a6: THIS, a7: THAT
+// a0: 実行する命令数の上限, a1: 実行した命令数, t6: アドレスのマスク
+// RAM[0..=4]は停止する時にレジスタから書き戻す
+const HEADER: &str = "    .bss
+    .globl ram
+    .p2align 4
+ram:
+    .zero 65536
+
+    .text
+    .globl vm_run
+vm_run:
+    la a2, ram
+    lh a3, 0(a2)
+    lh a4, 2(a2)
+    lh a5, 4(a2)
+    lh a6, 6(a2)
+    lh a7, 8(a2)
+    li t6, 0x7fff
+    li a1, 0
+    bgez a0, vm_start
+    li a0, 0x7fffffff
+vm_start:";
+
+const FOOTER: &str = "vm_halt:
+    sh a3, 0(a2)
+    sh a4, 2(a2)
+    sh a5, 4(a2)
+    sh a6, 6(a2)
+    sh a7, 8(a2)
+    ret";
+
+pub struct RiscvWriter {
+    file_name: String,
+    generated_code: Vec<String>,
+    function_name_stack: Vec<String>,
+    return_address_count: usize,
+    variables: StaticAllocation,
+    defined_functions: Vec<String>,
+    called_functions: Vec<String>,
+}
+
+impl RiscvWriter {
+    pub fn new(file_name: String) -> RiscvWriter {
+        RiscvWriter {
+            file_name,
+            generated_code: vec![],
+            function_name_stack: vec!["null".to_string()],
+            return_address_count: 0,
+            variables: StaticAllocation::default(),
+            defined_functions: vec![],
+            called_functions: vec![],
+        }
+    }
+
+    pub fn generate(&self) -> Vec<String> {
+        let mut code: Vec<String> = HEADER.lines().map(|l| l.to_string()).collect();
+        code.append(&mut self.generated_code.clone());
+        code.push("    j vm_halt".to_string());
+
+        // 戻り先の番号で分岐する (見つからなければ停止)
+        code.push("vm_dispatch:".to_string());
+        for i in 1..=self.return_address_count {
+            code.push(format!("    li t2, {}", i));
+            code.push(format!("    beq t1, t2, vm_return_{}", i));
+        }
+        code.push("    j vm_halt".to_string());
+
+        // 定義されていない関数を呼び出した場合は停止する
+        for function_name in &self.called_functions {
+            if !self.defined_functions.contains(function_name) {
+                code.push(format!("{}:", function_label(function_name)));
+                code.push("    j vm_halt".to_string());
+            }
+        }
+
+        code.append(&mut FOOTER.lines().map(|l| l.to_string()).collect());
+        code
+    }
+
+    // セグメントのアドレスをt2に計算する
+    fn segment_address(&mut self, segment: &str, index: &str) -> Vec<String> {
+        let index = parse(index);
+        match Segment::from_str(segment) {
+            Some(Segment::LOCAL)
+            | Some(Segment::ARGUMENT)
+            | Some(Segment::THIS)
+            | Some(Segment::THAT) => {
+                let register = match Segment::from_str(segment) {
+                    Some(Segment::LOCAL) => "a4",
+                    Some(Segment::ARGUMENT) => "a5",
+                    Some(Segment::THIS) => "a6",
+                    _ => "a7",
+                };
+                vec![
+                    format!("li t2, {}", index),
+                    format!("add t2, {}, t2", register),
+                ]
+            }
+            Some(Segment::TEMP) => vec![format!("li t2, {}", 5 + index)],
+            Some(Segment::STATIC) => {
+                let name = format!("{}.{}", filename_without_extention(&self.file_name), index);
+                vec![format!("li t2, {}", self.variables.address(&name))]
+            }
+            _ => panic!("{} has no address", segment),
+        }
+    }
+
+    fn current_label(&self, label_name: &str) -> String {
+        format!(
+            "vm_label_{}",
+            mangle_symbol(&format!(
+                "{}${}",
+                self.function_name_stack.last().unwrap(),
+                label_name
+            ))
+        )
+    }
+
+    fn append(&mut self, lines: Vec<String>) {
+        self.generated_code
+            .push("    bge a1, a0, vm_halt".to_string());
+        self.generated_code.push("    addi a1, a1, 1".to_string());
+        for line in lines {
+            self.generated_code.push(format!("    {}", line));
+        }
+    }
+}
+
+impl Translator for RiscvWriter {
+    fn set_static_allocation(&mut self, allocation: &StaticAllocation) {
+        self.variables = allocation.clone();
+    }
+
+    fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    fn output(&self, file_name: &str) {
+        let mut output = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        for line in self.generate() {
+            writeln!(output, "{}", line).unwrap();
+        }
+    }
+
+    fn push(&mut self, segment: &str, index: &str) {
+        let mut code = match Segment::from_str(segment) {
+            Some(Segment::CONSTANT) => vec![format!("li t1, {}", index)],
+            Some(Segment::POINTER) => vec![format!("mv t1, {}", pointer_register(index))],
+            _ => {
+                let mut code = self.segment_address(segment, index);
+                code.append(&mut address_code("t2"));
+                code.push("lh t1, 0(t0)".to_string());
+                code
+            }
+        };
+        code.append(&mut push_t1_code());
+        self.append(code);
+    }
+
+    fn pop(&mut self, segment: &str, index: &str) {
+        let code = match Segment::from_str(segment) {
+            Some(Segment::CONSTANT) => return,
+            Some(Segment::POINTER) => {
+                let mut code = vec![
+                    format!("li t2, {}", 3 + parse(index)),
+                    "sh t2, 26(a2)".to_string(),
+                ];
+                code.append(&mut pop_t1_code());
+                code.push(format!("mv {}, t1", pointer_register(index)));
+                code
+            }
+            _ => {
+                let mut code = self.segment_address(segment, index);
+                code.push("sh t2, 26(a2)".to_string());
+                code.append(&mut pop_t1_code());
+                code.append(&mut address_code("t2"));
+                code.push("sh t1, 0(t0)".to_string());
+                code
+            }
+        };
+        self.append(code);
+    }
+
+    fn write_label(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.generated_code.push(format!("{}:", label));
+    }
+
+    fn write_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.append(vec![format!("j {}", label)]);
+    }
+
+    fn write_if_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        let mut code = pop_t1_code();
+        code.push(format!("bnez t1, {}", label));
+        self.append(code);
+    }
+
+    fn write_call(&mut self, function_name: &str, n_arg: &str) {
+        self.return_address_count += 1;
+        let return_address = self.return_address_count;
+        if !self.called_functions.iter().any(|f| f == function_name) {
+            self.called_functions.push(function_name.to_string());
+        }
+
+        // return_addressの代わりに戻り先の番号をpushする
+        let mut code = vec![format!("li t1, {}", return_address)];
+        code.append(&mut push_t1_code());
+        for register in ["a4", "a5", "a6", "a7"] {
+            code.push(format!("mv t1, {}", register));
+            code.append(&mut push_t1_code());
+        }
+
+        // ARG = SP - n_arg - 5, LCL = SP (addiの即値は12ビットなのでliで読む)
+        code.append(&mut vec![
+            format!("li t2, {}", parse(n_arg) + 5),
+            "sub a5, a3, t2".to_string(),
+            "mv a4, a3".to_string(),
+            format!("j {}", function_label(function_name)),
+        ]);
+        self.append(code);
+        self.generated_code
+            .push(format!("vm_return_{}:", return_address));
+    }
+
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
+        use ArithmeticCommand::*;
+        let command = ArithmeticCommand::from_str(arithmetic_command).unwrap();
+        let mut code = vec![];
+        if command != NEG && command != NOT {
+            code.append(&mut pop_t1_code());
+        }
+        // t0にxのアドレス、t3にxの値を置く
+        code.append(&mut vec!["addi t2, a3, -1".to_string()]);
+        code.append(&mut address_code("t2"));
+        code.push("lh t3, 0(t0)".to_string());
+        code.append(&mut match command {
+            ADD => vec!["add t3, t3, t1".to_string()],
+            SUB => vec!["sub t3, t3, t1".to_string()],
+            AND => vec!["and t3, t3, t1".to_string()],
+            OR => vec!["or t3, t3, t1".to_string()],
+            NEG => vec!["neg t3, t3".to_string()],
+            NOT => vec!["not t3, t3".to_string()],
+            // Hackと同じくx-yの符号で比較し、真なら-1、偽なら0にする
+            _ => vec![
+                "sub t3, t3, t1".to_string(),
+                "sh t3, 0(t0)".to_string(),
+                "lh t3, 0(t0)".to_string(),
+                match command {
+                    EQ => "seqz t3, t3".to_string(),
+                    GT => "sgtz t3, t3".to_string(),
+                    _ => "sltz t3, t3".to_string(),
+                },
+                "neg t3, t3".to_string(),
+            ],
+        });
+        code.push("sh t3, 0(t0)".to_string());
+        self.append(code);
+    }
+
+    fn write_function(&mut self, function_name: &str, num_locals: &str) {
+        self.function_name_stack.push(function_name.to_string());
+        self.defined_functions.push(function_name.to_string());
+        self.generated_code
+            .push(format!("{}:", function_label(function_name)));
+        let mut code = vec![];
+        for _ in 0..parse(num_locals) {
+            code.push("li t1, 0".to_string());
+            code.append(&mut push_t1_code());
+        }
+        self.append(code);
+    }
+
+    fn write_return(&mut self) {
+        let frame = self.variables.address("FRAME") * 2;
+        let ret = self.variables.address("RET") * 2;
+        let mut code = vec![
+            "mv t4, a4".to_string(),
+            format!("sh t4, {}(a2)", frame),
+            "addi t2, t4, -5".to_string(),
+        ];
+        code.append(&mut address_code("t2"));
+        code.append(&mut vec![
+            "lh t5, 0(t0)".to_string(),
+            format!("sh t5, {}(a2)", ret),
+        ]);
+
+        // *ARG = pop(), SP = ARG + 1
+        code.push("sh a5, 26(a2)".to_string());
+        code.append(&mut pop_t1_code());
+        code.append(&mut address_code("a5"));
+        code.push("sh t1, 0(t0)".to_string());
+        code.push("addi a3, a5, 1".to_string());
+
+        // THAT, THIS, ARG, LCLを戻す
+        for (offset, register) in [(1, "a7"), (2, "a6"), (3, "a5"), (4, "a4")] {
+            code.push(format!("addi t2, t4, -{}", offset));
+            code.append(&mut address_code("t2"));
+            code.push(format!("lh {}, 0(t0)", register));
+        }
+
+        code.push("mv t1, t5".to_string());
+        code.push("j vm_dispatch".to_string());
+        self.append(code);
+    }
+}
+
+fn parse(number: &str) -> i32 {
+    number.parse::<i32>().unwrap()
+}
+
+fn pointer_register(index: &str) -> &'static str {
+    match index {
+        "0" => "a6",
+        _ => "a7",
+    }
+}
+
+// レジスタが指すRAMのアドレスをt0に計算する
+fn address_code(register: &str) -> Vec<String> {
+    vec![
+        format!("and t0, {}, t6", register),
+        "slli t0, t0, 1".to_string(),
+        "add t0, t0, a2".to_string(),
+    ]
+}
+
+fn push_t1_code() -> Vec<String> {
+    let mut res = address_code("a3");
+    res.push("sh t1, 0(t0)".to_string());
+    res.push("addi a3, a3, 1".to_string());
+    res
+}
+
+fn pop_t1_code() -> Vec<String> {
+    let mut res = vec!["addi a3, a3, -1".to_string()];
+    res.append(&mut address_code("a3"));
+    res.push("lh t1, 0(t0)".to_string());
+    res
+}
+
+fn function_label(function_name: &str) -> String {
+    format!("vm_function_{}", mangle_symbol(function_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::interpreter::Interpreter;
+    use super::*;
+    use crate::parser::Parser;
+    use crate::translator::skip_without_toolchain;
+    use crate::vm_interpreter::VmInterpreter;
+    use std::{env, process::Command};
+
+    const SOURCE: &str = "push constant 32767
+push constant 2
+add
+push constant 20000
+neg
+push constant 20000
+gt
+call Foo.double 1
+pop static 0
+label END
+goto END
+function Foo.double 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+push constant 0
+eq
+not
+if-goto SKIP
+push constant 1
+pop pointer 1
+label SKIP
+push temp 3
+push local 0
+return";
+
+    fn translate(source: &str) -> RiscvWriter {
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        let mut riscv_writer = RiscvWriter::new("Foo.vm".to_string());
+        for command in parser.collect_commands() {
+            riscv_writer.write_command(&command);
+        }
+        riscv_writer
+    }
+
+    #[test]
+    fn push_pointer_uses_register() {
+        let riscv_writer = translate("push pointer 1");
+        assert_eq!(riscv_writer.generated_code[2], "    mv t1, a7");
+    }
+
+    #[test]
+    fn interpreted_program_matches_vm_interpreter() {
+        let ram = [(0, 256), (1, 300), (2, 400)];
+        let mut interpreter = Interpreter::new(&translate(SOURCE).generate());
+        for &(address, value) in &ram {
+            interpreter.set_ram(address, value);
+        }
+        interpreter.run("vm_run", 100);
+
+        let mut parser = Parser::new(SOURCE.lines().map(|l| l.to_string()).collect());
+        let mut vm_interpreter =
+            VmInterpreter::new("Foo.vm".to_string(), parser.collect_commands());
+        for &(address, value) in &ram {
+            vm_interpreter.set(address, value);
+        }
+        vm_interpreter.run(Some(100));
+        assert_eq!(interpreter.dump(), vm_interpreter.dump());
+    }
+
+    #[test]
+    fn call_with_many_arguments() {
+        let source = "push constant 7
+call Foo.f 3000
+pop static 0
+label END
+goto END
+function Foo.f 0
+push argument 0
+return";
+        let riscv_writer = translate(source);
+        assert!(riscv_writer
+            .generated_code
+            .contains(&"    li t2, 3005".to_string()));
+        let mut interpreter = Interpreter::new(&riscv_writer.generate());
+        interpreter.set_ram(0, 3300);
+        interpreter.run("vm_run", 100);
+
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        let mut vm_interpreter =
+            VmInterpreter::new("Foo.vm".to_string(), parser.collect_commands());
+        vm_interpreter.set(0, 3300);
+        vm_interpreter.run(Some(100));
+        assert_eq!(interpreter.dump(), vm_interpreter.dump());
+    }
+
+    // ツールチェーンがある環境ではアセンブルできることも確かめる
+    #[test]
+    fn output_assembles() {
+        let dir = env::temp_dir().join(format!("riscv_writer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let asm_file = dir.join("Foo.s");
+        translate(SOURCE).output(asm_file.to_str().unwrap());
+
+        let mut assembled = false;
+        for (assembler, march) in [
+            ("riscv64-linux-gnu-as", "rv64i"),
+            ("riscv64-unknown-elf-as", "rv32i"),
+        ] {
+            if let Ok(status) = Command::new(assembler)
+                .arg(format!("-march={}", march))
+                .arg("-o")
+                .arg(dir.join("Foo.o"))
+                .arg(&asm_file)
+                .status()
+            {
+                assert!(status.success());
+                assembled = true;
+            }
+        }
+        if !assembled {
+            skip_without_toolchain("no RISC-V assembler is installed");
+        }
+    }
+}
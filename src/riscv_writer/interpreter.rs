@@ -0,0 +1,173 @@
+// riscv_writerが出力する命令だけを実行できる小さなインタプリタ
+use std::collections::HashMap;
+
+const RAM_BASE: i64 = 0x1000_0000;
+const RAM_BYTES: usize = 65536;
+// retで戻る先 (vm_runの呼び出し元)
+const EXIT_ADDRESS: i64 = -1;
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+pub struct Interpreter {
+    instructions: Vec<Vec<String>>,
+    labels: HashMap<String, usize>,
+    registers: [i64; 32],
+    ram: Vec<u8>,
+}
+
+impl Interpreter {
+    pub fn new(code: &[String]) -> Interpreter {
+        let mut instructions = vec![];
+        let mut labels = HashMap::new();
+        for line in code {
+            let line = line.trim();
+            if let Some(label) = line.strip_suffix(':') {
+                labels.insert(label.to_string(), instructions.len());
+            } else if !line.is_empty() && !line.starts_with('.') {
+                let (op, operands) = line.split_once(' ').unwrap_or((line, ""));
+                let mut instruction = vec![op.to_string()];
+                instruction.extend(operands.split(',').map(|o| o.trim().to_string()));
+                instructions.push(instruction);
+            }
+        }
+        Interpreter {
+            instructions,
+            labels,
+            registers: [0; 32],
+            ram: vec![0; RAM_BYTES],
+        }
+    }
+
+    pub fn set_ram(&mut self, address: i16, value: i16) {
+        self.store(RAM_BASE + address as i64 * 2, value);
+    }
+
+    pub fn dump(&self) -> Vec<(usize, i16)> {
+        (0..RAM_BYTES / 2)
+            .map(|address| (address, self.load(RAM_BASE + address as i64 * 2)))
+            .filter(|&(_, value)| value != 0)
+            .collect()
+    }
+
+    pub fn run(&mut self, entry: &str, limit: i64) {
+        self.registers[register("a0")] = limit;
+        self.registers[register("ra")] = EXIT_ADDRESS;
+        let mut pc = self.labels[entry] as i64;
+        while pc != EXIT_ADDRESS {
+            pc = self.execute(pc as usize);
+            self.registers[0] = 0;
+        }
+    }
+
+    fn load(&self, address: i64) -> i16 {
+        let i = (address - RAM_BASE) as usize;
+        i16::from_le_bytes([self.ram[i], self.ram[i + 1]])
+    }
+
+    fn store(&mut self, address: i64, value: i16) {
+        let i = (address - RAM_BASE) as usize;
+        self.ram[i..i + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn get(&self, name: &str) -> i64 {
+        self.registers[register(name)]
+    }
+
+    fn set(&mut self, name: &str, value: i64) {
+        self.registers[register(name)] = value;
+    }
+
+    // "8(a2)" のようなオペランドからアドレスを計算する
+    fn memory_address(&self, operand: &str) -> i64 {
+        let (offset, base) = operand.trim_end_matches(')').split_once('(').unwrap();
+        self.get(base) + offset.parse::<i64>().unwrap()
+    }
+
+    fn branch(&self, pc: usize, condition: bool, label: &str) -> i64 {
+        match condition {
+            true => self.labels[label] as i64,
+            false => pc as i64 + 1,
+        }
+    }
+
+    fn execute(&mut self, pc: usize) -> i64 {
+        let instruction = self.instructions[pc].clone();
+        let op = instruction[0].as_str();
+        let operand = |i: usize| instruction[i].as_str();
+        match op {
+            "la" => {
+                assert_eq!(operand(2), "ram");
+                self.set(operand(1), RAM_BASE);
+            }
+            "li" => self.set(operand(1), parse_immediate(operand(2))),
+            "mv" => self.set(operand(1), self.get(operand(2))),
+            "neg" => self.set(operand(1), self.get(operand(2)).wrapping_neg()),
+            "not" => self.set(operand(1), !self.get(operand(2))),
+            "seqz" => self.set(operand(1), (self.get(operand(2)) == 0) as i64),
+            "sgtz" => self.set(operand(1), (self.get(operand(2)) > 0) as i64),
+            "sltz" => self.set(operand(1), (self.get(operand(2)) < 0) as i64),
+            "addi" => {
+                // 即値は符号付き12ビットに収まらなければアセンブルできない
+                let immediate = parse_immediate(operand(3));
+                assert!(
+                    (-2048..=2047).contains(&immediate),
+                    "addi immediate out of range: {}",
+                    immediate
+                );
+                self.set(operand(1), self.get(operand(2)).wrapping_add(immediate));
+            }
+            "slli" => self.set(
+                operand(1),
+                self.get(operand(2)) << parse_immediate(operand(3)),
+            ),
+            "add" | "sub" | "and" | "or" => {
+                let x = self.get(operand(2));
+                let y = self.get(operand(3));
+                let value = match op {
+                    "add" => x.wrapping_add(y),
+                    "sub" => x.wrapping_sub(y),
+                    "and" => x & y,
+                    _ => x | y,
+                };
+                self.set(operand(1), value);
+            }
+            "lh" => {
+                let value = self.load(self.memory_address(operand(2)));
+                self.set(operand(1), value as i64);
+            }
+            "sh" => {
+                let value = self.get(operand(1)) as i16;
+                self.store(self.memory_address(operand(2)), value);
+            }
+            "j" => return self.labels[operand(1)] as i64,
+            "ret" => return self.get("ra"),
+            "bnez" => return self.branch(pc, self.get(operand(1)) != 0, operand(2)),
+            "bgez" => return self.branch(pc, self.get(operand(1)) >= 0, operand(2)),
+            "beq" => {
+                let condition = self.get(operand(1)) == self.get(operand(2));
+                return self.branch(pc, condition, operand(3));
+            }
+            "bge" => {
+                let condition = self.get(operand(1)) >= self.get(operand(2));
+                return self.branch(pc, condition, operand(3));
+            }
+            _ => panic!("Unsupported instruction: {}", op),
+        }
+        pc as i64 + 1
+    }
+}
+
+fn register(name: &str) -> usize {
+    REGISTER_NAMES.iter().position(|&r| r == name).unwrap()
+}
+
+fn parse_immediate(immediate: &str) -> i64 {
+    match immediate.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).unwrap(),
+        None => immediate.parse::<i64>().unwrap(),
+    }
+}
@@ -0,0 +1,191 @@
+// RAMのスクリーン領域 (512x256、1ワード16ピクセル) を画像ファイルにする
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const SCREEN_ADDRESS: usize = 16384;
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+const WORDS_PER_ROW: usize = WIDTH / 16;
+
+// ワードの最下位ビットが左端のピクセルで、1が黒
+pub fn pixel(ram: &[i16], x: usize, y: usize) -> bool {
+    let word = ram[SCREEN_ADDRESS + y * WORDS_PER_ROW + x / 16] as u16;
+    word >> (x % 16) & 1 == 1
+}
+
+// 左のピクセルを上位ビットに詰めた行。blackが1になるビット
+fn packed_rows(ram: &[i16], black: bool) -> Vec<Vec<u8>> {
+    (0..HEIGHT)
+        .map(|y| {
+            (0..WIDTH / 8)
+                .map(|i| {
+                    (0..8).fold(0, |byte, bit| {
+                        byte << 1 | (pixel(ram, i * 8 + bit, y) == black) as u8
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// バイナリ形式のPBM (P4)
+pub fn to_pbm(ram: &[i16]) -> Vec<u8> {
+    let mut image = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    for row in packed_rows(ram, true) {
+        image.extend(row);
+    }
+    image
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => crc >> 1 ^ 0xedb88320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+fn push_chunk(png: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(chunk_type);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+// 1ビットのグレースケールPNG。圧縮はせずにdeflateの無圧縮ブロックに入れる
+pub fn to_png(ram: &[i16]) -> Vec<u8> {
+    let mut raw = vec![];
+    // グレースケールでは1が白
+    for row in packed_rows(ram, false) {
+        raw.push(0);
+        raw.extend(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(*block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = vec![];
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    // ビット深度1、グレースケール、圧縮・フィルタ・インターレースは標準
+    header.extend([1, 0, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    push_chunk(&mut png, b"IHDR", &header);
+    push_chunk(&mut png, b"IDAT", &zlib);
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// 拡張子が.pngならPNG、それ以外はPBMで書く
+pub fn write_image(file_name: &str, ram: &[i16]) -> io::Result<()> {
+    let image = match Path::new(file_name).extension() {
+        Some(extension) if extension == "png" => to_png(ram),
+        _ => to_pbm(ram),
+    };
+    fs::write(file_name, image)
+}
+
+// screen.png の途中経過は screen-1000.png のようにサイクル数を付ける
+pub fn numbered_file_name(file_name: &str, cycles: usize) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, cycles, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, cycles),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hack_emulator::{HackEmulator, HackProgram};
+    use crate::program::{Program, VmFile};
+    use crate::vm_interpreter::RAM_SIZE;
+
+    #[test]
+    fn renders_pixels() {
+        let mut ram = vec![0; RAM_SIZE];
+        // (0,0) と (17,0)、最後の行の右端
+        ram[SCREEN_ADDRESS] = 1;
+        ram[SCREEN_ADDRESS + 1] = 2;
+        ram[SCREEN_ADDRESS + HEIGHT * WORDS_PER_ROW - 1] = -32768;
+        assert!(pixel(&ram, 0, 0));
+        assert!(pixel(&ram, 17, 0));
+        assert!(!pixel(&ram, 1, 0));
+        assert!(pixel(&ram, WIDTH - 1, HEIGHT - 1));
+
+        let pbm = to_pbm(&ram);
+        let header = b"P4\n512 256\n".len();
+        assert_eq!(pbm.len(), header + WIDTH * HEIGHT / 8);
+        assert_eq!(pbm[header..header + 3], [0x80, 0x00, 0x40]);
+        assert_eq!(pbm.last(), Some(&0x01));
+
+        let png = to_png(&ram);
+        assert_eq!(
+            png[..8],
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']
+        );
+        assert_eq!(
+            png[png.len() - 12..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn snapshot_of_translated_program() {
+        // 左上から8ピクセルの横線を引く
+        let source = "push constant 16384
+pop pointer 1
+push constant 255
+pop that 0";
+        let program = Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )]);
+        let hack_program = HackProgram::new(&program).unwrap();
+        let mut emulator = HackEmulator::new(hack_program.rom.clone());
+        emulator.set(0, 256);
+        emulator.run(None);
+        let pbm = to_pbm(&emulator.ram);
+        let mut expected = b"P4\n512 256\n".to_vec();
+        expected.push(0xff);
+        expected.resize(pbm.len(), 0);
+        assert_eq!(pbm, expected);
+    }
+
+    #[test]
+    fn numbers_file_names() {
+        assert_eq!(
+            numbered_file_name("out/screen.png", 1000),
+            "out/screen-1000.png"
+        );
+        assert_eq!(numbered_file_name("screen", 5), "screen-5");
+    }
+}
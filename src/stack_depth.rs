@@ -0,0 +1,203 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::parser::{Command, CommandType};
+use crate::program::Program;
+use std::collections::HashMap;
+
+// 関数ごとに全ての経路でスタックの深さを追い、崩れている箇所を報告する
+pub fn check(program: &Program) -> Vec<String> {
+    let mut errors = vec![];
+    for file in &program.files {
+        for (function_name, commands) in file.functions() {
+            for (line, message) in check_function(commands) {
+                errors.push(format!(
+                    "{}:{}: {} in {}",
+                    file.file_name, line, message, function_name
+                ));
+            }
+        }
+    }
+    errors
+}
+
+// (必要な値の数, 実行後の深さの変化)
+fn stack_effect(command: &Command) -> (i64, i64) {
+    let n_arg = |command: &Command| {
+        command
+            .arg2
+            .as_deref()
+            .and_then(|n| n.parse::<i64>().ok())
+            .unwrap_or(0)
+    };
+    match command.command_type {
+        CommandType::PUSH => (0, 1),
+        CommandType::POP | CommandType::IF => (1, -1),
+        CommandType::ARITHMETIC => {
+            match ArithmeticCommand::from_str(command.arg1.as_deref().unwrap()) {
+                Some(ArithmeticCommand::NEG) | Some(ArithmeticCommand::NOT) => (1, 0),
+                _ => (2, -1),
+            }
+        }
+        CommandType::CALL => (n_arg(command), 1 - n_arg(command)),
+        CommandType::RETURN => (1, 0),
+        _ => (0, 0),
+    }
+}
+
+// 次に実行するコマンドへ深さを伝え、初めて到達した場合はそのコマンドを返す
+fn flow(
+    commands: &[Command],
+    depths: &mut [Option<i64>],
+    errors: &mut Vec<(usize, String)>,
+    target: usize,
+    depth: i64,
+) -> Option<usize> {
+    if target >= commands.len() {
+        return None;
+    }
+    match depths[target] {
+        None => {
+            depths[target] = Some(depth);
+            Some(target)
+        }
+        Some(known) if known != depth => {
+            let message = format!(
+                "stack depth mismatch at {}: {} and {}",
+                commands[target],
+                known.min(depth),
+                known.max(depth)
+            );
+            if !errors.iter().any(|(i, m)| *i == target && *m == message) {
+                errors.push((target, message));
+            }
+            None
+        }
+        Some(_) => None,
+    }
+}
+
+fn check_function(commands: &[Command]) -> Vec<(usize, String)> {
+    let labels: HashMap<&str, usize> = commands
+        .iter()
+        .enumerate()
+        .filter(|(_, command)| command.command_type == CommandType::LABEL)
+        .map(|(i, command)| (command.arg1.as_deref().unwrap_or_default(), i))
+        .collect();
+    let mut depths: Vec<Option<i64>> = vec![None; commands.len()];
+    let mut errors: Vec<(usize, String)> = vec![];
+    let mut work = vec![];
+
+    work.extend(flow(commands, &mut depths, &mut errors, 0, 0));
+    while let Some(i) = work.pop() {
+        let command = &commands[i];
+        let depth = depths[i].unwrap();
+        let (needed, change) = stack_effect(command);
+        if depth < needed {
+            errors.push((
+                i,
+                format!(
+                    "stack underflow: {} needs {} values but the depth is {}",
+                    command, needed, depth
+                ),
+            ));
+            continue;
+        }
+        if command.command_type == CommandType::RETURN {
+            if depth != 1 {
+                errors.push((i, format!("return with stack depth {}", depth)));
+            }
+            continue;
+        }
+        let depth = depth + change;
+        let jump_target = || {
+            labels
+                .get(command.arg1.as_deref().unwrap_or_default())
+                .copied()
+        };
+        match command.command_type {
+            CommandType::GOTO => {
+                if let Some(target) = jump_target() {
+                    work.extend(flow(commands, &mut depths, &mut errors, target, depth));
+                }
+            }
+            CommandType::IF => {
+                if let Some(target) = jump_target() {
+                    work.extend(flow(commands, &mut depths, &mut errors, target, depth));
+                }
+                work.extend(flow(commands, &mut depths, &mut errors, i + 1, depth));
+            }
+            _ => work.extend(flow(commands, &mut depths, &mut errors, i + 1, depth)),
+        }
+    }
+
+    errors.sort_by_key(|(i, _)| *i);
+    errors
+        .into_iter()
+        .map(|(i, message)| (commands[i].line, message))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::VmFile;
+
+    fn errors(source: &str) -> Vec<String> {
+        let file = VmFile::new("Foo.vm", source.lines().map(|l| l.to_string()).collect());
+        check(&Program::new(vec![file]))
+    }
+
+    #[test]
+    fn balanced_functions() {
+        let source = "push constant 1
+pop temp 0
+function Foo.max 0
+push argument 0
+push argument 1
+gt
+if-goto FIRST
+push argument 1
+goto END
+label FIRST
+push argument 0
+label END
+return
+function Foo.loop 1
+label LOOP
+push local 0
+call Foo.max 1
+pop local 0
+goto LOOP";
+        assert!(errors(source).is_empty());
+    }
+
+    #[test]
+    fn unbalanced_functions() {
+        let source = "function Foo.underflow 0
+push constant 1
+add
+return
+function Foo.join 0
+push argument 0
+if-goto SKIP
+push constant 1
+label SKIP
+push constant 2
+return
+function Foo.extra 0
+push constant 1
+push constant 2
+return
+function Foo.call 0
+call Foo.extra 2
+return";
+        assert_eq!(
+            errors(source),
+            [
+                "Foo.vm:3: stack underflow: add needs 2 values but the depth is 1 in Foo.underflow",
+                "Foo.vm:9: stack depth mismatch at label SKIP: 0 and 1 in Foo.join",
+                "Foo.vm:15: return with stack depth 2 in Foo.extra",
+                "Foo.vm:17: stack underflow: call Foo.extra 2 needs 2 values but the depth is 0 in Foo.call",
+            ]
+        );
+    }
+}
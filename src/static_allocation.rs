@@ -0,0 +1,231 @@
+use crate::code_writer::helper::filename_without_extention;
+use crate::parser::{Command, CommandType};
+use crate::program::Program;
+use std::collections::HashSet;
+
+// Hackのstatic領域はRAM[16..=255]
+pub const STATIC_BASE_ADDRESS: usize = 16;
+pub const STATIC_END_ADDRESS: usize = 255;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticSymbol {
+    // write_returnが使うFRAMEとRETや、定義されていない関数はどのファイルにも属さない
+    pub file_name: Option<String>,
+    pub name: String,
+    pub address: usize,
+}
+
+// 全てのターゲットとVMインタプリタがこの割り当てを使う
+#[derive(Clone, Debug, Default)]
+pub struct StaticAllocation {
+    pub symbols: Vec<StaticSymbol>,
+}
+
+impl StaticAllocation {
+    pub fn new(program: &Program) -> Result<StaticAllocation, String> {
+        let allocation = StaticAllocation::scan(program.commands());
+
+        let capacity = STATIC_END_ADDRESS - STATIC_BASE_ADDRESS + 1;
+        if allocation.symbols.len() > capacity {
+            return Err(format!(
+                "Too many static variables: {} are needed but only {} fit in RAM[{}..={}]",
+                allocation.symbols.len(),
+                capacity,
+                STATIC_BASE_ADDRESS,
+                STATIC_END_ADDRESS
+            ));
+        }
+        Ok(allocation)
+    }
+
+    // アセンブラと同じく、翻訳したコードで初めて使われる順にアドレスを割り当てる
+    // 定義されていない関数へのジャンプ先もアセンブラは変数にする
+    pub fn scan<'a>(
+        commands: impl Iterator<Item = (&'a str, &'a Command)> + Clone,
+    ) -> StaticAllocation {
+        let functions: HashSet<&str> = commands
+            .clone()
+            .filter(|(_, command)| command.command_type == CommandType::FUNCTION)
+            .filter_map(|(_, command)| command.arg1.as_deref())
+            .collect();
+
+        let mut allocation = StaticAllocation::default();
+        for (file_name, command) in commands {
+            match command.command_type {
+                CommandType::PUSH | CommandType::POP
+                    if command.arg1.as_deref() == Some("static") =>
+                {
+                    let name = format!(
+                        "{}.{}",
+                        filename_without_extention(file_name),
+                        command.arg2.as_deref().unwrap()
+                    );
+                    allocation.allocate(Some(file_name), &name);
+                }
+                CommandType::CALL => {
+                    let function_name = command.arg1.as_deref().unwrap();
+                    if !functions.contains(function_name) {
+                        allocation.allocate(None, function_name);
+                    }
+                }
+                CommandType::RETURN => {
+                    allocation.allocate(None, "FRAME");
+                    allocation.allocate(None, "RET");
+                }
+                _ => (),
+            }
+        }
+        allocation
+    }
+
+    fn allocate(&mut self, file_name: Option<&str>, name: &str) -> usize {
+        match self.symbols.iter().find(|symbol| symbol.name == name) {
+            Some(symbol) => symbol.address,
+            None => {
+                let address = STATIC_BASE_ADDRESS + self.symbols.len();
+                self.symbols.push(StaticSymbol {
+                    file_name: file_name.map(|f| f.to_string()),
+                    name: name.to_string(),
+                    address,
+                });
+                address
+            }
+        }
+    }
+
+    // 事前に調べていない名前には続きのアドレスを割り当てる
+    pub fn address(&mut self, name: &str) -> usize {
+        self.allocate(None, name)
+    }
+
+    // ファイルのstatic変数の名前とアドレス
+    pub fn static_variables(&self, file_name: &str) -> Vec<(String, usize)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.file_name.as_deref() == Some(file_name))
+            .map(|symbol| (symbol.name.clone(), symbol.address))
+            .collect()
+    }
+
+    // ファイルごとのstatic変数のアドレス一覧
+    pub fn report(&self) -> Vec<String> {
+        let mut file_names: Vec<Option<String>> = vec![];
+        for symbol in &self.symbols {
+            if !file_names.contains(&symbol.file_name) {
+                file_names.push(symbol.file_name.clone());
+            }
+        }
+
+        let mut lines = vec![];
+        for file_name in file_names {
+            lines.push(match &file_name {
+                Some(file_name) => format!("{}:", file_name),
+                None => "(translator):".to_string(),
+            });
+            for symbol in self.symbols.iter().filter(|s| s.file_name == file_name) {
+                lines.push(format!("  {} {}", symbol.name, symbol.address));
+            }
+        }
+        lines.push(format!(
+            "{} of {} static addresses used",
+            self.symbols.len(),
+            STATIC_END_ADDRESS - STATIC_BASE_ADDRESS + 1
+        ));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code_writer::CodeWriter;
+    use crate::hack_emulator::assemble;
+    use crate::program::VmFile;
+
+    fn file(file_name: &str, source: &str) -> VmFile {
+        VmFile::new(file_name, source.lines().map(|l| l.to_string()).collect())
+    }
+
+    #[test]
+    fn allocates_in_order_of_appearance() {
+        let program = Program::new(vec![
+            file(
+                "dir/Foo.vm",
+                "push static 3\npop static 0\npush static 3\nreturn",
+            ),
+            file("dir/Bar.vm", "pop static 3"),
+        ]);
+        let allocation = StaticAllocation::new(&program).unwrap();
+        assert_eq!(
+            allocation.report(),
+            [
+                "dir/Foo.vm:",
+                "  Foo.3 16",
+                "  Foo.0 17",
+                "(translator):",
+                "  FRAME 18",
+                "  RET 19",
+                "dir/Bar.vm:",
+                "  Bar.3 20",
+                "5 of 240 static addresses used",
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_assembler_with_external_call() {
+        let program = Program::new(vec![
+            file(
+                "Main.vm",
+                "function Main.main 0\npush constant 1\ncall Math.abs 1\npop static 0\npush static 1\nreturn",
+            ),
+            file(
+                "Foo.vm",
+                "function Foo.f 0\ncall Main.main 0\npop static 2\nreturn",
+            ),
+        ]);
+        let allocation = StaticAllocation::new(&program).unwrap();
+        assert_eq!(
+            allocation.report(),
+            [
+                "(translator):",
+                "  Math.abs 16",
+                "  FRAME 19",
+                "  RET 20",
+                "Main.vm:",
+                "  Main.0 17",
+                "  Main.1 18",
+                "Foo.vm:",
+                "  Foo.2 21",
+                "6 of 240 static addresses used",
+            ]
+        );
+
+        let mut code_writer = CodeWriter::new("Main.vm".to_string());
+        program.translate(&mut code_writer);
+        let (_, symbols) = assemble(code_writer.code()).unwrap();
+        for symbol in &allocation.symbols {
+            assert_eq!(
+                symbols[&symbol.name] as usize, symbol.address,
+                "{}",
+                symbol.name
+            );
+        }
+    }
+
+    #[test]
+    fn too_many_statics() {
+        let source = (0..241)
+            .map(|i| format!("push static {}", i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let program = Program::new(vec![file("Foo.vm", &source)]);
+        assert_eq!(
+            StaticAllocation::new(&program).err(),
+            Some(
+                "Too many static variables: 241 are needed but only 240 fit in RAM[16..=255]"
+                    .to_string()
+            )
+        );
+    }
+}
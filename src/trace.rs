@@ -0,0 +1,127 @@
+// 実行した1ステップごとの状態をJSON Linesの1行にする
+use crate::code_writer::helper::json_string;
+use crate::hack_emulator::HackEmulator;
+use crate::vm_interpreter::VmInterpreter;
+
+// SP LCL ARG THIS THAT とスタックの先頭、書き込み
+fn registers(ram: &[i16], writes: &[(usize, i16)]) -> String {
+    let sp = ram[0];
+    let top = match sp {
+        1.. => ram[sp as usize - 1].to_string(),
+        _ => "null".to_string(),
+    };
+    let writes: Vec<String> = writes
+        .iter()
+        .map(|(address, value)| format!("[{},{}]", address, value))
+        .collect();
+    format!(
+        "\"sp\":{},\"lcl\":{},\"arg\":{},\"this\":{},\"that\":{},\"top\":{},\"writes\":[{}]",
+        sp,
+        ram[1],
+        ram[2],
+        ram[3],
+        ram[4],
+        top,
+        writes.join(",")
+    )
+}
+
+// command_indexのコマンドを実行した直後に呼ぶ
+pub fn vm_record(interpreter: &VmInterpreter, command_index: usize) -> String {
+    let command = &interpreter.commands()[command_index];
+    format!(
+        "{{\"step\":{},\"pc\":{},\"file\":{},\"line\":{},\"command\":{},{}}}",
+        interpreter.steps,
+        command_index,
+        json_string(interpreter.file_name(command_index)),
+        command.line,
+        json_string(&command.to_string()),
+        registers(&interpreter.ram, &interpreter.writes)
+    )
+}
+
+// pcの命令を実行した直後に呼ぶ
+pub fn hack_record(emulator: &HackEmulator, pc: u16, instruction: &str) -> String {
+    format!(
+        "{{\"step\":{},\"pc\":{},\"instruction\":{},\"a\":{},\"d\":{},{}}}",
+        emulator.steps,
+        pc,
+        json_string(instruction),
+        emulator.a,
+        emulator.d,
+        registers(&emulator.ram, &emulator.writes)
+    )
+}
+
+// ラベルは命令として数えないので記録しない
+pub fn trace_vm(interpreter: &mut VmInterpreter, limit: Option<usize>) -> Vec<String> {
+    let mut records = vec![];
+    while !interpreter.halted && limit.is_none_or(|limit| interpreter.steps < limit) {
+        let pc = interpreter.pc;
+        let steps = interpreter.steps;
+        interpreter.step();
+        if interpreter.steps > steps {
+            records.push(vm_record(interpreter, pc));
+        }
+    }
+    records
+}
+
+pub fn trace_hack(
+    emulator: &mut HackEmulator,
+    instructions: &[String],
+    limit: Option<usize>,
+) -> Vec<String> {
+    let mut records = vec![];
+    while !emulator.halted() && limit.is_none_or(|limit| emulator.steps < limit) {
+        let pc = emulator.pc;
+        emulator.step();
+        records.push(hack_record(emulator, pc, &instructions[pc as usize]));
+    }
+    records
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hack_emulator::HackProgram;
+    use crate::program::{Program, VmFile};
+
+    fn program(source: &str) -> Program {
+        Program::new(vec![VmFile::new(
+            "Main.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        )])
+    }
+
+    #[test]
+    fn trace_vm_steps() {
+        let program = program("push constant 7\nlabel L\npop local 1");
+        let mut interpreter = VmInterpreter::from_program(&program);
+        interpreter.set(0, 256);
+        interpreter.set(1, 300);
+        assert_eq!(
+            trace_vm(&mut interpreter, None),
+            [
+                "{\"step\":1,\"pc\":0,\"file\":\"Main.vm\",\"line\":1,\"command\":\"push constant 7\",\"sp\":257,\"lcl\":300,\"arg\":0,\"this\":0,\"that\":0,\"top\":7,\"writes\":[[256,7],[0,257]]}",
+                "{\"step\":2,\"pc\":2,\"file\":\"Main.vm\",\"line\":3,\"command\":\"pop local 1\",\"sp\":256,\"lcl\":300,\"arg\":0,\"this\":0,\"that\":0,\"top\":0,\"writes\":[[13,301],[0,256],[301,7]]}",
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_hack_steps() {
+        let program = program("push constant 7");
+        let hack_program = HackProgram::new(&program).unwrap();
+        let mut emulator = HackEmulator::new(hack_program.rom.clone());
+        emulator.set(0, 256);
+        let records = trace_hack(&mut emulator, &hack_program.instructions, None);
+        assert_eq!(records.len(), hack_program.rom.len());
+        assert_eq!(
+            records[0],
+            "{\"step\":1,\"pc\":0,\"instruction\":\"@7\",\"a\":7,\"d\":0,\"sp\":256,\"lcl\":0,\"arg\":0,\"this\":0,\"that\":0,\"top\":0,\"writes\":[]}"
+        );
+        assert!(records.last().unwrap().contains("\"sp\":257"));
+        assert!(records.iter().any(|r| r.contains("\"writes\":[[256,7]]")));
+    }
+}
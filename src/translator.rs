@@ -0,0 +1,50 @@
+use crate::parser::{Command, CommandType};
+use crate::static_allocation::StaticAllocation;
+
+pub trait Translator {
+    fn set_file_name(&mut self, file_name: &str);
+    fn push(&mut self, segment: &str, index: &str);
+    fn pop(&mut self, segment: &str, index: &str);
+    fn write_label(&mut self, label_name: &str);
+    fn write_go_to(&mut self, label_name: &str);
+    fn write_if_go_to(&mut self, label_name: &str);
+    fn write_call(&mut self, function_name: &str, n_arg: &str);
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str);
+    fn write_function(&mut self, function_name: &str, num_locals: &str);
+    fn write_return(&mut self);
+    fn output(&self, file_name: &str);
+
+    // 翻訳を始める前に、プログラム全体で割り当てたstatic変数のアドレスを受け取る
+    // Hackではアセンブラが同じ順に割り当てるので使わない
+    fn set_static_allocation(&mut self, _allocation: &StaticAllocation) {}
+
+    // 各コマンドを書く前に呼ばれる
+    fn begin_command(&mut self, _command: &Command) {}
+
+    fn write_command(&mut self, command: &Command) {
+        self.begin_command(command);
+        let arg1 = command.arg1.as_deref();
+        let arg2 = command.arg2.as_deref();
+        match command.command_type {
+            CommandType::ARITHMETIC => self.run_arichmetic_command(arg1.unwrap()),
+            CommandType::PUSH => self.push(arg1.unwrap(), arg2.unwrap()),
+            CommandType::POP => self.pop(arg1.unwrap(), arg2.unwrap()),
+            CommandType::LABEL => self.write_label(arg1.unwrap()),
+            CommandType::GOTO => self.write_go_to(arg1.unwrap()),
+            CommandType::IF => self.write_if_go_to(arg1.unwrap()),
+            CommandType::CALL => self.write_call(arg1.unwrap(), arg2.unwrap()),
+            CommandType::FUNCTION => self.write_function(arg1.unwrap(), arg2.unwrap()),
+            CommandType::RETURN => self.write_return(),
+        }
+    }
+}
+
+// ツールチェーンが無い環境ではテストを飛ばす
+// REQUIRE_TOOLCHAINSが設定されていれば黙って通さずに失敗させる
+#[cfg(test)]
+pub(crate) fn skip_without_toolchain(reason: &str) {
+    if std::env::var_os("REQUIRE_TOOLCHAINS").is_some() {
+        panic!("{}", reason);
+    }
+    eprintln!("skipped: {}", reason);
+}
@@ -0,0 +1,254 @@
+use crate::code_writer::segment::Segment;
+use crate::parser::{Command, CommandType};
+use crate::program::Program;
+use std::collections::HashMap;
+
+// Hackのメモリに収まらないセグメントやインデックスを翻訳前に見つける
+pub fn validate(program: &Program) -> Vec<String> {
+    let mut errors = vec![];
+    for file in &program.files {
+        let mut file_errors = file.errors.clone();
+        for command in &file.commands {
+            if let Err(message) = validate_command(command) {
+                file_errors.push((command.line, message));
+            }
+        }
+        // 読めなかった行も含めて行番号順にする
+        file_errors.sort_by_key(|&(line, _)| line);
+        for (line, message) in file_errors {
+            errors.push(format!("{}:{}: {}", file.file_name, line, message));
+        }
+    }
+    errors
+}
+
+// 全ファイルの関数定義と呼び出しを突き合わせる
+// allow_externalにはOSなど別に用意される関数名か"Class.*"を指定する
+pub fn check_calls(program: &Program, allow_external: &[String]) -> Vec<String> {
+    let mut errors = vec![];
+    let mut definitions: HashMap<&str, String> = HashMap::new();
+    for file in &program.files {
+        for command in &file.commands {
+            if command.command_type != CommandType::FUNCTION {
+                continue;
+            }
+            let location = format!("{}:{}", file.file_name, command.line);
+            let function_name = command.arg1.as_deref().unwrap_or_default();
+            match definitions.get(function_name) {
+                Some(defined_at) => errors.push(format!(
+                    "{}: function {} is already defined at {}",
+                    location, function_name, defined_at
+                )),
+                None => {
+                    definitions.insert(function_name, location);
+                }
+            }
+        }
+    }
+
+    // 関数ごとに最初の呼び出しの引数の数と場所
+    let mut first_calls: HashMap<&str, (&str, String)> = HashMap::new();
+    for file in &program.files {
+        for command in &file.commands {
+            if command.command_type != CommandType::CALL {
+                continue;
+            }
+            let location = format!("{}:{}", file.file_name, command.line);
+            let function_name = command.arg1.as_deref().unwrap_or_default();
+            let n_arg = command.arg2.as_deref().unwrap_or_default();
+            if !definitions.contains_key(function_name)
+                && !is_external(function_name, allow_external)
+            {
+                errors.push(format!(
+                    "{}: call to undefined function {}",
+                    location, function_name
+                ));
+            }
+            match first_calls.get(function_name) {
+                Some((first_n_arg, first_location)) if *first_n_arg != n_arg => {
+                    errors.push(format!(
+                        "{}: call {} {} differs from call {} {} at {}",
+                        location, function_name, n_arg, function_name, first_n_arg, first_location
+                    ))
+                }
+                Some(_) => (),
+                None => {
+                    first_calls.insert(function_name, (n_arg, location));
+                }
+            }
+        }
+    }
+    errors
+}
+
+fn is_external(function_name: &str, allow_external: &[String]) -> bool {
+    allow_external
+        .iter()
+        .any(|external| match external.strip_suffix('*') {
+            Some(prefix) => function_name.starts_with(prefix),
+            None => external == function_name,
+        })
+}
+
+fn validate_command(command: &Command) -> Result<(), String> {
+    match command.command_type {
+        CommandType::PUSH | CommandType::POP => (),
+        CommandType::FUNCTION | CommandType::CALL => return validate_count(command),
+        _ => return Ok(()),
+    }
+    let segment_name = command.arg1.as_deref().unwrap_or_default();
+    let index_str = command.arg2.as_deref().unwrap_or_default();
+    let text = command.to_string();
+
+    let segment = Segment::parse(segment_name)
+        .ok_or(format!("{}: unknown segment {}", text, segment_name))?;
+    let index = index_str
+        .parse::<i64>()
+        .map_err(|_| format!("{}: index {} is not a number", text, index_str))?;
+    if index < 0 {
+        return Err(format!("{}: index must not be negative", text));
+    }
+
+    // constantへのpopはコードが生成されない
+    if segment == Segment::CONSTANT && command.command_type == CommandType::POP {
+        return Err(format!("{}: cannot pop to constant", text));
+    }
+    // 定数以外もインデックスはHackのAレジスタに入る範囲にする
+    let max = match segment {
+        Segment::POINTER => 1,
+        Segment::TEMP => 7,
+        _ => 32767,
+    };
+    if index > max {
+        return Err(format!("{}: index must be in 0..={}", text, max));
+    }
+    Ok(())
+}
+
+// functionのローカル変数の数とcallの引数の数
+fn validate_count(command: &Command) -> Result<(), String> {
+    let count = command.arg2.as_deref().unwrap_or_default();
+    match count.parse::<i16>() {
+        Ok(count) if count >= 0 => Ok(()),
+        _ => Err(format!("{}: {} is not a valid count", command, count)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::VmFile;
+
+    fn errors(source: &str) -> Vec<String> {
+        let file = VmFile::new(
+            "dir/Foo.vm",
+            source.lines().map(|l| l.to_string()).collect(),
+        );
+        validate(&Program::new(vec![file]))
+    }
+
+    #[test]
+    fn valid_commands() {
+        let source = "push constant 32767
+pop pointer 1
+push temp 7
+push local 100
+pop static 0
+push that 32767
+pop argument 32767
+add";
+        assert!(errors(source).is_empty());
+    }
+
+    #[test]
+    fn invalid_commands_are_located() {
+        let source = "// comment
+pop constant 3
+push pointer 5
+
+push temp 9
+push constant 70000
+pop local -1
+push heap 0
+push that x
+pusj constant 1
+function Foo.bar -1
+call Foo.bar x
+push local 32768
+pop argument 40000
+push this 32768
+pop that 99999
+push static 32768";
+        assert_eq!(
+            errors(source),
+            [
+                "dir/Foo.vm:2: pop constant 3: cannot pop to constant",
+                "dir/Foo.vm:3: push pointer 5: index must be in 0..=1",
+                "dir/Foo.vm:5: push temp 9: index must be in 0..=7",
+                "dir/Foo.vm:6: push constant 70000: index must be in 0..=32767",
+                "dir/Foo.vm:7: pop local -1: index must not be negative",
+                "dir/Foo.vm:8: push heap 0: unknown segment heap",
+                "dir/Foo.vm:9: push that x: index x is not a number",
+                "dir/Foo.vm:10: unknown command pusj constant 1",
+                "dir/Foo.vm:11: function Foo.bar -1: -1 is not a valid count",
+                "dir/Foo.vm:12: call Foo.bar x: x is not a valid count",
+                "dir/Foo.vm:13: push local 32768: index must be in 0..=32767",
+                "dir/Foo.vm:14: pop argument 40000: index must be in 0..=32767",
+                "dir/Foo.vm:15: push this 32768: index must be in 0..=32767",
+                "dir/Foo.vm:16: pop that 99999: index must be in 0..=32767",
+                "dir/Foo.vm:17: push static 32768: index must be in 0..=32767",
+            ]
+        );
+    }
+
+    fn call_errors(sources: &[(&str, &str)], allow_external: &[&str]) -> Vec<String> {
+        let files = sources
+            .iter()
+            .map(|(file_name, source)| {
+                VmFile::new(file_name, source.lines().map(|l| l.to_string()).collect())
+            })
+            .collect();
+        let allow_external: Vec<String> = allow_external.iter().map(|s| s.to_string()).collect();
+        check_calls(&Program::new(files), &allow_external)
+    }
+
+    #[test]
+    fn calls_across_files() {
+        let main = "function Main.main 0
+call Foo.bar 2
+call Math.multiply 2
+call Output.printInt 1
+return";
+        let foo = "function Foo.bar 0
+call Main.main 0
+return";
+        assert!(call_errors(
+            &[("Main.vm", main), ("Foo.vm", foo)],
+            &["Math.multiply", "Output.*"]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn invalid_calls_are_located() {
+        let main = "function Main.main 0
+call Foo.bar 2
+call Foo.baz 0
+call Math.multiply 2
+return";
+        let foo = "function Foo.bar 0
+call Foo.bar 1
+return
+function Main.main 0
+return";
+        assert_eq!(
+            call_errors(&[("Main.vm", main), ("Foo.vm", foo)], &[]),
+            [
+                "Foo.vm:4: function Main.main is already defined at Main.vm:1",
+                "Main.vm:3: call to undefined function Foo.baz",
+                "Main.vm:4: call to undefined function Math.multiply",
+                "Foo.vm:2: call Foo.bar 1 differs from call Foo.bar 2 at Main.vm:2",
+            ]
+        );
+    }
+}
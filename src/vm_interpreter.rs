@@ -0,0 +1,497 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::code_writer::helper::filename_without_extention;
+use crate::code_writer::segment::Segment;
+use crate::jack_os::{self, JackOs, Memory, NativeResult};
+use crate::parser::{Command, CommandType};
+use crate::program::Program;
+use crate::static_allocation::StaticAllocation;
+use std::collections::HashMap;
+
+pub const RAM_SIZE: usize = 32768;
+
+pub struct VmInterpreter {
+    pub ram: Vec<i16>,
+    pub pc: usize,
+    pub steps: usize,
+    pub halted: bool,
+    // 直前のstepで書き込んだアドレスと値
+    pub writes: Vec<(usize, i16)>,
+    commands: Vec<Command>,
+    // コマンドごとのファイル名
+    file_names: Vec<String>,
+    scopes: Vec<String>,
+    labels: HashMap<String, usize>,
+    functions: HashMap<String, usize>,
+    variables: StaticAllocation,
+    return_addresses: Vec<usize>,
+    // 定義されていないOSの関数を代わりに実行する
+    os: JackOs,
+}
+
+impl VmInterpreter {
+    pub fn new(file_name: String, commands: Vec<Command>) -> VmInterpreter {
+        let file_names = vec![file_name; commands.len()];
+        VmInterpreter::with_file_names(commands, file_names)
+    }
+
+    // 全てのファイルのコマンドを順に並べて実行する
+    pub fn from_program(program: &Program) -> VmInterpreter {
+        let mut commands = vec![];
+        let mut file_names = vec![];
+        for file in &program.files {
+            commands.extend(file.commands.iter().cloned());
+            file_names.extend(file.commands.iter().map(|_| file.file_name.clone()));
+        }
+        VmInterpreter::with_file_names(commands, file_names)
+    }
+
+    // Sys.init、なければ組み込みのOSを使うとみなしてMain.mainから始め、RAMの初期値を書く
+    pub fn prepare(program: &Program, ram: &[(i16, i16)]) -> VmInterpreter {
+        let mut interpreter = VmInterpreter::from_program(program);
+        if let Some(entry) = interpreter
+            .function_address("Sys.init")
+            .or_else(|| interpreter.function_address("Main.main"))
+        {
+            interpreter.boot(entry);
+        }
+        for &(address, value) in ram {
+            interpreter.set(address, value);
+        }
+        interpreter
+    }
+
+    fn with_file_names(commands: Vec<Command>, file_names: Vec<String>) -> VmInterpreter {
+        let mut interpreter = VmInterpreter {
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            steps: 0,
+            halted: commands.is_empty(),
+            writes: vec![],
+            commands,
+            file_names,
+            scopes: vec![],
+            labels: HashMap::new(),
+            functions: HashMap::new(),
+            variables: StaticAllocation::default(),
+            return_addresses: vec![],
+            os: JackOs::default(),
+        };
+        interpreter.scan();
+        interpreter
+    }
+
+    // ラベルと関数の位置、変数のアドレス、戻り先を事前に調べる
+    fn scan(&mut self) {
+        let mut scope = "null".to_string();
+        for i in 0..self.commands.len() {
+            let command = self.commands[i].clone();
+            let arg1 = command.arg1.clone().unwrap_or_default();
+            match command.command_type {
+                CommandType::FUNCTION => {
+                    scope = arg1.clone();
+                    self.functions.insert(arg1, i);
+                }
+                CommandType::LABEL => {
+                    self.labels.insert(format!("{}${}", scope, arg1), i);
+                }
+                CommandType::CALL => self.return_addresses.push(i + 1),
+                _ => (),
+            }
+            self.scopes.push(scope.clone());
+        }
+        self.variables = StaticAllocation::scan(
+            self.file_names
+                .iter()
+                .map(|file_name| file_name.as_str())
+                .zip(&self.commands),
+        );
+    }
+
+    fn static_name(&self, command_index: usize, index: &str) -> String {
+        format!(
+            "{}.{}",
+            filename_without_extention(&self.file_names[command_index]),
+            index
+        )
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn file_name(&self, command_index: usize) -> &str {
+        &self.file_names[command_index]
+    }
+
+    // コマンドが属する関数 (トップレベルは"null")
+    pub fn function_name(&self, command_index: usize) -> &str {
+        &self.scopes[command_index]
+    }
+
+    pub fn function_address(&self, function_name: &str) -> Option<usize> {
+        self.functions.get(function_name).copied()
+    }
+
+    // "関数名$ラベル"でもラベル名だけでも探せる
+    pub fn label_addresses(&self, label: &str) -> Vec<usize> {
+        let suffix = format!("${}", label);
+        let mut addresses: Vec<usize> = self
+            .labels
+            .iter()
+            .filter(|(name, _)| *name == label || name.ends_with(&suffix))
+            .map(|(_, &i)| i)
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
+    // 戻り先の値から呼び出し元のcallの次のコマンドを求める
+    pub fn return_command(&self, return_address: i16) -> Option<usize> {
+        match return_address {
+            ret if ret > 0 => self.return_addresses.get(ret as usize - 1).copied(),
+            _ => None,
+        }
+    }
+
+    // ファイルのstatic変数の名前とアドレス
+    pub fn static_variables(&self, file_name: &str) -> Vec<(String, usize)> {
+        self.variables.static_variables(file_name)
+    }
+
+    // ブートストラップの SP=256; call Sys.init を実行した後と同じ状態にする
+    pub fn boot(&mut self, entry: usize) {
+        self.set(0, 261);
+        self.set(1, 261);
+        self.set(2, 256);
+        self.pc = entry;
+        self.halted = false;
+    }
+
+    pub fn get(&self, address: i16) -> i16 {
+        self.ram[address as u16 as usize & 0x7fff]
+    }
+
+    pub fn set(&mut self, address: i16, value: i16) {
+        let address = address as u16 as usize & 0x7fff;
+        self.ram[address] = value;
+        self.writes.push((address, value));
+    }
+
+    fn push_value(&mut self, value: i16) {
+        let sp = self.get(0);
+        self.set(sp, value);
+        self.set(0, sp.wrapping_add(1));
+    }
+
+    fn pop_value(&mut self) -> i16 {
+        let sp = self.get(0).wrapping_sub(1);
+        self.set(0, sp);
+        self.get(sp)
+    }
+
+    fn segment_address(&mut self, current: usize, segment: &str, index: &str) -> i16 {
+        let index = index.parse::<i16>().unwrap();
+        match Segment::from_str(segment) {
+            Some(Segment::LOCAL) => self.get(1).wrapping_add(index),
+            Some(Segment::ARGUMENT) => self.get(2).wrapping_add(index),
+            Some(Segment::THIS) => self.get(3).wrapping_add(index),
+            Some(Segment::THAT) => self.get(4).wrapping_add(index),
+            Some(Segment::POINTER) => 3 + index,
+            Some(Segment::TEMP) => 5 + index,
+            Some(Segment::STATIC) => {
+                let name = self.static_name(current, &index.to_string());
+                self.variables.address(&name) as i16
+            }
+            _ => panic!("{} has no address", segment),
+        }
+    }
+
+    fn jump_to_label(&mut self, label_name: &str) {
+        let label = format!("{}${}", self.scopes[self.pc], label_name);
+        match self.labels.get(&label) {
+            Some(&i) => self.pc = i,
+            None => self.halted = true,
+        }
+    }
+
+    pub fn run(&mut self, limit: Option<usize>) {
+        while !self.halted && limit.is_none_or(|limit| self.steps < limit) {
+            self.step();
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.halted {
+            return;
+        }
+        self.writes.clear();
+        let command = self.commands[self.pc].clone();
+        let arg1 = command.arg1.as_deref().unwrap_or_default();
+        let arg2 = command.arg2.as_deref().unwrap_or_default();
+        if command.command_type != CommandType::LABEL {
+            self.steps += 1;
+        }
+        let current = self.pc;
+        self.pc += 1;
+
+        match command.command_type {
+            CommandType::ARITHMETIC => self.run_arithmetic_command(arg1),
+            CommandType::PUSH => {
+                let value = match Segment::from_str(arg1) {
+                    Some(Segment::CONSTANT) => arg2.parse::<i16>().unwrap(),
+                    _ => {
+                        let address = self.segment_address(current, arg1, arg2);
+                        self.get(address)
+                    }
+                };
+                self.push_value(value);
+            }
+            CommandType::POP => {
+                if Segment::from_str(arg1) != Some(Segment::CONSTANT) {
+                    let address = self.segment_address(current, arg1, arg2);
+                    self.set(13, address);
+                    let value = self.pop_value();
+                    self.set(address, value);
+                }
+            }
+            CommandType::LABEL => (),
+            CommandType::GOTO => {
+                self.pc = current;
+                self.jump_to_label(arg1);
+            }
+            CommandType::IF => {
+                if self.pop_value() != 0 {
+                    self.pc = current;
+                    self.jump_to_label(arg1);
+                }
+            }
+            CommandType::FUNCTION => {
+                for _ in 0..arg2.parse::<i16>().unwrap() {
+                    self.push_value(0);
+                }
+            }
+            CommandType::CALL => self.call(current, arg1, arg2),
+            CommandType::RETURN => self.return_from_function(),
+        }
+
+        if self.pc >= self.commands.len() {
+            self.halted = true;
+        }
+    }
+
+    fn run_arithmetic_command(&mut self, arithmetic_command: &str) {
+        use ArithmeticCommand::*;
+        let command = ArithmeticCommand::from_str(arithmetic_command).unwrap();
+        if command == NEG || command == NOT {
+            let x = self.pop_value();
+            self.push_value(if command == NEG { x.wrapping_neg() } else { !x });
+            return;
+        }
+
+        let y = self.pop_value();
+        let x = self.pop_value();
+        // Hackと同じくx-yの符号で比較する
+        let result = match command {
+            ADD => x.wrapping_add(y),
+            SUB => x.wrapping_sub(y),
+            AND => x & y,
+            OR => x | y,
+            EQ => -((x.wrapping_sub(y) == 0) as i16),
+            GT => -((x.wrapping_sub(y) > 0) as i16),
+            _ => -((x.wrapping_sub(y) < 0) as i16),
+        };
+        self.push_value(result);
+    }
+
+    pub fn os(&self) -> &JackOs {
+        &self.os
+    }
+
+    fn call(&mut self, current: usize, function_name: &str, n_arg: &str) {
+        if !self.functions.contains_key(function_name) && jack_os::is_os_function(function_name) {
+            self.call_native(current, function_name, n_arg.parse::<i16>().unwrap());
+            return;
+        }
+        // 戻り先には何番目のcallかを積む
+        let return_address = self.return_addresses.binary_search(&(current + 1)).unwrap() + 1;
+        self.push_value(return_address as i16);
+        for register in 1..=4 {
+            let value = self.get(register);
+            self.push_value(value);
+        }
+        let sp = self.get(0);
+        let n_arg = n_arg.parse::<i16>().unwrap();
+        self.set(2, sp.wrapping_sub(n_arg).wrapping_sub(5));
+        self.set(1, sp);
+
+        match self.functions.get(function_name) {
+            Some(&i) => self.pc = i,
+            None => self.halted = true,
+        }
+    }
+
+    // VMEmulatorと同じく、フレームを作らずに引数を戻り値に置き換える
+    fn call_native(&mut self, current: usize, function_name: &str, n_arg: i16) {
+        let sp = self.get(0);
+        let args: Vec<i16> = (0..n_arg)
+            .map(|i| self.get(sp.wrapping_sub(n_arg - i)))
+            .collect();
+        let mut os = std::mem::take(&mut self.os);
+        let result = os.call(function_name, &args, self);
+        match result {
+            NativeResult::RETURN(value) => {
+                self.set(0, sp.wrapping_sub(n_arg));
+                self.push_value(value);
+            }
+            // キー入力を待つ間は同じcallを繰り返す
+            NativeResult::WAIT => self.pc = current,
+            NativeResult::HALT => self.halted = true,
+            NativeResult::ERROR(code) => {
+                os.print_error(code, self);
+                self.halted = true;
+            }
+        }
+        self.os = os;
+    }
+
+    fn return_from_function(&mut self) {
+        let frame_address = self.variables.address("FRAME") as i16;
+        let ret_address = self.variables.address("RET") as i16;
+        let frame = self.get(1);
+        self.set(frame_address, frame);
+        let ret = self.get(frame.wrapping_sub(5));
+        self.set(ret_address, ret);
+
+        let arg = self.get(2);
+        self.set(13, arg);
+        let value = self.pop_value();
+        self.set(arg, value);
+        self.set(0, arg.wrapping_add(1));
+
+        for (offset, register) in [(1, 4), (2, 3), (3, 2), (4, 1)] {
+            let value = self.get(frame.wrapping_sub(offset));
+            self.set(register, value);
+        }
+
+        match self.return_addresses.get((ret as usize).wrapping_sub(1)) {
+            Some(&i) if ret > 0 => self.pc = i,
+            _ => self.halted = true,
+        }
+    }
+
+    // 0でないRAMの値をアドレス順に返す
+    pub fn dump(&self) -> Vec<(usize, i16)> {
+        self.ram
+            .iter()
+            .enumerate()
+            .filter(|(_, &value)| value != 0)
+            .map(|(address, &value)| (address, value))
+            .collect()
+    }
+}
+
+impl Memory for VmInterpreter {
+    fn get(&self, address: i16) -> i16 {
+        VmInterpreter::get(self, address)
+    }
+
+    fn set(&mut self, address: i16, value: i16) {
+        VmInterpreter::set(self, address, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn interpreter(source: &str) -> VmInterpreter {
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        VmInterpreter::new("Foo.vm".to_string(), parser.collect_commands())
+    }
+
+    #[test]
+    fn arithmetic_wraps_around() {
+        let mut interpreter =
+            interpreter("push constant 32767\npush constant 1\nadd\npush constant 1\nlt");
+        interpreter.set(0, 256);
+        interpreter.run(None);
+        assert!(interpreter.halted);
+        // -32768 - 1 は 32767 になるので lt は偽
+        assert_eq!(interpreter.dump(), [(0, 257), (257, 1)]);
+    }
+
+    #[test]
+    fn call_with_many_arguments_wraps_around() {
+        let mut interpreter = interpreter("call Foo.f 32767\nfunction Foo.f 0");
+        interpreter.set(0, 256);
+        interpreter.run(None);
+        assert!(interpreter.halted);
+        // ARG = 261 - 32767 - 5
+        assert_eq!(
+            interpreter.get(2),
+            261i16.wrapping_sub(32767).wrapping_sub(5)
+        );
+    }
+
+    #[test]
+    fn goto_loops_until_limit() {
+        let mut interpreter = interpreter("label LOOP\npush constant 1\ngoto LOOP");
+        interpreter.set(0, 256);
+        interpreter.run(Some(10));
+        assert!(!interpreter.halted);
+        assert_eq!(interpreter.steps, 10);
+        assert_eq!(interpreter.get(0), 261);
+    }
+
+    #[test]
+    fn call_and_return() {
+        let source = "push constant 32767
+push constant 2
+add
+push constant 20000
+neg
+push constant 20000
+gt
+call Foo.double 1
+pop static 0
+label END
+goto END
+function Foo.double 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+push constant 0
+eq
+not
+pop pointer 1
+push local 0
+return";
+        let mut interpreter = interpreter(source);
+        interpreter.set(0, 256);
+        interpreter.set(1, 300);
+        interpreter.set(2, 400);
+        interpreter.run(Some(100));
+        assert_eq!(
+            interpreter.dump(),
+            [
+                (0, 257),
+                (1, 300),
+                (2, 400),
+                (13, 16),
+                (16, -2),
+                (17, 263),
+                (18, 1),
+                (256, -32767),
+                (257, -2),
+                (258, 1),
+                (259, 300),
+                (260, 400),
+                (263, -2),
+                (264, -2),
+            ]
+        );
+    }
+}
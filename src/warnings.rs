@@ -0,0 +1,125 @@
+use crate::cfg::{function_graphs, CallGraph};
+use crate::parser::CommandType;
+use crate::program::Program;
+
+// エントリポイントとして呼ばれる関数
+const ENTRY_FUNCTION: &str = "Sys.init";
+
+// 翻訳はできるが誤りの可能性が高い箇所
+pub fn warnings(program: &Program) -> Vec<String> {
+    let mut warnings = vec![];
+    for graph in function_graphs(program) {
+        let reachable = graph.reachable_blocks();
+        for (i, block) in graph.blocks.iter().enumerate() {
+            // 連続した到達不能なブロックは先頭だけ報告する
+            if !reachable[i] && (i == 0 || reachable[i - 1]) {
+                warnings.push(format!(
+                    "{}:{}: unreachable code in {}",
+                    graph.file_name, block.commands[0].line, graph.name
+                ));
+            }
+        }
+
+        let targets: Vec<&str> = graph
+            .blocks
+            .iter()
+            .flat_map(|block| block.commands.iter())
+            .filter(|command| matches!(command.command_type, CommandType::GOTO | CommandType::IF))
+            .map(|command| command.arg1.as_deref().unwrap_or_default())
+            .collect();
+        for block in &graph.blocks {
+            let command = &block.commands[0];
+            let label = command.arg1.as_deref().unwrap_or_default();
+            if command.command_type == CommandType::LABEL && !targets.contains(&label) {
+                warnings.push(format!(
+                    "{}:{}: label {} is never used in {}",
+                    graph.file_name, command.line, label, graph.name
+                ));
+            }
+        }
+    }
+
+    // エントリポイントがない場合はプログラムの一部とみなして検査しない
+    let call_graph = CallGraph::new(program);
+    if !call_graph.functions.iter().any(|f| f == ENTRY_FUNCTION) {
+        return warnings;
+    }
+    for file in &program.files {
+        for command in &file.commands {
+            let function_name = command.arg1.as_deref().unwrap_or_default();
+            let is_called = call_graph
+                .calls
+                .iter()
+                .any(|(caller, callee)| callee == function_name && caller != function_name);
+            if command.command_type == CommandType::FUNCTION
+                && function_name != ENTRY_FUNCTION
+                && !is_called
+            {
+                warnings.push(format!(
+                    "{}:{}: function {} is never called",
+                    file.file_name, command.line, function_name
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::VmFile;
+
+    fn warnings_of(sources: &[(&str, &str)]) -> Vec<String> {
+        let files = sources
+            .iter()
+            .map(|(file_name, source)| {
+                VmFile::new(file_name, source.lines().map(|l| l.to_string()).collect())
+            })
+            .collect();
+        warnings(&Program::new(files))
+    }
+
+    #[test]
+    fn unreachable_code_and_unused_labels() {
+        let source = "function Foo.main 0
+label LOOP
+push constant 1
+if-goto END
+goto LOOP
+push constant 2
+label UNUSED
+pop temp 0
+label END
+push constant 0
+return
+push constant 3";
+        assert_eq!(
+            warnings_of(&[("Foo.vm", source)]),
+            [
+                "Foo.vm:6: unreachable code in Foo.main",
+                "Foo.vm:12: unreachable code in Foo.main",
+                "Foo.vm:7: label UNUSED is never used in Foo.main",
+            ]
+        );
+    }
+
+    #[test]
+    fn uncalled_functions() {
+        let sys = "function Sys.init 0
+call Main.main 0
+return";
+        let main = "function Main.main 0
+push constant 0
+return
+function Main.unused 0
+call Main.unused 0
+return";
+        assert_eq!(
+            warnings_of(&[("Sys.vm", sys), ("Main.vm", main)]),
+            ["Main.vm:4: function Main.unused is never called"]
+        );
+        // Sys.initがなければ呼ばれない関数は報告しない
+        assert!(warnings_of(&[("Main.vm", main)]).is_empty());
+    }
+}
@@ -0,0 +1,534 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::code_writer::helper::filename_without_extention;
+use crate::code_writer::segment::Segment;
+use crate::static_allocation::StaticAllocation;
+use crate::translator::Translator;
+use std::{fs::OpenOptions, io::prelude::*};
+
+// 0番は停止、1番はプログラムの先頭
+const HALT_ID: usize = 0;
+const ENTRY_ID: usize = 1;
+
+const HEADER: &str = "(module
+  (memory (export \"memory\") 1)
+  (global $limit (mut i32) (i32.const -1))
+  (global $steps (mut i32) (i32.const 0))
+
+  (func $wrap (param $value i32) (result i32)
+    (i32.shr_s (i32.shl (local.get $value) (i32.const 16)) (i32.const 16)))
+
+  (func $get (export \"ram\") (param $address i32) (result i32)
+    (i32.load16_s
+      (i32.shl (i32.and (local.get $address) (i32.const 0x7fff)) (i32.const 1))))
+
+  (func $set (export \"set_ram\") (param $address i32) (param $value i32)
+    (i32.store16
+      (i32.shl (i32.and (local.get $address) (i32.const 0x7fff)) (i32.const 1))
+      (local.get $value)))
+
+  (func $push (param $value i32)
+    (call $set (call $get (i32.const 0)) (local.get $value))
+    (call $set (i32.const 0) (i32.add (call $get (i32.const 0)) (i32.const 1))))
+
+  (func $pop (result i32)
+    (call $set (i32.const 0) (i32.sub (call $get (i32.const 0)) (i32.const 1)))
+    (call $get (call $get (i32.const 0))))
+
+  (func $step (result i32)
+    (if (result i32)
+      (i32.and
+        (i32.ge_s (global.get $limit) (i32.const 0))
+        (i32.ge_s (global.get $steps) (global.get $limit)))
+      (then (i32.const 1))
+      (else
+        (global.set $steps (i32.add (global.get $steps) (i32.const 1)))
+        (i32.const 0))))
+
+  (func (export \"run\") (param $limit i32)
+    (local $pc i32)
+    (local $x i32)
+    (local $y i32)
+    (local $address i32)
+    (global.set $limit (local.get $limit))
+    (global.set $steps (i32.const 0))
+    (local.set $pc (i32.const 1))";
+
+const FOOTER: &str = "  )
+)";
+
+struct Block {
+    id: usize,
+    code: Vec<String>,
+}
+
+pub struct WatWriter {
+    file_name: String,
+    blocks: Vec<Block>,
+    block_names: Vec<String>,
+    function_name_stack: Vec<String>,
+    return_address_count: usize,
+    variables: StaticAllocation,
+}
+
+impl WatWriter {
+    pub fn new(file_name: String) -> WatWriter {
+        WatWriter {
+            file_name,
+            blocks: vec![Block {
+                id: ENTRY_ID,
+                code: vec![],
+            }],
+            // HALT_IDとENTRY_IDの分
+            block_names: vec!["".to_string(), "".to_string()],
+            function_name_stack: vec!["null".to_string()],
+            return_address_count: 0,
+            variables: StaticAllocation::default(),
+        }
+    }
+
+    pub fn generate(&self) -> Vec<String> {
+        let mut code: Vec<String> = HEADER.lines().map(|l| l.to_string()).collect();
+        code.push("    loop $dispatch".to_string());
+        code.push("    block $halt".to_string());
+        for i in (0..self.blocks.len()).rev() {
+            code.push(format!("    block $block_{}", i));
+        }
+
+        // $pcの番号から飛び先のブロックを引く (定義されていない番号は停止)
+        let targets = (0..self.block_names.len())
+            .map(|id| match self.blocks.iter().position(|b| b.id == id) {
+                Some(i) if id != HALT_ID => format!("$block_{}", i),
+                _ => "$halt".to_string(),
+            })
+            .collect::<Vec<String>>();
+        code.push("    local.get $pc".to_string());
+        code.push(format!("    br_table {} $halt", targets.join(" ")));
+
+        for block in &self.blocks {
+            code.push("    end".to_string());
+            code.append(&mut block.code.clone());
+        }
+        code.push("    end".to_string());
+        code.push("    end".to_string());
+        code.append(&mut FOOTER.lines().map(|l| l.to_string()).collect());
+        code
+    }
+
+    fn block_id(&mut self, name: &str) -> usize {
+        match self.block_names.iter().skip(2).position(|n| n == name) {
+            Some(i) => i + 2,
+            None => {
+                self.block_names.push(name.to_string());
+                self.block_names.len() - 1
+            }
+        }
+    }
+
+    fn start_block(&mut self, name: &str) {
+        let id = self.block_id(name);
+        self.blocks.push(Block { id, code: vec![] });
+    }
+
+    // セグメントのアドレスをスタックに積む
+    fn segment_address(&mut self, segment: &str, index: &str) -> Vec<String> {
+        match Segment::from_str(segment) {
+            Some(Segment::LOCAL)
+            | Some(Segment::ARGUMENT)
+            | Some(Segment::THIS)
+            | Some(Segment::THAT) => {
+                let register = match Segment::from_str(segment) {
+                    Some(Segment::LOCAL) => 1,
+                    Some(Segment::ARGUMENT) => 2,
+                    Some(Segment::THIS) => 3,
+                    _ => 4,
+                };
+                vec![
+                    format!("i32.const {}", register),
+                    "call $get".to_string(),
+                    format!("i32.const {}", index),
+                    "i32.add".to_string(),
+                ]
+            }
+            Some(Segment::POINTER) => vec![format!("i32.const {}", 3 + parse(index))],
+            Some(Segment::TEMP) => vec![format!("i32.const {}", 5 + parse(index))],
+            Some(Segment::STATIC) => {
+                let name = format!("{}.{}", filename_without_extention(&self.file_name), index);
+                vec![format!("i32.const {}", self.variables.address(&name))]
+            }
+            _ => panic!("{} has no address", segment),
+        }
+    }
+
+    fn scoped_label(&self, label_name: &str) -> String {
+        format!(
+            "{}${}",
+            self.function_name_stack.last().unwrap(),
+            label_name
+        )
+    }
+
+    fn append(&mut self, lines: Vec<String>) {
+        let code = &mut self.blocks.last_mut().unwrap().code;
+        code.push("    call $step".to_string());
+        code.push("    br_if $halt".to_string());
+        for line in lines {
+            code.push(format!("    {}", line));
+        }
+    }
+}
+
+impl Translator for WatWriter {
+    fn set_static_allocation(&mut self, allocation: &StaticAllocation) {
+        self.variables = allocation.clone();
+    }
+
+    fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    fn output(&self, file_name: &str) {
+        let mut output = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        for line in self.generate() {
+            writeln!(output, "{}", line).unwrap();
+        }
+    }
+
+    fn push(&mut self, segment: &str, index: &str) {
+        let mut code = match Segment::from_str(segment) {
+            Some(Segment::CONSTANT) => vec![format!("i32.const {}", index)],
+            _ => {
+                let mut code = self.segment_address(segment, index);
+                code.push("call $get".to_string());
+                code
+            }
+        };
+        code.push("call $push".to_string());
+        self.append(code);
+    }
+
+    fn pop(&mut self, segment: &str, index: &str) {
+        if Segment::from_str(segment) == Some(Segment::CONSTANT) {
+            return;
+        }
+        let mut code = self.segment_address(segment, index);
+        code.append(&mut vec![
+            "local.set $address".to_string(),
+            "i32.const 13".to_string(),
+            "local.get $address".to_string(),
+            "call $set".to_string(),
+            "local.get $address".to_string(),
+            "call $pop".to_string(),
+            "call $set".to_string(),
+        ]);
+        self.append(code);
+    }
+
+    fn write_label(&mut self, label_name: &str) {
+        let label = self.scoped_label(label_name);
+        self.start_block(&label);
+    }
+
+    fn write_go_to(&mut self, label_name: &str) {
+        let label = self.scoped_label(label_name);
+        let id = self.block_id(&label);
+        self.append(jump_code(id));
+    }
+
+    fn write_if_go_to(&mut self, label_name: &str) {
+        let label = self.scoped_label(label_name);
+        let id = self.block_id(&label);
+        let mut code = vec!["call $pop".to_string(), "if".to_string()];
+        code.append(&mut jump_code(id));
+        code.push("end".to_string());
+        self.append(code);
+    }
+
+    fn write_call(&mut self, function_name: &str, n_arg: &str) {
+        self.return_address_count += 1;
+        let return_address = format!("Return_address.{}", self.return_address_count);
+        let return_id = self.block_id(&return_address);
+        let function_id = self.block_id(function_name);
+
+        // return_addressの代わりに戻り先のブロック番号をpushする
+        let mut code = vec![format!("i32.const {}", return_id), "call $push".to_string()];
+        for register in 1..=4 {
+            code.append(&mut vec![
+                format!("i32.const {}", register),
+                "call $get".to_string(),
+                "call $push".to_string(),
+            ]);
+        }
+
+        // ARG = SP - n_arg - 5, LCL = SP
+        code.append(&mut vec![
+            "i32.const 2".to_string(),
+            "i32.const 0".to_string(),
+            "call $get".to_string(),
+            format!("i32.const {}", parse(n_arg) + 5),
+            "i32.sub".to_string(),
+            "call $set".to_string(),
+            "i32.const 1".to_string(),
+            "i32.const 0".to_string(),
+            "call $get".to_string(),
+            "call $set".to_string(),
+        ]);
+        code.append(&mut jump_code(function_id));
+        self.append(code);
+        self.start_block(&return_address);
+    }
+
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
+        use ArithmeticCommand::*;
+        let code = match ArithmeticCommand::from_str(arithmetic_command) {
+            Some(ADD) => two_operands(vec!["i32.add"]),
+            Some(SUB) => two_operands(vec!["i32.sub"]),
+            Some(AND) => two_operands(vec!["i32.and"]),
+            Some(OR) => two_operands(vec!["i32.or"]),
+            Some(EQ) => condition(vec!["i32.eqz"]),
+            Some(GT) => condition(vec!["i32.const 0", "i32.gt_s"]),
+            Some(LT) => condition(vec!["i32.const 0", "i32.lt_s"]),
+            Some(NEG) => vec![
+                "i32.const 0".to_string(),
+                "call $pop".to_string(),
+                "i32.sub".to_string(),
+                "call $push".to_string(),
+            ],
+            Some(NOT) => vec![
+                "call $pop".to_string(),
+                "i32.const -1".to_string(),
+                "i32.xor".to_string(),
+                "call $push".to_string(),
+            ],
+            _ => return,
+        };
+        self.append(code);
+    }
+
+    fn write_function(&mut self, function_name: &str, num_locals: &str) {
+        self.function_name_stack.push(function_name.to_string());
+        self.start_block(function_name);
+        let mut code = vec![];
+        for _ in 0..parse(num_locals) {
+            code.push("i32.const 0".to_string());
+            code.push("call $push".to_string());
+        }
+        self.append(code);
+    }
+
+    fn write_return(&mut self) {
+        let frame = self.variables.address("FRAME");
+        let ret = self.variables.address("RET");
+        let mut code = vec![
+            format!("i32.const {}", frame),
+            "i32.const 1".to_string(),
+            "call $get".to_string(),
+            "call $set".to_string(),
+            format!("i32.const {}", ret),
+            format!("i32.const {}", frame),
+            "call $get".to_string(),
+            "i32.const 5".to_string(),
+            "i32.sub".to_string(),
+            "call $get".to_string(),
+            "call $set".to_string(),
+        ];
+
+        // *ARG = pop(), SP = ARG + 1
+        code.append(&mut vec![
+            "i32.const 13".to_string(),
+            "i32.const 2".to_string(),
+            "call $get".to_string(),
+            "call $set".to_string(),
+            "i32.const 2".to_string(),
+            "call $get".to_string(),
+            "call $pop".to_string(),
+            "call $set".to_string(),
+            "i32.const 0".to_string(),
+            "i32.const 2".to_string(),
+            "call $get".to_string(),
+            "i32.const 1".to_string(),
+            "i32.add".to_string(),
+            "call $set".to_string(),
+        ]);
+
+        // THAT, THIS, ARG, LCLを戻す
+        for (offset, register) in [(1, 4), (2, 3), (3, 2), (4, 1)] {
+            code.append(&mut vec![
+                format!("i32.const {}", register),
+                format!("i32.const {}", frame),
+                "call $get".to_string(),
+                format!("i32.const {}", offset),
+                "i32.sub".to_string(),
+                "call $get".to_string(),
+                "call $set".to_string(),
+            ]);
+        }
+
+        code.append(&mut vec![
+            format!("i32.const {}", ret),
+            "call $get".to_string(),
+            "local.set $pc".to_string(),
+            "br $dispatch".to_string(),
+        ]);
+        self.append(code);
+    }
+}
+
+fn parse(number: &str) -> i32 {
+    number.parse::<i32>().unwrap()
+}
+
+fn jump_code(id: usize) -> Vec<String> {
+    vec![
+        format!("i32.const {}", id),
+        "local.set $pc".to_string(),
+        "br $dispatch".to_string(),
+    ]
+}
+
+fn two_operands(operation: Vec<&str>) -> Vec<String> {
+    let mut res = vec![
+        "call $pop".to_string(),
+        "local.set $y".to_string(),
+        "call $pop".to_string(),
+        "local.set $x".to_string(),
+        "local.get $x".to_string(),
+        "local.get $y".to_string(),
+    ];
+    res.append(&mut operation.iter().map(|o| o.to_string()).collect());
+    res.push("call $push".to_string());
+    res
+}
+
+// Hackと同じくx-yの符号で比較し、真なら-1、偽なら0にする
+fn condition(mut comparison: Vec<&str>) -> Vec<String> {
+    let mut operation = vec!["i32.sub", "call $wrap"];
+    operation.append(&mut comparison);
+    let mut res = vec!["i32.const 0".to_string()];
+    res.append(&mut two_operands(operation));
+    let push = res.pop().unwrap();
+    res.push("i32.sub".to_string());
+    res.push(push);
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn translate(source: &str) -> WatWriter {
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        let mut wat_writer = WatWriter::new("Foo.vm".to_string());
+        for command in parser.collect_commands() {
+            wat_writer.write_command(&command);
+        }
+        wat_writer
+    }
+
+    fn run(wat_writer: &WatWriter, ram: &[(i32, i32)], limit: i32) -> Vec<(i32, i32)> {
+        let wasm = wat::parse_str(wat_writer.generate().join("\n")).unwrap();
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &wasm[..]).unwrap();
+        let mut store = wasmi::Store::new(&engine, ());
+        let linker = wasmi::Linker::<()>::new(&engine);
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let set_ram = instance
+            .get_typed_func::<(i32, i32), ()>(&store, "set_ram")
+            .unwrap();
+        let get_ram = instance.get_typed_func::<i32, i32>(&store, "ram").unwrap();
+        let run = instance.get_typed_func::<i32, ()>(&store, "run").unwrap();
+
+        for &(address, value) in ram {
+            set_ram.call(&mut store, (address, value)).unwrap();
+        }
+        run.call(&mut store, limit).unwrap();
+        (0..32768)
+            .map(|address| (address, get_ram.call(&mut store, address).unwrap()))
+            .filter(|&(_, value)| value != 0)
+            .collect()
+    }
+
+    #[test]
+    fn labels_and_functions_get_block_ids() {
+        let wat_writer = translate("goto A\nlabel A\nfunction Foo.f 0\ncall Foo.f 0");
+        assert_eq!(
+            wat_writer.block_names,
+            ["", "", "null$A", "Foo.f", "Return_address.1"]
+        );
+        let code = wat_writer.generate();
+        assert!(code
+            .contains(&"    br_table $halt $block_0 $block_1 $block_2 $block_3 $halt".to_string()));
+    }
+
+    #[test]
+    fn simple_arithmetic() {
+        let wat_writer = translate("push constant 7\npush constant 8\nadd\npop local 2");
+        assert_eq!(
+            run(&wat_writer, &[(0, 256), (1, 300)], -1),
+            [
+                (0, 256),
+                (1, 300),
+                (13, 302),
+                (256, 15),
+                (257, 8),
+                (302, 15)
+            ]
+        );
+    }
+
+    #[test]
+    fn call_and_return() {
+        let source = "push constant 32767
+push constant 2
+add
+push constant 20000
+neg
+push constant 20000
+gt
+call Foo.double 1
+pop static 0
+label END
+goto END
+function Foo.double 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+push constant 0
+eq
+not
+pop pointer 1
+push local 0
+return";
+        let wat_writer = translate(source);
+        assert_eq!(
+            run(&wat_writer, &[(0, 256), (1, 300), (2, 400)], 100),
+            [
+                (0, 257),
+                (1, 300),
+                (2, 400),
+                (13, 16),
+                (16, -2),
+                (17, 263),
+                (18, 2),
+                (256, -32767),
+                (257, -2),
+                (258, 2),
+                (259, 300),
+                (260, 400),
+                (263, -2),
+                (264, -2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,484 @@
+use crate::code_writer::arithmetic_command::ArithmeticCommand;
+use crate::code_writer::helper::{filename_without_extention, mangle_symbol};
+use crate::code_writer::segment::Segment;
+use crate::static_allocation::StaticAllocation;
+use crate::translator::Translator;
+use std::{fs::OpenOptions, io::prelude::*, path::Path};
+
+pub const RUNTIME: &str = include_str!("x86_writer/runtime.c");
+pub const RUNTIME_FILE_NAME: &str = "vm_runtime.c";
+
+// %rbx: ramの先頭, %r12: 実行する命令数の上限, %r13: 実行した命令数
+const HEADER: &str = "    .bss
+    .globl ram
+    .p2align 4
+ram:
+    .zero 65536
+
+    .macro STEP
+    testq %r12, %r12
+    js 1f
+    cmpq %r12, %r13
+    jge vm_halt
+    incq %r13
+1:
+    .endm
+
+    .text
+    .globl vm_run
+vm_run:
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    leaq ram(%rip), %rbx
+    movq %rdi, %r12
+    xorl %r13d, %r13d";
+
+const FOOTER: &str = "vm_halt:
+    popq %r13
+    popq %r12
+    popq %rbx
+    ret
+    .section .note.GNU-stack,\"\",@progbits";
+
+pub struct X86Writer {
+    file_name: String,
+    generated_code: Vec<String>,
+    function_name_stack: Vec<String>,
+    return_address_count: usize,
+    variables: StaticAllocation,
+    defined_functions: Vec<String>,
+    called_functions: Vec<String>,
+}
+
+impl X86Writer {
+    pub fn new(file_name: String) -> X86Writer {
+        X86Writer {
+            file_name,
+            generated_code: vec![],
+            function_name_stack: vec!["null".to_string()],
+            return_address_count: 0,
+            variables: StaticAllocation::default(),
+            defined_functions: vec![],
+            called_functions: vec![],
+        }
+    }
+
+    pub fn generate(&self) -> Vec<String> {
+        let mut code: Vec<String> = HEADER.lines().map(|l| l.to_string()).collect();
+        code.append(&mut self.generated_code.clone());
+        code.push("    jmp vm_halt".to_string());
+
+        // 戻り先の番号からアドレスを引く (0番は停止)
+        code.append(&mut vec![
+            "vm_dispatch:".to_string(),
+            format!("    cmpl ${}, %eax", self.return_address_count),
+            "    ja vm_halt".to_string(),
+            "    leaq vm_return_table(%rip), %rcx".to_string(),
+            "    movslq (%rcx,%rax,4), %rax".to_string(),
+            "    addq %rcx, %rax".to_string(),
+            "    jmp *%rax".to_string(),
+        ]);
+
+        // 定義されていない関数を呼び出した場合は停止する
+        for function_name in &self.called_functions {
+            if !self.defined_functions.contains(function_name) {
+                code.push(format!("{}:", function_label(function_name)));
+                code.push("    jmp vm_halt".to_string());
+            }
+        }
+
+        code.append(&mut FOOTER.lines().map(|l| l.to_string()).collect());
+
+        code.push("    .section .rodata".to_string());
+        code.push("    .p2align 2".to_string());
+        code.push("vm_return_table:".to_string());
+        code.push("    .long vm_halt - vm_return_table".to_string());
+        for i in 1..=self.return_address_count {
+            code.push(format!("    .long vm_return_{} - vm_return_table", i));
+        }
+        code
+    }
+
+    // セグメントのアドレスを%eaxに計算する
+    fn segment_address(&mut self, segment: &str, index: &str) -> Vec<String> {
+        let mut res = match Segment::from_str(segment) {
+            Some(Segment::LOCAL)
+            | Some(Segment::ARGUMENT)
+            | Some(Segment::THIS)
+            | Some(Segment::THAT) => {
+                let register = match Segment::from_str(segment) {
+                    Some(Segment::LOCAL) => 1,
+                    Some(Segment::ARGUMENT) => 2,
+                    Some(Segment::THIS) => 3,
+                    _ => 4,
+                };
+                vec![
+                    format!("movzwl {}(%rbx), %eax", register * 2),
+                    format!("addl ${}, %eax", index),
+                ]
+            }
+            Some(Segment::POINTER) => vec![format!("movl $3 + {}, %eax", index)],
+            Some(Segment::TEMP) => vec![format!("movl $5 + {}, %eax", index)],
+            Some(Segment::STATIC) => {
+                let name = format!("{}.{}", filename_without_extention(&self.file_name), index);
+                vec![format!("movl ${}, %eax", self.variables.address(&name))]
+            }
+            _ => panic!("{} has no address", segment),
+        };
+        res.push("andl $0x7fff, %eax".to_string());
+        res
+    }
+
+    fn current_label(&self, label_name: &str) -> String {
+        format!(
+            "vm_label_{}",
+            mangle_symbol(&format!(
+                "{}${}",
+                self.function_name_stack.last().unwrap(),
+                label_name
+            ))
+        )
+    }
+
+    fn append(&mut self, lines: Vec<String>) {
+        self.generated_code.push("    STEP".to_string());
+        for line in lines {
+            self.generated_code.push(format!("    {}", line));
+        }
+    }
+}
+
+impl Translator for X86Writer {
+    fn set_static_allocation(&mut self, allocation: &StaticAllocation) {
+        self.variables = allocation.clone();
+    }
+
+    fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    fn output(&self, file_name: &str) {
+        let mut output = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        for line in self.generate() {
+            writeln!(output, "{}", line).unwrap();
+        }
+
+        let runtime_file_name = Path::new(file_name).with_file_name(RUNTIME_FILE_NAME);
+        std::fs::write(runtime_file_name, RUNTIME).unwrap();
+    }
+
+    fn push(&mut self, segment: &str, index: &str) {
+        let mut code = match Segment::from_str(segment) {
+            Some(Segment::CONSTANT) => vec![format!("movw ${}, %dx", index)],
+            _ => {
+                let mut code = self.segment_address(segment, index);
+                code.push("movw (%rbx,%rax,2), %dx".to_string());
+                code
+            }
+        };
+        code.append(&mut push_dx_code());
+        self.append(code);
+    }
+
+    fn pop(&mut self, segment: &str, index: &str) {
+        if Segment::from_str(segment) == Some(Segment::CONSTANT) {
+            return;
+        }
+        let mut code = self.segment_address(segment, index);
+        code.push("movw %ax, 26(%rbx)".to_string());
+        code.append(&mut pop_dx_code());
+        code.push("movw %dx, (%rbx,%rax,2)".to_string());
+        self.append(code);
+    }
+
+    fn write_label(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.generated_code.push(format!("{}:", label));
+    }
+
+    fn write_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        self.append(vec![format!("jmp {}", label)]);
+    }
+
+    fn write_if_go_to(&mut self, label_name: &str) {
+        let label = self.current_label(label_name);
+        let mut code = pop_dx_code();
+        code.push("testw %dx, %dx".to_string());
+        code.push(format!("jne {}", label));
+        self.append(code);
+    }
+
+    fn write_call(&mut self, function_name: &str, n_arg: &str) {
+        self.return_address_count += 1;
+        let return_address = self.return_address_count;
+        if !self.called_functions.iter().any(|f| f == function_name) {
+            self.called_functions.push(function_name.to_string());
+        }
+
+        // return_addressの代わりに戻り先の番号をpushする
+        let mut code = vec![format!("movw ${}, %dx", return_address)];
+        code.append(&mut push_dx_code());
+        for register in 1..=4 {
+            code.push(format!("movw {}(%rbx), %dx", register * 2));
+            code.append(&mut push_dx_code());
+        }
+
+        // ARG = SP - n_arg - 5, LCL = SP
+        code.append(&mut vec![
+            "movw (%rbx), %dx".to_string(),
+            "movw %dx, 2(%rbx)".to_string(),
+            format!("subw ${} + 5, %dx", n_arg),
+            "movw %dx, 4(%rbx)".to_string(),
+            format!("jmp {}", function_label(function_name)),
+        ]);
+        self.append(code);
+        self.generated_code
+            .push(format!("vm_return_{}:", return_address));
+    }
+
+    fn run_arichmetic_command(&mut self, arithmetic_command: &str) {
+        use ArithmeticCommand::*;
+        let code = match ArithmeticCommand::from_str(arithmetic_command) {
+            Some(ADD) => two_operands(vec!["addw %dx, (%rbx,%rcx,2)".to_string()]),
+            Some(SUB) => two_operands(vec!["subw %dx, (%rbx,%rcx,2)".to_string()]),
+            Some(AND) => two_operands(vec!["andw %dx, (%rbx,%rcx,2)".to_string()]),
+            Some(OR) => two_operands(vec!["orw %dx, (%rbx,%rcx,2)".to_string()]),
+            Some(EQ) => condition("sete"),
+            Some(GT) => condition("setg"),
+            Some(LT) => condition("setl"),
+            Some(NEG) => one_operand("negw (%rbx,%rcx,2)"),
+            Some(NOT) => one_operand("notw (%rbx,%rcx,2)"),
+            _ => return,
+        };
+        self.append(code);
+    }
+
+    fn write_function(&mut self, function_name: &str, num_locals: &str) {
+        self.function_name_stack.push(function_name.to_string());
+        self.defined_functions.push(function_name.to_string());
+        self.generated_code
+            .push(format!("{}:", function_label(function_name)));
+        let mut code = vec![];
+        for _ in 0..num_locals.parse::<i32>().unwrap() {
+            code.push("xorl %edx, %edx".to_string());
+            code.append(&mut push_dx_code());
+        }
+        self.append(code);
+    }
+
+    fn write_return(&mut self) {
+        let frame = self.variables.address("FRAME") * 2;
+        let ret = self.variables.address("RET") * 2;
+        let mut code = vec![
+            "movzwl 2(%rbx), %eax".to_string(),
+            format!("movw %ax, {}(%rbx)", frame),
+            "subl $5, %eax".to_string(),
+            "andl $0x7fff, %eax".to_string(),
+            "movw (%rbx,%rax,2), %dx".to_string(),
+            format!("movw %dx, {}(%rbx)", ret),
+        ];
+
+        // *ARG = pop()
+        code.append(&mut vec![
+            "movzwl 4(%rbx), %eax".to_string(),
+            "movw %ax, 26(%rbx)".to_string(),
+            "andl $0x7fff, %eax".to_string(),
+        ]);
+        code.append(&mut pop_dx_code());
+        code.append(&mut vec![
+            "movw %dx, (%rbx,%rax,2)".to_string(),
+            "movw 4(%rbx), %dx".to_string(),
+            "incw %dx".to_string(),
+            "movw %dx, (%rbx)".to_string(),
+        ]);
+
+        // THAT, THIS, ARG, LCLを戻す
+        for (offset, register) in [(1, 4), (2, 3), (3, 2), (4, 1)] {
+            code.append(&mut vec![
+                format!("movzwl {}(%rbx), %eax", frame),
+                format!("subl ${}, %eax", offset),
+                "andl $0x7fff, %eax".to_string(),
+                "movw (%rbx,%rax,2), %dx".to_string(),
+                format!("movw %dx, {}(%rbx)", register * 2),
+            ]);
+        }
+
+        code.push(format!("movzwl {}(%rbx), %eax", ret));
+        code.push("jmp vm_dispatch".to_string());
+        self.append(code);
+    }
+}
+
+fn push_dx_code() -> Vec<String> {
+    vec![
+        "movzwl (%rbx), %ecx".to_string(),
+        "andl $0x7fff, %ecx".to_string(),
+        "movw %dx, (%rbx,%rcx,2)".to_string(),
+        "incw (%rbx)".to_string(),
+    ]
+}
+
+fn pop_dx_code() -> Vec<String> {
+    vec![
+        "decw (%rbx)".to_string(),
+        "movzwl (%rbx), %ecx".to_string(),
+        "andl $0x7fff, %ecx".to_string(),
+        "movw (%rbx,%rcx,2), %dx".to_string(),
+    ]
+}
+
+// %dxにy、%rcxにxのアドレスを置いてから演算する
+fn two_operands(mut operation: Vec<String>) -> Vec<String> {
+    let mut res = pop_dx_code();
+    res.append(&mut vec![
+        "decl %ecx".to_string(),
+        "andl $0x7fff, %ecx".to_string(),
+    ]);
+    res.append(&mut operation);
+    res
+}
+
+fn one_operand(operation: &str) -> Vec<String> {
+    vec![
+        "movzwl (%rbx), %ecx".to_string(),
+        "decl %ecx".to_string(),
+        "andl $0x7fff, %ecx".to_string(),
+        operation.to_string(),
+    ]
+}
+
+// Hackと同じくx-yの符号で比較し、真なら-1、偽なら0にする
+fn condition(set_instruction: &str) -> Vec<String> {
+    two_operands(vec![
+        "movw (%rbx,%rcx,2), %ax".to_string(),
+        "subw %dx, %ax".to_string(),
+        "testw %ax, %ax".to_string(),
+        format!("{} %al", set_instruction),
+        "movzbl %al, %eax".to_string(),
+        "negl %eax".to_string(),
+        "movw %ax, (%rbx,%rcx,2)".to_string(),
+    ])
+}
+
+fn function_label(function_name: &str) -> String {
+    format!("vm_function_{}", mangle_symbol(function_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn translate<T: Translator>(translator: &mut T, source: &str) {
+        let mut parser = Parser::new(source.lines().map(|l| l.to_string()).collect());
+        for command in parser.collect_commands() {
+            translator.write_command(&command);
+        }
+    }
+
+    #[test]
+    fn push_constant() {
+        let mut x86_writer = X86Writer::new("a.vm".to_string());
+        translate(&mut x86_writer, "push constant 7");
+        assert_eq!(
+            x86_writer.generated_code,
+            [
+                "    STEP",
+                "    movw $7, %dx",
+                "    movzwl (%rbx), %ecx",
+                "    andl $0x7fff, %ecx",
+                "    movw %dx, (%rbx,%rcx,2)",
+                "    incw (%rbx)",
+            ]
+        );
+    }
+
+    #[test]
+    fn return_table_has_entry_per_call() {
+        let mut x86_writer = X86Writer::new("a.vm".to_string());
+        translate(&mut x86_writer, "call Foo.bar 0\ncall Foo.bar 1");
+        let code = x86_writer.generate();
+        assert!(code.contains(&"vm_function_Foo_2ebar:".to_string()));
+        assert!(code.contains(&"    .long vm_return_2 - vm_return_table".to_string()));
+    }
+
+    // 生成するのはx86-64 Linuxのアセンブリなので、他の環境では動かせない
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn run(dir: &Path, name: &str, sources: &[&str], args: &[&str]) -> Option<String> {
+        use std::process::Command;
+        let binary = dir.join(name);
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&binary)
+            .args(sources)
+            .status()
+            .ok()?;
+        assert!(status.success());
+        let output = Command::new(&binary).args(args).output().unwrap();
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn assembled_program_matches_c_backend() {
+        use crate::c_writer::CWriter;
+        use crate::translator::skip_without_toolchain;
+        use std::env;
+        let source = "push constant 32767
+push constant 2
+add
+push constant 20000
+neg
+push constant 20000
+gt
+call Foo.double 1
+pop static 0
+label END
+goto END
+function Foo.double 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+push constant 0
+eq
+not
+pop pointer 1
+push local 0
+return";
+        let dir = env::temp_dir().join(format!("x86_writer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let asm_file = dir.join("Foo.s");
+        let c_file = dir.join("Foo.c");
+
+        let mut x86_writer = X86Writer::new("Foo.vm".to_string());
+        translate(&mut x86_writer, source);
+        x86_writer.output(asm_file.to_str().unwrap());
+        let mut c_writer = CWriter::new("Foo.vm".to_string());
+        translate(&mut c_writer, source);
+        c_writer.output(c_file.to_str().unwrap());
+
+        let args = ["-n", "100", "0=256", "1=300", "2=400"];
+        let runtime_file = dir.join(RUNTIME_FILE_NAME);
+        let x86_dump = match run(
+            &dir,
+            "x86",
+            &[asm_file.to_str().unwrap(), runtime_file.to_str().unwrap()],
+            &args,
+        ) {
+            Some(dump) => dump,
+            None => return skip_without_toolchain("cc is not installed"),
+        };
+        let c_dump = run(&dir, "c", &[c_file.to_str().unwrap()], &args).unwrap();
+        assert_eq!(x86_dump, c_dump);
+        assert!(x86_dump.contains("256: -32767\n"));
+    }
+}
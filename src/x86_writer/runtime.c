@@ -0,0 +1,30 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+extern int16_t ram[32768];
+void vm_run(long limit);
+
+void vm_halt(void) {
+    for (int i = 0; i < 32768; i++) {
+        if (ram[i] != 0) {
+            printf("%d: %d\n", i, ram[i]);
+        }
+    }
+    exit(0);
+}
+
+int main(int argc, char **argv) {
+    long limit = -1;
+    for (int i = 1; i < argc; i++) {
+        int address, value;
+        if (argv[i][0] == '-' && argv[i][1] == 'n' && i + 1 < argc) {
+            limit = atol(argv[++i]);
+        } else if (sscanf(argv[i], "%d=%d", &address, &value) == 2) {
+            ram[address & 0x7fff] = (int16_t)(uint16_t)value;
+        }
+    }
+
+    vm_run(limit);
+    vm_halt();
+}